@@ -0,0 +1,174 @@
+// Compares the work-stealing ThreadPool against the design it replaced, where
+// every worker pulled jobs from one channel behind a shared mutex.
+//
+// Run it with:
+//
+//     cargo bench --bench pool
+
+use server::ThreadPool;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WORKERS: usize = 4;
+const JOBS: usize = 100_000;
+const FAN_OUT: usize = 100;
+const ROUNDS: usize = 5;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Both pools, as far as the benchmarks are concerned.
+trait Pool: Send + Sync + 'static {
+    fn submit(&self, job: Job);
+}
+
+impl Pool for ThreadPool {
+    fn submit(&self, job: Job) {
+        self.execute(job).unwrap();
+    }
+}
+
+// The previous ThreadPool, cut down to the parts that matter here.
+struct ChannelPool {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ChannelPool {
+    fn new(size: usize) -> ChannelPool {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        let workers = (0..size)
+            .map(|_| {
+                let rx = Arc::clone(&rx);
+                thread::spawn(move || loop {
+                    let message = rx.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        ChannelPool {
+            sender: Some(tx),
+            workers,
+        }
+    }
+}
+
+impl Pool for ChannelPool {
+    fn submit(&self, job: Job) {
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+// Submit a flood of empty jobs from one thread and time how long it takes to
+// get through them.
+fn throughput<P: Pool>(pool: &P) -> Duration {
+    let (tx, rx) = mpsc::channel();
+    let start = Instant::now();
+
+    for _ in 0..JOBS {
+        let tx = tx.clone();
+        pool.submit(Box::new(move || tx.send(()).unwrap()));
+    }
+
+    rx.iter().take(JOBS).for_each(drop);
+    start.elapsed()
+}
+
+// How long each job waits between being submitted and starting to run.
+fn latency<P: Pool>(pool: &P) -> Vec<Duration> {
+    let (tx, rx) = mpsc::channel();
+
+    for _ in 0..JOBS {
+        let tx = tx.clone();
+        let submitted = Instant::now();
+        pool.submit(Box::new(move || tx.send(submitted.elapsed()).unwrap()));
+    }
+
+    rx.iter().take(JOBS).collect()
+}
+
+// Jobs that split themselves into smaller jobs, like a parallel map over a
+// batch. This is where submitting to a worker's own queue should pay off.
+fn fan_out<P: Pool>(pool: &Arc<P>) -> Duration {
+    let (tx, rx) = mpsc::channel();
+    let start = Instant::now();
+
+    for _ in 0..JOBS / FAN_OUT {
+        let inner = Arc::clone(pool);
+        let tx = tx.clone();
+        pool.submit(Box::new(move || {
+            for _ in 0..FAN_OUT {
+                let tx = tx.clone();
+                inner.submit(Box::new(move || tx.send(()).unwrap()));
+            }
+        }));
+    }
+
+    rx.iter().take(JOBS / FAN_OUT * FAN_OUT).for_each(drop);
+    start.elapsed()
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn bench<P: Pool>(name: &str, pool: P) {
+    let pool = Arc::new(pool);
+    let mut runs = Vec::new();
+    let mut fan_outs = Vec::new();
+    let mut waits = Vec::new();
+
+    for _ in 0..ROUNDS {
+        runs.push(throughput(&*pool));
+        fan_outs.push(fan_out(&pool));
+        waits.extend(latency(&*pool));
+    }
+
+    runs.sort();
+    fan_outs.sort();
+    waits.sort();
+
+    let jobs_per_sec = |d: Duration| JOBS as f64 / d.as_secs_f64();
+
+    eprintln!("{}:", name);
+    eprintln!(
+        "  throughput  {:>10.0} jobs/s (median of {})",
+        jobs_per_sec(runs[ROUNDS / 2]),
+        ROUNDS
+    );
+    eprintln!(
+        "  fan-out     {:>10.0} jobs/s (median of {})",
+        jobs_per_sec(fan_outs[ROUNDS / 2]),
+        ROUNDS
+    );
+    eprintln!(
+        "  latency     p50 {:?}, p99 {:?}, max {:?}",
+        percentile(&waits, 0.5),
+        percentile(&waits, 0.99),
+        waits[waits.len() - 1]
+    );
+}
+
+fn main() {
+    eprintln!("{} workers, {} jobs per run\n", WORKERS, JOBS);
+
+    bench("mutex + channel", ChannelPool::new(WORKERS));
+    bench("work stealing", ThreadPool::new(WORKERS));
+}
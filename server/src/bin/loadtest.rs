@@ -0,0 +1,299 @@
+// Compares the two ways the server can wait on its connections: a worker per
+// connection, and an epoll event loop that only hands workers complete
+// requests. A few clients send their requests a header at a time, the way a
+// slow network or a Slowloris attack would, while the rest make ordinary
+// requests as fast as they're answered.
+//
+// Run it with:
+//
+//     cargo run --release --bin loadtest -- --slow 8 --clients 16
+
+use server::{
+    OverflowPolicy, Params, PoolConfig, Request, Response, Router, Server, StatusCode, ThreadPool,
+};
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: loadtest [OPTIONS]
+
+Options:
+    --threads N       workers in the server's pool [4]
+    --clients N       clients making requests as fast as they can [16]
+    --slow N          clients that trickle in a header a second [8]
+    --secs N          how long to run each model for [5]
+    --keep-alive      reuse connections instead of opening one per request
+";
+
+struct Options {
+    threads: usize,
+    clients: usize,
+    slow: usize,
+    duration: Duration,
+    keep_alive: bool,
+}
+
+// What the fast clients saw.
+struct Results {
+    latencies: Vec<Duration>,
+    errors: usize,
+    elapsed: Duration,
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|problem| {
+        eprintln!("{}\n\n{}", problem, USAGE);
+        process::exit(2);
+    });
+
+    println!(
+        "{} workers, {} clients, {} slow clients, {}s per model, {}",
+        options.threads,
+        options.clients,
+        options.slow,
+        options.duration.as_secs(),
+        if options.keep_alive {
+            "keep-alive"
+        } else {
+            "a connection per request"
+        }
+    );
+    println!(
+        "{:<10} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "model", "requests", "req/s", "p50", "p99", "errors"
+    );
+
+    report("blocking", &run(&options, false));
+    #[cfg(target_os = "linux")]
+    report("epoll", &run(&options, true));
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        threads: 4,
+        clients: 16,
+        slow: 8,
+        duration: Duration::from_secs(5),
+        keep_alive: false,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        if flag == "--keep-alive" {
+            options.keep_alive = true;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        let number: usize = value
+            .parse()
+            .map_err(|_| format!("{} should be a whole number, not {:?}", flag, value))?;
+
+        match flag.as_str() {
+            "--threads" => options.threads = number,
+            "--clients" => options.clients = number,
+            "--slow" => options.slow = number,
+            "--secs" => options.duration = Duration::from_secs(number as u64),
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn run(options: &Options, event_loop: bool) -> Results {
+    // The same pool the binary starts with, give or take the size.
+    let pool = ThreadPool::with_config(PoolConfig {
+        queue_capacity: Some(64),
+        overflow: OverflowPolicy::Reject,
+        ..PoolConfig::new(options.threads)
+    })
+    .unwrap();
+    let router = Router::new().get("/", |_: &Request, _: &Params| {
+        Response::new(StatusCode::Ok).with_body("Hello!")
+    });
+
+    let server = Server::bind("127.0.0.1:0", pool, router).unwrap();
+    #[cfg(target_os = "linux")]
+    let server = if event_loop {
+        server.with_event_loop().unwrap()
+    } else {
+        server
+    };
+    #[cfg(not(target_os = "linux"))]
+    let _ = event_loop;
+
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let slow: Vec<_> = (0..options.slow)
+        .map(|_| {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || trickle(addr, &stop))
+        })
+        .collect();
+    // Let the slow clients settle in before measuring.
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let keep_alive = options.keep_alive;
+    let clients: Vec<_> = (0..options.clients)
+        .map(|_| thread::spawn(move || hammer(addr, deadline, keep_alive)))
+        .collect();
+
+    let mut results = Results {
+        latencies: Vec::new(),
+        errors: 0,
+        elapsed: Duration::from_secs(0),
+    };
+    for client in clients {
+        let (latencies, errors) = client.join().unwrap();
+        results.latencies.extend(latencies);
+        results.errors += errors;
+    }
+    results.elapsed = start.elapsed();
+
+    // Hang up the slow clients first, so the blocking model's workers are
+    // free to notice the shutdown.
+    stop.store(true, Ordering::SeqCst);
+    for client in slow {
+        client.join().unwrap();
+    }
+    handle.shutdown();
+    running.join().unwrap();
+
+    results
+}
+
+// Start a request and send another header every second, never finishing it.
+fn trickle(addr: SocketAddr, stop: &AtomicBool) {
+    let mut stream = match TcpStream::connect(addr) {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+    if stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n").is_err() {
+        return;
+    }
+
+    let mut last = Instant::now();
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(50));
+        if last.elapsed() >= Duration::from_secs(1) {
+            if stream.write_all(b"X-Slow: 1\r\n").is_err() {
+                return;
+            }
+            last = Instant::now();
+        }
+    }
+}
+
+// Make requests until the deadline, returning how long each successful one
+// took and how many failed.
+fn hammer(addr: SocketAddr, deadline: Instant, keep_alive: bool) -> (Vec<Duration>, usize) {
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    let mut connection = None;
+
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        match request(addr, &mut connection, keep_alive) {
+            Ok(true) => latencies.push(start.elapsed()),
+            Ok(false) | Err(_) => {
+                errors += 1;
+                connection = None;
+                // Don't spin on a server that's turning us away.
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        if !keep_alive {
+            connection = None;
+        }
+    }
+
+    (latencies, errors)
+}
+
+// Send one request, on `connection` if there is one, and read the response.
+// Returns whether it was a 200.
+fn request(
+    addr: SocketAddr,
+    connection: &mut Option<TcpStream>,
+    keep_alive: bool,
+) -> io::Result<bool> {
+    if connection.is_none() {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        *connection = Some(stream);
+    }
+    let stream = connection.as_mut().unwrap();
+
+    let close = if keep_alive {
+        ""
+    } else {
+        "Connection: close\r\n"
+    };
+    // In one write, or Nagle's algorithm holds the end of it back.
+    let request = format!("GET / HTTP/1.1\r\nHost: x\r\n{}\r\n", close);
+    stream.write_all(request.as_bytes())?;
+
+    // Read the head, then as much body as it says there is.
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    let head_len = loop {
+        if let Some(i) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        match stream.read(&mut buf)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => response.extend_from_slice(&buf[..n]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&response[..head_len]).to_ascii_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0);
+    while response.len() < head_len + content_length {
+        match stream.read(&mut buf)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => response.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    Ok(head.starts_with("http/1.1 200 "))
+}
+
+fn report(model: &str, results: &Results) {
+    let mut latencies = results.latencies.clone();
+    latencies.sort();
+    let percentile = |p: usize| match latencies.len() {
+        0 => String::from("-"),
+        len => format!(
+            "{:.1}ms",
+            latencies[(len - 1) * p / 100].as_secs_f64() * 1000.0
+        ),
+    };
+
+    println!(
+        "{:<10} {:>9} {:>9.0} {:>9} {:>9} {:>9}",
+        model,
+        latencies.len(),
+        latencies.len() as f64 / results.elapsed.as_secs_f64(),
+        percentile(50),
+        percentile(99),
+        results.errors
+    );
+}
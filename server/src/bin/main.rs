@@ -1,76 +1,165 @@
-use server::ThreadPool;
-use std::fs;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
+#[cfg(target_os = "linux")]
+use server::Acceptor;
+use server::{
+    shutdown_on_signals, Handler, Logger, Metrics, Mode, Params, PoolConfig, PoolStats, Recover,
+    Request, RequestId, Router, Server, ServerConfig, StaticFiles, ThreadPool, Timing, WebSocket,
+};
+#[cfg(feature = "tls")]
+use server::{Certificate, TlsConfig};
+use std::io;
+use std::process;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 fn main() {
-    // Bind the TcpListener to local IP on port 7878. The bind function
-    // is similar to ::new in that it returns a new instance of a TcpListener.
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
-
-    // The incoming method returns an iterator of TcpStreams. A single stream
-    // is a connection between client and server. A connection is the name for the
-    // full request / response process.
-    //
-    // To simulate graceful shutdown, we only take two incoming TCP connections.
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
-
-        // Spawn a new connection for each incoming request (connection).
-        // This allows a new thread to handle each connection.
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    // Settings come from flags, SERVER_* environment variables and an
+    // optional config file; see --help.
+    let (config, mode) = ServerConfig::from_env().unwrap_or_else(|err| {
+        eprintln!("Problem with the configuration: {}", err);
+        process::exit(2);
+    });
+
+    match mode {
+        Mode::Help => {
+            print!("{}", ServerConfig::USAGE);
+            return;
+        }
+        Mode::CheckConfig => {
+            println!("Configuration is valid:\n{:#?}", config);
+            return;
+        }
+        Mode::Serve => {}
     }
-}
 
-// Note that we need to mark this parameter as mutable because a TcpStream instance
-// internally mutates the data it returns to us.
-fn handle_connection(mut stream: TcpStream) {
-    // Declare a buffer 1024 bytes in length. Handling requests of an arbitrary size
-    // is a more complex process.
-    let mut buffer = [0; 1024];
-
-    // Read bytes from the stream and put them in the buffer.
-    stream.read(&mut buffer).unwrap();
-
-    // Print the request as a String. The lossy suffix below indicates that Rust should
-    // use the U+FFDD REPLACEMENT CHARACTER, �, when it encounters invalid UTF-8.
-    println!("Request: {}", String::from_utf8_lossy(&buffer[..]));
-
-    // b transforms the string literal into a byte string.
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-
-    // Ensure that the request is made only to /, otherwise 404.
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
+    // Everything that's logged is also counted, for the /metrics route.
+    let metrics = Metrics::new();
+    let logger = Logger::stdout(config.log_format).and(metrics.logger());
+
+    // By default, four workers growing to sixteen when connections start
+    // backing up, with room for 64 connections to wait for one. Past that,
+    // new connections get a 503 rather than piling up.
+    let pool = ThreadPool::with_config(PoolConfig {
+        logger: logger.clone(),
+        ..config.pool_config()
+    })
+    .unwrap_or_else(|err| {
+        eprintln!("Problem creating the thread pool: {}", err);
+        process::exit(1);
+    });
+
+    // Every request gets an ID that follows it into the access log, and a
+    // handler that panics answers with a 500 rather than dropping the
+    // connection.
+    let router = routes(&config, &metrics, pool.stats())
+        .wrap(RequestId::new())
+        .wrap(Recover::new(logger.clone()))
+        .wrap(Timing);
+
+    // The server owns the TcpListener and hands each incoming connection to
+    // the pool.
+    let server = Server::bind(config.bind, pool, router)
+        .unwrap_or_else(|err| {
+            eprintln!("Problem listening on {}: {}", config.bind, err);
+            process::exit(1);
+        })
+        .with_connection_config(config.connection_config())
+        .with_logger(logger.clone())
+        .with_shutdown_timeout(config.shutdown_timeout);
+
+    // With a certificate, every connection is HTTPS.
+    #[cfg(feature = "tls")]
+    let server = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let cert = Certificate::from_pem_files(cert, key).unwrap_or_else(|err| {
+                eprintln!("Problem loading the TLS certificate: {}", err);
+                process::exit(1);
+            });
+            server.with_tls(TlsConfig::new(cert)).unwrap_or_else(|err| {
+                eprintln!("Problem setting up TLS: {}", err);
+                process::exit(1);
+            })
+        }
+        _ => server,
+    };
+
+    // Validation has already turned epoll down anywhere but Linux.
+    #[cfg(target_os = "linux")]
+    let server = match config.acceptor {
+        Acceptor::Epoll => server.with_event_loop().unwrap_or_else(|err| {
+            eprintln!("Problem setting up the event loop: {}", err);
+            process::exit(1);
+        }),
+        Acceptor::Blocking => server,
     };
 
-    let contents = fs::read_to_string(filename).unwrap();
+    // Ctrl-C (SIGINT) or SIGTERM asks the server to stop accepting connections
+    // and lets the jobs already running finish.
+    shutdown_on_signals(&server.shutdown_handle(), logger).unwrap_or_else(|err| {
+        eprintln!("Problem installing signal handlers: {}", err);
+        process::exit(1);
+    });
+
+    let report = server.run();
 
-    let response = format!(
-        "{}\r\n Content-Length: {} \r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
+    if report.is_clean() {
+        println!("Shut down cleanly.");
+    } else {
+        println!(
+            "Shut down with workers {:?} and {} WebSockets still running.",
+            report.timed_out, report.upgraded_timed_out
+        );
+    }
+}
+
+fn routes(config: &ServerConfig, metrics: &Metrics, pool: PoolStats) -> Router {
+    let files = Arc::new(
+        StaticFiles::new(&config.root)
+            .index(config.index.as_str())
+            .not_found_page(config.not_found.as_str()),
     );
+    let index = format!("/{}", config.index);
+
+    let hello = Arc::clone(&files);
+    let hello_page = index.clone();
+    let sleep = Arc::clone(&files);
+    let live = metrics.clone();
+    let live_pool = pool.clone();
 
-    // Write the response back to the stream.
-    stream.write(response.as_bytes()).unwrap();
+    let router = Router::new()
+        .get("/metrics", metrics.handler(pool))
+        .cache_control("no-store")
+        // The same metrics, pushed over a WebSocket every second for
+        // dashboards to draw.
+        .get("/metrics/live", move |request: &Request, _: &Params| {
+            let (metrics, pool) = (live.clone(), live_pool.clone());
+            WebSocket::upgrade(request, move |mut socket| loop {
+                socket.send(metrics.render(Some(&pool)))?;
+                match socket.recv_timeout(Duration::from_secs(1)) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => return Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                    Err(e) => return Err(e),
+                }
+            })
+        })
+        .get("/", move |request: &Request, _: &Params| {
+            hello.serve_for(request, &hello_page)
+        })
+        .cache_control("no-cache")
+        .get("/sleep", move |request: &Request, _: &Params| {
+            thread::sleep(Duration::from_secs(5));
+            sleep.serve_for(request, &index)
+        })
+        .cache_control("no-cache")
+        .get("/*path", move |request: &Request, params: &Params| {
+            files.handle(request, params)
+        });
 
-    // Calling the flush method will prevent the program from continuing until all
-    // bytes are written to the connection.
-    stream.flush().unwrap();
+    match &config.cache_control {
+        Some(policy) => router.cache_control(policy.as_str()),
+        None => router,
+    }
 }
 
 // HTTP is a text-based protocol, with a request taking the form:
@@ -0,0 +1,226 @@
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{self, Write};
+
+/// A `Content-Encoding` the server can compress responses with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format, which is what HTTP means by `deflate`.
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Compress `bytes` in one go.
+    pub fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let level = flate2::Compression::default();
+
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Pick the encoding from `offered` that an `Accept-Encoding` header likes
+/// best, or `None` if the client should get the response as it is.
+///
+/// Each coding in the header can carry a weight from 0 to 1, as in
+/// `gzip;q=0.8, deflate;q=0.5`; a weight of 0 means "not acceptable". Codings
+/// that aren't mentioned get the weight of `*`, or 0 if there's no `*`. When
+/// two codings are liked equally, the one earlier in `offered` wins.
+pub fn negotiate(accept_encoding: Option<&str>, offered: &[Encoding]) -> Option<Encoding> {
+    // No header means the client will take anything, but since it didn't
+    // ask, it doesn't get compression it may not be able to undo.
+    let header = accept_encoding?;
+    let preferences = parse_accept_encoding(header);
+
+    let named = |name: &str| {
+        preferences
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(name))
+            .map(|(_, q)| *q)
+    };
+    let wildcard = named("*").unwrap_or(0);
+
+    let mut best: Option<(Encoding, u16)> = None;
+    for &encoding in offered {
+        let mut q = named(encoding.as_str());
+        if encoding == Encoding::Gzip {
+            // Some older clients still send the name from before gzip was
+            // registered.
+            q = q.max(named("x-gzip"));
+        }
+        let q = q.unwrap_or(wildcard);
+
+        if q > 0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+// Split an Accept-Encoding header into codings and their weights, in
+// thousandths so they can be compared exactly.
+fn parse_accept_encoding(header: &str) -> Vec<(String, u16)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim();
+            if coding.is_empty() {
+                return None;
+            }
+
+            let mut q = 1000;
+            for param in parts {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        // A weight we can't read is treated as a refusal
+                        // rather than a guess.
+                        q = parse_qvalue(value.trim()).unwrap_or(0);
+                    }
+                }
+            }
+
+            Some((coding.to_string(), q))
+        })
+        .collect()
+}
+
+// A qvalue is 0 or 1 with up to three decimal places.
+fn parse_qvalue(value: &str) -> Option<u16> {
+    let (whole, fraction) = match value.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (value, ""),
+    };
+
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths: u16 = format!("{:0<3}", fraction).parse().ok()?;
+
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Whether a response of this `Content-Type` is worth compressing. Images,
+/// audio, video and archives are already compressed, so only text-like types
+/// are.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/x-icon"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    const BOTH: [Encoding; 2] = [Encoding::Gzip, Encoding::Deflate];
+
+    #[test]
+    fn picks_the_highest_weight() {
+        assert_eq!(negotiate(None, &BOTH), None);
+        assert_eq!(
+            negotiate(Some("gzip, deflate"), &BOTH),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate(Some("gzip;q=0.5, deflate"), &BOTH),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate(Some("br"), &BOTH), None);
+        assert_eq!(negotiate(Some("identity"), &BOTH), None);
+        assert_eq!(negotiate(Some("x-gzip"), &BOTH), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("GZIP;Q=1.0"), &BOTH), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn the_wildcard_covers_everything_not_mentioned() {
+        assert_eq!(negotiate(Some("*"), &BOTH), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate(Some("gzip;q=0, *;q=0.1"), &BOTH),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate(Some("*;q=0"), &BOTH), None);
+        assert_eq!(
+            negotiate(Some("deflate;q=0.2, *"), &[Encoding::Deflate]),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn reads_qvalues_strictly() {
+        assert_eq!(parse_qvalue("1"), Some(1000));
+        assert_eq!(parse_qvalue("0.5"), Some(500));
+        assert_eq!(parse_qvalue("0.125"), Some(125));
+        assert_eq!(parse_qvalue("1.5"), None);
+        assert_eq!(parse_qvalue("0.1234"), None);
+        assert_eq!(parse_qvalue("high"), None);
+    }
+
+    #[test]
+    fn compressed_bodies_decompress() {
+        let text = "hello hello hello hello hello hello".repeat(20);
+
+        let gzipped = Encoding::Gzip.compress(text.as_bytes()).unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+        assert!(gzipped.len() < text.len());
+
+        let deflated = Encoding::Deflate.compress(text.as_bytes()).unwrap();
+        let mut decoded = String::new();
+        ZlibDecoder::new(&deflated[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn only_text_like_types_are_compressible() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+    }
+}
@@ -0,0 +1,812 @@
+use crate::connection::ConnectionConfig;
+use crate::logging::LogFormat;
+use crate::pool::{OverflowPolicy, PoolConfig};
+use crate::request::RequestLimits;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// Every setting, by the name it goes by in a config file. Flags use the same
+// names with dashes (`--max-threads`), and environment variables put them in
+// capitals after `SERVER_` (`SERVER_MAX_THREADS`).
+const KEYS: [&str; 20] = [
+    "bind",
+    "threads",
+    "max_threads",
+    "queue_capacity",
+    "overflow",
+    "root",
+    "index",
+    "not_found",
+    "cache_control",
+    "tls_cert",
+    "tls_key",
+    "acceptor",
+    "log_format",
+    "idle_timeout_secs",
+    "read_timeout_secs",
+    "write_timeout_secs",
+    "request_timeout_secs",
+    "max_header_bytes",
+    "max_body_bytes",
+    "shutdown_timeout_secs",
+];
+
+/// Settings for the server binary.
+///
+/// Settings are read from, in increasing order of precedence: the defaults,
+/// a TOML file named by `--config` or `SERVER_CONFIG`, `SERVER_*`
+/// environment variables and command-line flags.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The address to listen on.
+    pub bind: SocketAddr,
+    /// Worker threads to start with.
+    pub threads: usize,
+    /// The most worker threads to grow to under load, or `None` for a pool
+    /// that stays at `threads`.
+    pub max_threads: Option<usize>,
+    /// The most connections that can wait for a worker, or `None` for no
+    /// limit.
+    pub queue_capacity: Option<usize>,
+    /// What to do with connections that arrive while the queue is full.
+    pub overflow: OverflowPolicy,
+    /// The directory to serve files from.
+    pub root: PathBuf,
+    /// The file under `root` served for `/`.
+    pub index: String,
+    /// The file under `root` sent with 404 responses.
+    pub not_found: String,
+    /// The `Cache-Control` policy for files under `root`, or `None` to send
+    /// none. The index page is always revalidated.
+    pub cache_control: Option<String>,
+    /// A PEM file with the certificate chain to serve HTTPS with, or `None`
+    /// to serve plain HTTP. Needs the `tls` feature.
+    pub tls_cert: Option<PathBuf>,
+    /// A PEM file with the private key for `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// How connections are accepted and read.
+    pub acceptor: Acceptor,
+    pub log_format: LogFormat,
+    /// How long an idle keep-alive connection is kept open.
+    pub idle_timeout: Duration,
+    /// How long a client can go quiet partway through a request.
+    pub read_timeout: Duration,
+    /// How long writing a response can be stuck on a client that isn't
+    /// reading.
+    pub write_timeout: Duration,
+    /// How long a client gets to send a whole request.
+    pub request_timeout: Duration,
+    /// How big a request's line and headers can be, in bytes.
+    pub max_header_bytes: usize,
+    /// How big a request's body can be, in bytes.
+    pub max_body_bytes: usize,
+    /// How long running requests get to finish when shutting down.
+    pub shutdown_timeout: Duration,
+}
+
+/// How the server waits on its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acceptor {
+    /// Each connection gets a worker of its own for as long as it's open.
+    Blocking,
+    /// One thread watches every connection with epoll, and workers only see
+    /// complete requests. Linux only, and not with TLS.
+    Epoll,
+}
+
+/// What the binary has been asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Serve,
+    /// Check the configuration and exit.
+    CheckConfig,
+    /// Print [`ServerConfig::USAGE`] and exit.
+    Help,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 7878)),
+            threads: 4,
+            max_threads: Some(16),
+            queue_capacity: Some(64),
+            overflow: OverflowPolicy::Reject,
+            // The public directory next to Cargo.toml, no matter which
+            // directory the server is started from.
+            root: Path::new(env!("CARGO_MANIFEST_DIR")).join("public"),
+            index: String::from("hello.html"),
+            not_found: String::from("404.html"),
+            cache_control: Some(String::from("public, max-age=300")),
+            tls_cert: None,
+            tls_key: None,
+            acceptor: Acceptor::Blocking,
+            log_format: LogFormat::Human,
+            idle_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            max_header_bytes: 8 * 1024,
+            max_body_bytes: 1024 * 1024,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub const USAGE: &'static str = "\
+Usage: main [OPTIONS]
+
+Options:
+    --config FILE                 read settings from a TOML file
+    --bind ADDR                   address to listen on [127.0.0.1:7878]
+    --threads N                   worker threads to start with [4]
+    --max-threads N|none          most worker threads under load [16]
+    --queue-capacity N|none       most connections waiting for a worker [64]
+    --overflow POLICY             block, reject, drop-oldest or caller-runs [reject]
+    --root DIR                    directory to serve files from [public]
+    --index FILE                  file under the root served for / [hello.html]
+    --not-found FILE              file under the root sent with 404s [404.html]
+    --cache-control POLICY|none   Cache-Control for files [public, max-age=300]
+    --tls-cert FILE|none          serve HTTPS with this PEM certificate chain [none]
+    --tls-key FILE|none           the PEM private key for --tls-cert [none]
+    --acceptor blocking|epoll     a worker per connection, or an epoll event loop [blocking]
+    --log-format human|json       how to write the log [human]
+    --idle-timeout-secs N         how long to keep idle connections open [5]
+    --read-timeout-secs N         how long a client can stall mid-request [10]
+    --write-timeout-secs N        how long a write can wait on a client [10]
+    --request-timeout-secs N      how long a client gets to send a request [30]
+    --max-header-bytes N          largest request line and headers [8192]
+    --max-body-bytes N            largest request body [1048576]
+    --shutdown-timeout-secs N     how long requests get to finish on shutdown [30]
+    --check-config                check the configuration and exit
+    --help                        print this message and exit
+
+Each option can also be set in the config file, using underscores instead of
+dashes (max_threads = 16), or in an environment variable (SERVER_MAX_THREADS).
+Flags override environment variables, which override the config file.
+";
+
+    /// Load the configuration from the process's arguments and environment.
+    pub fn from_env() -> Result<(ServerConfig, Mode), ConfigError> {
+        ServerConfig::load(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    /// Load the configuration from `args`, not including the program name,
+    /// and the environment variables `var` looks up. The result has been
+    /// [validated](ServerConfig::validate).
+    pub fn load<I>(
+        args: I,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<(ServerConfig, Mode), ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut mode = Mode::Serve;
+        let mut file = var("SERVER_CONFIG").map(PathBuf::from);
+        let mut flags = Vec::new();
+
+        // Work out what the flags say first: they might name a config file,
+        // which has to be applied before them.
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name,
+                None => return Err(ConfigError::UnexpectedArgument(arg)),
+            };
+
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (name.to_string(), None),
+            };
+
+            match name.as_str() {
+                "help" => mode = Mode::Help,
+                "check-config" => mode = Mode::CheckConfig,
+                _ => {
+                    let flag = format!("--{}", name);
+                    let value = match value.or_else(|| args.next()) {
+                        Some(value) => value,
+                        None => return Err(ConfigError::MissingValue(flag)),
+                    };
+
+                    if name == "config" {
+                        file = Some(PathBuf::from(value));
+                    } else {
+                        flags.push((name.replace('-', "_"), value, Source::Flag(flag)));
+                    }
+                }
+            }
+        }
+
+        let mut config = ServerConfig::default();
+        if mode == Mode::Help {
+            return Ok((config, mode));
+        }
+
+        if let Some(path) = file {
+            config.apply_file(&path)?;
+        }
+
+        for key in KEYS {
+            let name = format!("SERVER_{}", key.to_uppercase());
+            if let Some(value) = var(&name) {
+                config.set(key, &value, &Source::Env(name))?;
+            }
+        }
+
+        for (key, value, source) in flags {
+            config.set(&key, &value, &source)?;
+        }
+
+        config.validate()?;
+        Ok((config, mode))
+    }
+
+    /// Check that the settings make sense together and that the files they
+    /// name are there.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.threads == 0 {
+            problems.push(String::from("threads must be at least 1"));
+        }
+        if let Some(max) = self.max_threads {
+            if max < self.threads {
+                problems.push(format!(
+                    "max_threads ({}) can't be less than threads ({})",
+                    max, self.threads
+                ));
+            }
+        }
+        if self.queue_capacity == Some(0) {
+            problems.push(String::from(
+                "queue_capacity must be at least 1, or none for no limit",
+            ));
+        }
+        for (key, timeout) in [
+            ("idle_timeout_secs", self.idle_timeout),
+            ("read_timeout_secs", self.read_timeout),
+            ("write_timeout_secs", self.write_timeout),
+            ("request_timeout_secs", self.request_timeout),
+        ] {
+            if timeout.is_zero() {
+                problems.push(format!("{} must be at least 1", key));
+            }
+        }
+        if self.max_header_bytes == 0 {
+            problems.push(String::from("max_header_bytes must be at least 1"));
+        }
+
+        if !self.root.is_dir() {
+            problems.push(format!("root {} is not a directory", self.root.display()));
+        } else {
+            for (key, file) in [("index", &self.index), ("not_found", &self.not_found)] {
+                if !self.root.join(file).is_file() {
+                    problems.push(format!(
+                        "{} file {} doesn't exist under {}",
+                        key,
+                        file,
+                        self.root.display()
+                    ));
+                }
+            }
+        }
+
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                if cfg!(not(feature = "tls")) {
+                    problems.push(String::from(
+                        "tls_cert needs the server built with --features tls",
+                    ));
+                }
+                for (key, file) in [("tls_cert", cert), ("tls_key", key)] {
+                    if !file.is_file() {
+                        problems.push(format!("{} file {} doesn't exist", key, file.display()));
+                    }
+                }
+            }
+            (None, None) => {}
+            _ => problems.push(String::from("tls_cert and tls_key must be set together")),
+        }
+
+        if self.acceptor == Acceptor::Epoll {
+            if cfg!(not(target_os = "linux")) {
+                problems.push(String::from("acceptor epoll is only available on Linux"));
+            }
+            if self.tls_cert.is_some() {
+                problems.push(String::from("acceptor epoll can't serve TLS"));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Settings for the server's thread pool.
+    pub fn pool_config(&self) -> PoolConfig {
+        PoolConfig {
+            max_size: self.max_threads,
+            queue_capacity: self.queue_capacity,
+            overflow: self.overflow,
+            ..PoolConfig::new(self.threads)
+        }
+    }
+
+    /// Settings for each connection the server serves.
+    pub fn connection_config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            idle_timeout: self.idle_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            request_timeout: self.request_timeout,
+            limits: RequestLimits {
+                max_head: self.max_header_bytes,
+                max_body: self.max_body_bytes,
+            },
+            ..ConnectionConfig::default()
+        }
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let table: toml::Table = text.parse().map_err(|error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        for (key, value) in &table {
+            let source = Source::File(path.to_path_buf());
+
+            // Everything is read as text, the same as flags and environment
+            // variables, so the rules for each setting live in one place.
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(n) => n.to_string(),
+                other => {
+                    return Err(ConfigError::BadValue {
+                        key: key.clone(),
+                        value: other.to_string(),
+                        source,
+                        expected: "a string or a whole number",
+                    })
+                }
+            };
+
+            self.set(key, &value, &source)?;
+        }
+
+        // A relative path in a config file means relative to the file, not
+        // to wherever the server happens to be started.
+        if let Some(dir) = path.parent() {
+            if table.contains_key("root") && self.root.is_relative() {
+                self.root = dir.join(&self.root);
+            }
+            for (key, file) in [
+                ("tls_cert", &mut self.tls_cert),
+                ("tls_key", &mut self.tls_key),
+            ] {
+                if let Some(file) = file.as_mut().filter(|_| table.contains_key(key)) {
+                    if file.is_relative() {
+                        *file = dir.join(&*file);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str, source: &Source) -> Result<(), ConfigError> {
+        let bad = |expected: &'static str| ConfigError::BadValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            source: source.clone(),
+            expected,
+        };
+
+        match key {
+            "bind" => {
+                self.bind = value
+                    .parse()
+                    .map_err(|_| bad("an address and port, like 127.0.0.1:7878"))?
+            }
+            "threads" => self.threads = parse(value).ok_or_else(|| bad("a whole number"))?,
+            "max_threads" => {
+                self.max_threads =
+                    parse_limit(value).ok_or_else(|| bad("a whole number or none"))?
+            }
+            "queue_capacity" => {
+                self.queue_capacity =
+                    parse_limit(value).ok_or_else(|| bad("a whole number or none"))?
+            }
+            "overflow" => {
+                self.overflow = match value {
+                    "block" => OverflowPolicy::Block,
+                    "reject" => OverflowPolicy::Reject,
+                    "drop-oldest" => OverflowPolicy::DropOldest,
+                    "caller-runs" => OverflowPolicy::CallerRuns,
+                    _ => return Err(bad("block, reject, drop-oldest or caller-runs")),
+                }
+            }
+            "root" => self.root = PathBuf::from(value),
+            "index" => self.index = value.to_string(),
+            "not_found" => self.not_found = value.to_string(),
+            "cache_control" => {
+                self.cache_control = match value.trim() {
+                    "none" => None,
+                    policy => Some(policy.to_string()),
+                }
+            }
+            "tls_cert" => self.tls_cert = parse_path(value),
+            "tls_key" => self.tls_key = parse_path(value),
+            "acceptor" => {
+                self.acceptor = match value {
+                    "blocking" => Acceptor::Blocking,
+                    "epoll" => Acceptor::Epoll,
+                    _ => return Err(bad("blocking or epoll")),
+                }
+            }
+            "log_format" => {
+                self.log_format = match value {
+                    "human" => LogFormat::Human,
+                    "json" => LogFormat::Json,
+                    _ => return Err(bad("human or json")),
+                }
+            }
+            "idle_timeout_secs" => {
+                self.idle_timeout = parse(value)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| bad("a whole number of seconds"))?
+            }
+            "read_timeout_secs" => {
+                self.read_timeout = parse(value)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| bad("a whole number of seconds"))?
+            }
+            "write_timeout_secs" => {
+                self.write_timeout = parse(value)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| bad("a whole number of seconds"))?
+            }
+            "request_timeout_secs" => {
+                self.request_timeout = parse(value)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| bad("a whole number of seconds"))?
+            }
+            "max_header_bytes" => {
+                self.max_header_bytes = parse(value).ok_or_else(|| bad("a whole number"))?
+            }
+            "max_body_bytes" => {
+                self.max_body_bytes = parse(value).ok_or_else(|| bad("a whole number"))?
+            }
+            "shutdown_timeout_secs" => {
+                self.shutdown_timeout = parse(value)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| bad("a whole number of seconds"))?
+            }
+            _ => {
+                return Err(ConfigError::UnknownKey {
+                    key: key.to_string(),
+                    source: source.clone(),
+                })
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Option<T> {
+    value.trim().parse().ok()
+}
+
+// A limit that can be switched off.
+fn parse_limit(value: &str) -> Option<Option<usize>> {
+    match value.trim() {
+        "none" => Some(None),
+        value => parse(value).map(Some),
+    }
+}
+
+// A file that can be left out.
+fn parse_path(value: &str) -> Option<PathBuf> {
+    match value.trim() {
+        "none" => None,
+        value => Some(PathBuf::from(value)),
+    }
+}
+
+/// Where a setting came from, so errors can point at it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File(PathBuf),
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Env(name) => write!(f, "environment variable {}", name),
+            Source::Flag(flag) => write!(f, "{}", flag),
+        }
+    }
+}
+
+/// The ways loading a [`ServerConfig`] can fail.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read.
+    Read { path: PathBuf, error: io::Error },
+    /// The config file isn't valid TOML.
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    /// A setting that doesn't exist.
+    UnknownKey { key: String, source: Source },
+    /// A setting was given a value it can't take.
+    BadValue {
+        key: String,
+        value: String,
+        source: Source,
+        expected: &'static str,
+    },
+    /// A flag was given without a value.
+    MissingValue(String),
+    /// Something on the command line that isn't a flag.
+    UnexpectedArgument(String),
+    /// Each setting is fine on its own, but not all together.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => {
+                write!(f, "couldn't read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse { path, error } => {
+                write!(f, "{} isn't valid TOML: {}", path.display(), error)
+            }
+            ConfigError::UnknownKey { key, source } => {
+                write!(f, "unknown setting {} (from {})", key, source)
+            }
+            ConfigError::BadValue {
+                key,
+                value,
+                source,
+                expected,
+            } => write!(
+                f,
+                "{} should be {}, not {:?} (from {})",
+                key, expected, value, source
+            ),
+            ConfigError::MissingValue(flag) => write!(f, "{} needs a value", flag),
+            ConfigError::UnexpectedArgument(arg) => write!(f, "unexpected argument {:?}", arg),
+            ConfigError::Invalid(problems) => write!(f, "{}", problems.join("; ")),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read { error, .. } => Some(error),
+            ConfigError::Parse { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(args: &[&str], vars: &[(&str, &str)]) -> Result<(ServerConfig, Mode), ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        ServerConfig::load(args.iter().map(|arg| arg.to_string()), |name| {
+            vars.get(name).cloned()
+        })
+    }
+
+    // Write a config file that's removed when the test is done with it.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> TempFile {
+            let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        let (config, mode) = load(&[], &[]).unwrap();
+
+        assert_eq!(mode, Mode::Serve);
+        assert_eq!(config.bind.to_string(), "127.0.0.1:7878");
+        assert_eq!(config.threads, 4);
+    }
+
+    #[test]
+    fn flags_beat_the_environment_which_beats_the_file() {
+        let file = TempFile::new(
+            "precedence.toml",
+            "threads = 2\nmax_threads = 8\nlog_format = \"json\"\n",
+        );
+
+        let (config, _) = load(
+            &["--config", file.path(), "--max-threads=none"],
+            &[("SERVER_THREADS", "3"), ("SERVER_MAX_THREADS", "6")],
+        )
+        .unwrap();
+
+        assert_eq!(config.threads, 3);
+        assert_eq!(config.max_threads, None);
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn bad_values_say_where_they_came_from() {
+        let err = load(&["--threads", "lots"], &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "threads should be a whole number, not \"lots\" (from --threads)"
+        );
+
+        let err = load(&[], &[("SERVER_OVERFLOW", "panic")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "overflow should be block, reject, drop-oldest or caller-runs, not \"panic\" \
+             (from environment variable SERVER_OVERFLOW)"
+        );
+
+        let file = TempFile::new("unknown.toml", "thread = 4\n");
+        let err = load(&["--config", file.path()], &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownKey { ref key, .. } if key == "thread"));
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let err = load(
+            &[
+                "--threads",
+                "4",
+                "--max-threads",
+                "2",
+                "--queue-capacity",
+                "0",
+                "--root",
+                "/definitely/not/here",
+            ],
+            &[],
+        )
+        .unwrap_err();
+
+        match err {
+            ConfigError::Invalid(problems) => assert_eq!(
+                problems,
+                vec![
+                    "max_threads (2) can't be less than threads (4)",
+                    "queue_capacity must be at least 1, or none for no limit",
+                    "root /definitely/not/here is not a directory",
+                ]
+            ),
+            other => panic!("expected Invalid, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_config_and_help_are_modes() {
+        assert_eq!(load(&["--check-config"], &[]).unwrap().1, Mode::CheckConfig);
+        // Help works even when the rest of the configuration wouldn't.
+        assert_eq!(
+            load(&["--help", "--threads", "0"], &[]).unwrap().1,
+            Mode::Help
+        );
+        assert!(matches!(
+            load(&["--threads"], &[]),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            load(&["serve"], &[]),
+            Err(ConfigError::UnexpectedArgument(_))
+        ));
+    }
+
+    #[test]
+    fn a_relative_root_in_a_file_is_relative_to_the_file() {
+        let dir = std::env::temp_dir().join(format!("{}-site", std::process::id()));
+        fs::create_dir_all(dir.join("files")).unwrap();
+        fs::write(dir.join("files/hello.html"), "hi").unwrap();
+        fs::write(dir.join("files/404.html"), "no").unwrap();
+        fs::write(dir.join("server.toml"), "root = \"files\"\n").unwrap();
+
+        let config = dir.join("server.toml");
+        let result = load(&["--config", config.to_str().unwrap()], &[]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result.unwrap().0.root, dir.join("files"));
+    }
+
+    #[test]
+    fn tls_files_come_in_pairs_relative_to_the_file() {
+        let err = load(&["--tls-cert", "cert.pem"], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "tls_cert and tls_key must be set together");
+
+        let dir = std::env::temp_dir().join(format!("{}-tls", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cert.pem"), "").unwrap();
+        fs::write(dir.join("key.pem"), "").unwrap();
+        fs::write(
+            dir.join("server.toml"),
+            "tls_cert = \"cert.pem\"\ntls_key = \"key.pem\"\n",
+        )
+        .unwrap();
+
+        let config = dir.join("server.toml");
+        let result = load(&["--config", config.to_str().unwrap()], &[]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        if cfg!(feature = "tls") {
+            let config = result.unwrap().0;
+            assert_eq!(config.tls_cert, Some(dir.join("cert.pem")));
+            assert_eq!(config.tls_key, Some(dir.join("key.pem")));
+        } else {
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "tls_cert needs the server built with --features tls"
+            );
+        }
+    }
+
+    #[test]
+    fn the_epoll_acceptor_is_for_plain_http_on_linux() {
+        let result = load(&["--acceptor", "epoll"], &[]);
+        if cfg!(target_os = "linux") {
+            assert_eq!(result.unwrap().0.acceptor, Acceptor::Epoll);
+        } else {
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "acceptor epoll is only available on Linux"
+            );
+        }
+
+        let err = load(&["--acceptor", "select"], &[("SERVER_ACCEPTOR", "epoll")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "acceptor should be blocking or epoll, not \"select\" (from --acceptor)"
+        );
+    }
+}
@@ -0,0 +1,798 @@
+use crate::logging::{Event, Logger};
+use crate::request::{Method, ParseError, Parser, Request, RequestLimits, Version};
+use crate::response::{Response, StatusCode};
+use crate::router::Router;
+use crate::shutdown::ShutdownHandle;
+use std::fmt;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+// How often a connection blocked on read wakes up to check whether the
+// server is shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long to keep reading from a client we've refused, so it gets to see
+// the error before the connection goes away.
+pub(crate) const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Settings that control how a single connection is served.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// How long to wait for the next request on an idle keep-alive
+    /// connection before closing it.
+    pub idle_timeout: Duration,
+    /// How long a client can go quiet partway through sending a request
+    /// before it's answered with a 408.
+    pub read_timeout: Duration,
+    /// How long writing a response can be stuck on a client that isn't
+    /// reading it before the connection is dropped.
+    pub write_timeout: Duration,
+    /// How long a client gets to send a whole request, from its first byte
+    /// to its last, however steadily it trickles in. Past that it gets a 408.
+    pub request_timeout: Duration,
+    /// How big a request's head and body can be. Bigger requests are
+    /// answered with a 431 or a 413.
+    pub limits: RequestLimits,
+    /// Where to send access logs and reports of bad requests. Silent unless
+    /// set.
+    pub logger: Logger,
+}
+
+/// A stream that requests can be served over.
+///
+/// This is implemented for `TcpStream` and, with the `tls` feature, for TLS
+/// connections on top of one, so the same router serves both. The timeouts
+/// and shutdown do nothing by default, so any other stream that reads and
+/// writes, like an in-memory pipe in a test, can be served with an empty
+/// impl.
+pub trait Transport: Read + Write {
+    /// Make a read that blocks for longer than `timeout` fail with
+    /// `WouldBlock` or `TimedOut`.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Make a write that blocks for longer than `timeout` fail with
+    /// `WouldBlock` or `TimedOut`.
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Tell the client we won't send anything more, while still being able
+    /// to read what it sends.
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// A connection that's been switched to another protocol, with everything
+/// the new protocol needs to carry on.
+pub(crate) struct Upgraded {
+    pub(crate) stream: Box<dyn Transport + Send>,
+    /// Bytes the client sent after the request that asked for the upgrade,
+    /// which were read along with it.
+    pub(crate) read_ahead: Vec<u8>,
+    pub(crate) shutdown: ShutdownHandle,
+    pub(crate) logger: Logger,
+}
+
+/// Takes over a connection after a `101 Switching Protocols` response. It
+/// runs on the worker, so anything long-lived belongs on a thread of its own,
+/// holding [`ShutdownHandle::track_upgraded`] so the server waits for it.
+pub(crate) struct Upgrade(Box<dyn FnOnce(Upgraded) -> io::Result<()> + Send>);
+
+impl Upgrade {
+    pub(crate) fn new<F>(f: F) -> Upgrade
+    where
+        F: FnOnce(Upgraded) -> io::Result<()> + Send + 'static,
+    {
+        Upgrade(Box::new(f))
+    }
+
+    pub(crate) fn start(self, upgraded: Upgraded) -> io::Result<()> {
+        (self.0)(upgraded)
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Upgrade")
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            idle_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            limits: RequestLimits::default(),
+            logger: Logger::silent(),
+        }
+    }
+}
+
+/// Serve requests from `stream` until the client is done with it.
+///
+/// Connections are persistent: HTTP/1.1 clients can send any number of
+/// requests on one stream, and can pipeline them by sending the next request
+/// before the previous response has arrived. The connection is closed when
+/// the client asks for it with `Connection: close`, when an HTTP/1.0 client
+/// doesn't ask for `Connection: keep-alive`, after a malformed request, or
+/// when the client sends nothing for `config.idle_timeout`. Once `shutdown`
+/// is triggered, the current request is finished and the connection closed.
+///
+/// A client that's too slow to send its request, or whose request is too
+/// big, is answered with an error and the connection closed, so it can't keep
+/// a worker busy indefinitely.
+///
+/// A response that switches protocols, like a WebSocket handshake, takes the
+/// stream with it and frees up the worker.
+pub fn handle_connection<S: Transport + Send + 'static>(
+    stream: S,
+    router: &Router,
+    config: &ConnectionConfig,
+    shutdown: &ShutdownHandle,
+) -> io::Result<()> {
+    // A read that blocks for longer than this fails with WouldBlock or
+    // TimedOut (depending on the platform). Rather than block for the whole
+    // idle timeout at once, wake up regularly so a shutdown isn't stuck
+    // waiting on a client that has nothing more to say.
+    stream.set_read_timeout(Some(poll_interval(config)))?;
+    stream.set_write_timeout(Some(config.write_timeout))?;
+
+    // Responses are buffered, and always flushed before we read again, so
+    // reads can go straight to the stream underneath the buffer.
+    let mut writer = BufWriter::new(stream);
+
+    // Bytes we've read but not yet parsed into a request. With pipelining
+    // this can hold several requests at once.
+    let mut buffer = Vec::new();
+    let mut parser = Parser::new(config.limits);
+    let mut chunk = [0; 4096];
+    let mut last_activity = Instant::now();
+    // When the first byte of the request we're waiting on arrived.
+    let mut request_started = None;
+
+    loop {
+        match parser.parse(&buffer) {
+            Ok(Some((mut request, used))) => {
+                buffer.drain(..used);
+                request_started = None;
+
+                match respond(&mut writer, router, &mut request, config, shutdown)? {
+                    After::KeepAlive => {}
+                    After::Close => return writer.flush(),
+                    After::Upgrade(upgrade) => {
+                        writer.flush()?;
+                        let stream = writer.into_inner().map_err(|e| e.into_error())?;
+                        return upgrade.start(Upgraded {
+                            stream: Box::new(stream),
+                            read_ahead: buffer,
+                            shutdown: shutdown.clone(),
+                            logger: config.logger.clone(),
+                        });
+                    }
+                }
+
+                // However long the response took, the clock for the next
+                // request starts now.
+                last_activity = Instant::now();
+
+                // There may already be another complete request waiting in
+                // the buffer, so try parsing again before reading.
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                config.logger.log(&Event::BadRequest { error: &e });
+
+                // We can't tell where a malformed request ends, so there's no
+                // way to find the next one. Answer and hang up.
+                return match error_status(&e) {
+                    Some(status) => refuse(&mut writer, status),
+                    None => writer.flush(),
+                };
+            }
+        }
+
+        // We need more bytes. Send off any responses we've queued up first so
+        // a pipelining client isn't left waiting while we block on read.
+        writer.flush()?;
+
+        if !buffer.is_empty() {
+            let started = *request_started.get_or_insert_with(Instant::now);
+
+            if last_activity.elapsed() >= config.read_timeout
+                || started.elapsed() >= config.request_timeout
+            {
+                let error = io::Error::new(io::ErrorKind::TimedOut, "request took too long");
+                config.logger.log(&Event::BadRequest { error: &error });
+                return refuse(&mut writer, StatusCode::RequestTimeout);
+            }
+        }
+
+        match writer.get_mut().read(&mut chunk) {
+            // The client closed its end of the connection.
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                buffer.extend_from_slice(&chunk[..n]);
+                last_activity = Instant::now();
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                // Only a connection sitting between requests is closed for
+                // shutdown or for being idle; one halfway through sending a
+                // request is timed out above.
+                let idle = shutdown.is_shutdown() || last_activity.elapsed() >= config.idle_timeout;
+                if buffer.is_empty() && idle {
+                    return Ok(());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// How often to wake up and check the clock, and for shutdown, while waiting
+// on a client.
+pub(crate) fn poll_interval(config: &ConnectionConfig) -> Duration {
+    config
+        .idle_timeout
+        .min(config.read_timeout)
+        .min(SHUTDOWN_POLL_INTERVAL)
+}
+
+// What happens to the connection after a response.
+pub(crate) enum After {
+    KeepAlive,
+    Close,
+    Upgrade(Upgrade),
+}
+
+// Route the request, write the response and work out what's next for the
+// connection.
+pub(crate) fn respond<W: Write>(
+    writer: &mut W,
+    router: &Router,
+    request: &mut Request,
+    config: &ConnectionConfig,
+    shutdown: &ShutdownHandle,
+) -> io::Result<After> {
+    let start = Instant::now();
+    let (mut response, route) = router.dispatch(request);
+
+    // Chunked encoding is new in HTTP/1.1, so older clients get the whole
+    // body at once with a Content-Length.
+    if request.version() == Version::Http10 {
+        response.buffer()?;
+    }
+
+    let after = match response.take_upgrade() {
+        // The response already says which protocol comes next.
+        Some(upgrade) => After::Upgrade(upgrade),
+        None => {
+            let keep_alive = wants_keep_alive(request)
+                && !response.headers().has_token("Connection", "close")
+                && !shutdown.is_shutdown();
+
+            // HTTP/1.1 connections are persistent by default, so we only need
+            // to say something when that's not the case. HTTP/1.0 is the
+            // other way around.
+            if !keep_alive {
+                response.headers_mut().insert("Connection", "close");
+            } else if request.version() == Version::Http10 {
+                response.headers_mut().insert("Connection", "keep-alive");
+            }
+
+            if keep_alive {
+                After::KeepAlive
+            } else {
+                After::Close
+            }
+        }
+    };
+
+    let status = response.status();
+    response.write_head_to(writer)?;
+    let bytes = if request.method() == Method::Head {
+        0
+    } else {
+        response.write_body_to(writer)?
+    };
+
+    config.logger.log(&Event::Access {
+        method: request.method().as_str(),
+        path: request.path(),
+        route,
+        status: status.code(),
+        bytes,
+        latency: start.elapsed(),
+        // Middleware may have given the request an ID on its way through.
+        request_id: request.header("X-Request-Id"),
+    });
+
+    Ok(after)
+}
+
+// Answer with an error and hang up.
+fn refuse<S: Transport>(writer: &mut BufWriter<S>, status: StatusCode) -> io::Result<()> {
+    Response::new(status)
+        .with_header("Connection", "close")
+        .write_to(writer)?;
+    writer.flush()?;
+
+    linger(writer.get_mut());
+    Ok(())
+}
+
+// Closing a socket that still has unread data in it makes the kernel reset
+// the connection, and the reset can destroy the response we just sent before
+// the client reads it. So we stop writing first, then read and throw away
+// whatever the client is still sending until it hangs up or we run out of
+// patience.
+fn linger<S: Transport>(stream: &mut S) {
+    if stream.shutdown_write().is_err() {
+        return;
+    }
+
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut discard = [0; 4096];
+
+    while Instant::now() < deadline {
+        match stream.read(&mut discard) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+fn wants_keep_alive(request: &Request) -> bool {
+    let headers = request.headers();
+
+    match request.version() {
+        Version::Http11 => !headers.has_token("Connection", "close"),
+        Version::Http10 => headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+// The response to send for a request we couldn't parse, if any.
+pub(crate) fn error_status(error: &ParseError) -> Option<StatusCode> {
+    match error {
+        // There's nobody left to answer.
+        ParseError::Io(_) | ParseError::UnexpectedEof => None,
+        ParseError::UnsupportedMethod(_) | ParseError::UnsupportedTransferEncoding(_) => {
+            Some(StatusCode::NotImplemented)
+        }
+        ParseError::UnsupportedVersion(_) => Some(StatusCode::HttpVersionNotSupported),
+        ParseError::HeadTooLarge => Some(StatusCode::RequestHeaderFieldsTooLarge),
+        ParseError::BodyTooLarge => Some(StatusCode::ContentTooLarge),
+        _ => Some(StatusCode::BadRequest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Params;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Start a connection handler on a loopback socket and return the client
+    // end of the connection.
+    fn connect(config: ConnectionConfig) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let router = Router::new().get("/:name", |_: &Request, params: &Params| {
+                Response::new(StatusCode::Ok).with_body(params.get("name").unwrap().to_string())
+            });
+            handle_connection(stream, &router, &config, &ShutdownHandle::new()).unwrap();
+        });
+
+        let client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (client, handle)
+    }
+
+    // Read until the server closes the connection.
+    fn read_all(client: &mut TcpStream) -> String {
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_pipelined_requests_in_order() {
+        let (mut client, handle) = connect(ConnectionConfig::default());
+
+        client
+            .write_all(
+                b"GET /one HTTP/1.1\r\nHost: x\r\n\r\n\
+                  GET /two HTTP/1.1\r\nHost: x\r\n\r\n\
+                  GET /three HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let response = read_all(&mut client);
+        handle.join().unwrap();
+
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+        let one = response.find("one").unwrap();
+        let two = response.find("two").unwrap();
+        let three = response.find("three").unwrap();
+        assert!(one < two && two < three);
+        assert!(response.ends_with("Connection: close\r\nContent-Length: 5\r\n\r\nthree"));
+    }
+
+    #[test]
+    fn keeps_the_connection_open_between_requests() {
+        let (mut client, handle) = connect(ConnectionConfig::default());
+        let mut buf = [0; 1024];
+
+        client
+            .write_all(b"GET /first HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let n = client.read(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).ends_with("first"));
+
+        client
+            .write_all(b"GET /second HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert!(read_all(&mut client).ends_with("second"));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn http_10_closes_unless_asked_not_to() {
+        let (mut client, handle) = connect(ConnectionConfig::default());
+
+        client.write_all(b"GET /a HTTP/1.0\r\n\r\n").unwrap();
+        let response = read_all(&mut client);
+        handle.join().unwrap();
+
+        assert!(response.contains("Connection: close\r\n"));
+
+        let (mut client, handle) = connect(ConnectionConfig::default());
+        let mut buf = [0; 1024];
+
+        client
+            .write_all(b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let n = client.read(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).contains("Connection: keep-alive\r\n"));
+
+        client.write_all(b"GET /b HTTP/1.0\r\n\r\n").unwrap();
+        assert!(read_all(&mut client).ends_with("b"));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn head_responses_have_no_body() {
+        let (mut client, handle) = connect(ConnectionConfig::default());
+
+        client
+            .write_all(b"HEAD /abc HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let response = read_all(&mut client);
+        handle.join().unwrap();
+
+        assert!(response.ends_with("Content-Length: 3\r\n\r\n"));
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let (mut client, handle) = connect(ConnectionConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..ConnectionConfig::default()
+        });
+
+        // Say nothing; the server should give up on us.
+        assert_eq!(read_all(&mut client), "");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn requests_are_logged() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&lines);
+        let (mut client, handle) = connect(ConnectionConfig {
+            logger: Logger::new(move |event: &Event| {
+                if let Event::Access {
+                    method,
+                    path,
+                    status,
+                    bytes,
+                    ..
+                } = *event
+                {
+                    let line = format!("{} {} {} {}", method, path, status, bytes);
+                    sink.lock().unwrap().push(line);
+                }
+            }),
+            ..ConnectionConfig::default()
+        });
+
+        client
+            .write_all(
+                b"GET /hello?x=1 HTTP/1.1\r\nHost: x\r\n\r\n\
+                  HEAD /hello HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        read_all(&mut client);
+        handle.join().unwrap();
+
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec!["GET /hello 200 5", "HEAD /hello 200 0"]
+        );
+    }
+
+    #[test]
+    fn streams_to_http_11_and_buffers_for_http_10() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let router = Router::new().post("/count", |request: &Request, _: &Params| {
+                let n: usize = std::str::from_utf8(request.body())
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                Response::new(StatusCode::Ok).with_chunks((1..=n).map(|i| format!("{};", i)))
+            });
+
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let config = ConnectionConfig::default();
+                handle_connection(stream, &router, &config, &ShutdownHandle::new()).unwrap();
+            }
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"POST /count HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\
+                  Connection: close\r\n\r\n1\r\n3\r\n0\r\n\r\n",
+            )
+            .unwrap();
+        let response = read_all(&mut client);
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with("\r\n\r\n6\r\n1;2;3;\r\n0\r\n\r\n"));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"POST /count HTTP/1.0\r\nContent-Length: 1\r\n\r\n2")
+            .unwrap();
+        let response = read_all(&mut client);
+        assert!(!response.contains("chunked"));
+        assert!(response.ends_with("Content-Length: 4\r\n\r\n1;2;"));
+
+        server.join().unwrap();
+    }
+
+    // A client whose whole conversation is written up front.
+    struct Scripted {
+        input: io::Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Scripted {}
+
+    #[test]
+    fn serves_any_transport() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let stream = Scripted {
+            input: io::Cursor::new(
+                b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\n\r\n".to_vec(),
+            ),
+            output: Arc::clone(&output),
+        };
+        let router = Router::new().get("/:name", |_: &Request, params: &Params| {
+            Response::new(StatusCode::Ok).with_body(params.get("name").unwrap().to_string())
+        });
+
+        handle_connection(
+            stream,
+            &router,
+            &ConnectionConfig::default(),
+            &ShutdownHandle::new(),
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(output.ends_with("\r\n\r\nb"));
+    }
+
+    #[test]
+    fn malformed_requests_get_an_error_and_close() {
+        let (mut client, handle) = connect(ConnectionConfig::default());
+
+        client
+            .write_all(b"GET / HTTP/3.0\r\nHost: x\r\n\r\nGET /ignored HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let response = read_all(&mut client);
+        // Hanging up lets the server stop waiting for us to finish.
+        drop(client);
+        handle.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        assert!(!response.contains("ignored"));
+    }
+
+    fn limited() -> ConnectionConfig {
+        ConnectionConfig {
+            read_timeout: Duration::from_millis(200),
+            request_timeout: Duration::from_millis(500),
+            limits: RequestLimits {
+                max_head: 256,
+                max_body: 16,
+            },
+            ..ConnectionConfig::default()
+        }
+    }
+
+    #[test]
+    fn clients_that_stall_mid_request_get_a_408() {
+        let (mut client, handle) = connect(limited());
+
+        client.write_all(b"GET /slow HTTP/1.1\r\nHo").unwrap();
+        let response = read_all(&mut client);
+        drop(client);
+        handle.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn clients_that_trickle_get_a_408() {
+        let (mut client, handle) = connect(limited());
+        let start = Instant::now();
+
+        // Each byte comes well within the read timeout, but the request as a
+        // whole never finishes. Keep going until the server has had enough.
+        client.set_nonblocking(true).unwrap();
+        let mut byte = [0];
+        while matches!(client.peek(&mut byte), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock)
+        {
+            assert!(start.elapsed() < Duration::from_secs(2), "never timed out");
+            client.write_all(b"a").unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+        client.set_nonblocking(false).unwrap();
+
+        let response = read_all(&mut client);
+        drop(client);
+        handle.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn oversized_headers_get_a_431() {
+        let (mut client, handle) = connect(limited());
+
+        let request = format!(
+            "GET /big HTTP/1.1\r\nHost: x\r\nX-Big: {}\r\n\r\n",
+            "a".repeat(300)
+        );
+        client.write_all(request.as_bytes()).unwrap();
+        let response = read_all(&mut client);
+        drop(client);
+        handle.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn oversized_bodies_get_a_413() {
+        let (mut client, handle) = connect(limited());
+
+        // The server answers as soon as it sees the Content-Length, and
+        // still lets us finish sending without the connection being reset.
+        client
+            .write_all(b"POST /big HTTP/1.1\r\nHost: x\r\nContent-Length: 100000\r\n\r\n")
+            .unwrap();
+        client.write_all(&[b'a'; 100_000]).unwrap();
+        let response = read_all(&mut client);
+        drop(client);
+        handle.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+
+        let (mut client, handle) = connect(limited());
+        client
+            .write_all(
+                b"POST /big HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  10\r\naaaaaaaaaaaaaaaa\r\n1\r\na\r\n0\r\n\r\n",
+            )
+            .unwrap();
+        let response = read_all(&mut client);
+        drop(client);
+        handle.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[test]
+    fn clients_that_stop_reading_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let router = Router::new().get("/forever", |_: &Request, _: &Params| {
+                Response::new(StatusCode::Ok).with_stream(|writer| loop {
+                    writer.write_all(&[b'a'; 64 * 1024])?;
+                })
+            });
+            let config = ConnectionConfig {
+                write_timeout: Duration::from_millis(200),
+                ..ConnectionConfig::default()
+            };
+
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &router, &config, &ShutdownHandle::new())
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /forever HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+
+        // Never read anything. Once the socket buffers fill up, the server's
+        // writes block and then time out.
+        let error = server.join().unwrap().unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+    }
+}
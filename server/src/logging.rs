@@ -0,0 +1,626 @@
+use std::error::Error;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Something worth knowing about that happened in the pool or the server.
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// A worker picked up a job.
+    JobStarted { worker: usize },
+    /// A worker finished a job, whether or not it panicked.
+    JobFinished { worker: usize, duration: Duration },
+    /// A job panicked. `worker` is `None` if it was run by the thread that
+    /// submitted it.
+    JobPanicked {
+        worker: Option<usize>,
+        message: &'a str,
+    },
+    /// A worker thread started, either with the pool or because jobs were
+    /// backing up.
+    WorkerStarted { worker: usize },
+    /// A worker finished the queued jobs and stopped.
+    WorkerStopped { worker: usize },
+    /// A worker in an elastic pool was idle for too long and left.
+    WorkerRetired { worker: usize },
+    /// A worker thread died, and a replacement is being started.
+    WorkerDied { worker: usize },
+    /// A worker was still busy when a shutdown's deadline passed.
+    WorkerTimedOut { worker: usize },
+    /// The pool was told to stop.
+    PoolStopping,
+    /// The server stopped accepting connections.
+    ServerStopping,
+    /// The process was sent `signal`. The first one starts a shutdown; if
+    /// `forced`, it's a second one and the process exits without waiting.
+    SignalReceived { signal: i32, forced: bool },
+    /// A request was served.
+    Access {
+        method: &'a str,
+        path: &'a str,
+        /// The pattern of the route that handled the request, or `None` if
+        /// no route matched.
+        route: Option<&'a str>,
+        status: u16,
+        /// The size of the response body.
+        bytes: usize,
+        /// From having the whole request to having written the response.
+        latency: Duration,
+        /// The request's `X-Request-Id`, if it has one.
+        request_id: Option<&'a str>,
+    },
+    /// A handler, and any middleware around it, answered a request. Logged
+    /// by the [`Logging`](crate::Logging) middleware.
+    Handled {
+        method: &'a str,
+        path: &'a str,
+        status: u16,
+        request_id: Option<&'a str>,
+        duration: Duration,
+    },
+    /// A handler panicked, and the [`Recover`](crate::Recover) middleware
+    /// answered with a 500 instead.
+    HandlerPanicked {
+        method: &'a str,
+        path: &'a str,
+        message: &'a str,
+    },
+    /// A client sent something we couldn't parse.
+    BadRequest { error: &'a dyn Error },
+    /// Something went wrong while doing `context`.
+    Error {
+        context: &'a str,
+        error: &'a dyn Error,
+    },
+}
+
+/// How much an [`Event`] matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl<'a> Event<'a> {
+    pub fn level(&self) -> Level {
+        match self {
+            Event::JobStarted { .. } | Event::JobFinished { .. } | Event::WorkerStopped { .. } => {
+                Level::Debug
+            }
+            Event::WorkerStarted { .. }
+            | Event::WorkerRetired { .. }
+            | Event::PoolStopping
+            | Event::ServerStopping
+            | Event::SignalReceived { forced: false, .. }
+            | Event::Access { .. }
+            | Event::Handled { .. } => Level::Info,
+            Event::JobPanicked { .. }
+            | Event::WorkerTimedOut { .. }
+            | Event::SignalReceived { forced: true, .. }
+            | Event::BadRequest { .. } => Level::Warn,
+            Event::WorkerDied { .. } | Event::HandlerPanicked { .. } | Event::Error { .. } => {
+                Level::Error
+            }
+        }
+    }
+
+    /// A short, stable name for the kind of event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::JobStarted { .. } => "job_started",
+            Event::JobFinished { .. } => "job_finished",
+            Event::JobPanicked { .. } => "job_panicked",
+            Event::WorkerStarted { .. } => "worker_started",
+            Event::WorkerStopped { .. } => "worker_stopped",
+            Event::WorkerRetired { .. } => "worker_retired",
+            Event::WorkerDied { .. } => "worker_died",
+            Event::WorkerTimedOut { .. } => "worker_timed_out",
+            Event::PoolStopping => "pool_stopping",
+            Event::ServerStopping => "server_stopping",
+            Event::SignalReceived { .. } => "signal_received",
+            Event::Access { .. } => "access",
+            Event::Handled { .. } => "handled",
+            Event::HandlerPanicked { .. } => "handler_panicked",
+            Event::BadRequest { .. } => "bad_request",
+            Event::Error { .. } => "error",
+        }
+    }
+
+    /// The event as a single line of JSON, without the trailing newline.
+    pub fn to_json(&self) -> String {
+        let mut json = Json::new();
+        json.field("level", self.level().as_str());
+        json.field("event", self.name());
+
+        match *self {
+            Event::JobStarted { worker }
+            | Event::WorkerStarted { worker }
+            | Event::WorkerStopped { worker }
+            | Event::WorkerRetired { worker }
+            | Event::WorkerDied { worker }
+            | Event::WorkerTimedOut { worker } => json.number("worker", worker),
+            Event::JobFinished { worker, duration } => {
+                json.number("worker", worker);
+                json.millis("duration_ms", duration);
+            }
+            Event::JobPanicked { worker, message } => {
+                match worker {
+                    Some(worker) => json.number("worker", worker),
+                    None => json.raw("worker", "null"),
+                }
+                json.field("message", message);
+            }
+            Event::PoolStopping | Event::ServerStopping => {}
+            Event::SignalReceived { signal, forced } => {
+                json.number("signal", signal);
+                json.raw("forced", if forced { "true" } else { "false" });
+            }
+            Event::Access {
+                method,
+                path,
+                route,
+                status,
+                bytes,
+                latency,
+                request_id,
+            } => {
+                json.field("method", method);
+                json.field("path", path);
+                match route {
+                    Some(route) => json.field("route", route),
+                    None => json.raw("route", "null"),
+                }
+                json.number("status", status);
+                json.number("bytes", bytes);
+                json.millis("latency_ms", latency);
+                if let Some(id) = request_id {
+                    json.field("request_id", id);
+                }
+            }
+            Event::Handled {
+                method,
+                path,
+                status,
+                request_id,
+                duration,
+            } => {
+                json.field("method", method);
+                json.field("path", path);
+                json.number("status", status);
+                if let Some(id) = request_id {
+                    json.field("request_id", id);
+                }
+                json.millis("duration_ms", duration);
+            }
+            Event::HandlerPanicked {
+                method,
+                path,
+                message,
+            } => {
+                json.field("method", method);
+                json.field("path", path);
+                json.field("message", message);
+            }
+            Event::BadRequest { error } => json.field("error", &error.to_string()),
+            Event::Error { context, error } => {
+                json.field("context", context);
+                json.field("error", &error.to_string());
+            }
+        }
+
+        json.finish()
+    }
+}
+
+// The human-readable form, one line per event.
+impl<'a> fmt::Display for Event<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::JobStarted { worker } => write!(f, "Worker {} got a job; executing!", worker),
+            Event::JobFinished { worker, duration } => {
+                write!(f, "Worker {} finished a job in {:?}.", worker, duration)
+            }
+            Event::JobPanicked {
+                worker: Some(worker),
+                message,
+            } => write!(f, "Worker {} caught a panic in a job: {}", worker, message),
+            Event::JobPanicked {
+                worker: None,
+                message,
+            } => write!(f, "Caught a panic in a job run by its caller: {}", message),
+            Event::WorkerStarted { worker } => write!(f, "Started worker {}.", worker),
+            Event::WorkerStopped { worker } => {
+                write!(f, "Worker {} was told to terminate.", worker)
+            }
+            Event::WorkerRetired { worker } => {
+                write!(f, "Worker {} was idle for too long; retiring.", worker)
+            }
+            Event::WorkerDied { worker } => {
+                write!(f, "Worker {} died; starting a replacement.", worker)
+            }
+            Event::WorkerTimedOut { worker } => {
+                write!(f, "Worker {} did not stop before the deadline.", worker)
+            }
+            Event::PoolStopping => write!(f, "Sending terminate message to all workers."),
+            Event::ServerStopping => {
+                write!(f, "No longer accepting connections; waiting for workers.")
+            }
+            Event::SignalReceived {
+                signal,
+                forced: false,
+            } => write!(f, "Received signal {}; shutting down.", signal),
+            Event::SignalReceived {
+                signal,
+                forced: true,
+            } => write!(f, "Received signal {} again; exiting now.", signal),
+            Event::Access {
+                method,
+                path,
+                status,
+                bytes,
+                latency,
+                request_id,
+                ..
+            } => {
+                write!(f, "{} {} {} {}B {:?}", method, path, status, bytes, latency)?;
+                match request_id {
+                    Some(id) => write!(f, " [{}]", id),
+                    None => Ok(()),
+                }
+            }
+            Event::Handled {
+                method,
+                path,
+                status,
+                request_id,
+                duration,
+            } => {
+                write!(
+                    f,
+                    "Handled {} {} with {} in {:?}",
+                    method, path, status, duration
+                )?;
+                match request_id {
+                    Some(id) => write!(f, " [{}]", id),
+                    None => Ok(()),
+                }
+            }
+            Event::HandlerPanicked {
+                method,
+                path,
+                message,
+            } => write!(f, "Handler for {} {} panicked: {}", method, path, message),
+            Event::BadRequest { error } => write!(f, "Bad request: {}", error),
+            Event::Error { context, error } => write!(f, "Error {}: {}", context, error),
+        }
+    }
+}
+
+/// Receives log events.
+///
+/// Any `Fn(&Event)` closure will do, which is handy for collecting events in
+/// tests or passing them on to another logging library.
+pub trait Log: Send + Sync + 'static {
+    fn log(&self, event: &Event);
+}
+
+impl<F> Log for F
+where
+    F: Fn(&Event) + Send + Sync + 'static,
+{
+    fn log(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// How [`Logger::stdout`] and [`Logger::to_writer`] write events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One sentence per line.
+    Human,
+    /// One JSON object per line, with a `time` field in seconds since the
+    /// Unix epoch.
+    Json,
+}
+
+/// A cheaply cloneable handle to wherever log events should go.
+///
+/// The default logger throws everything away, so the pool and server are
+/// silent unless asked otherwise:
+///
+/// ```
+/// use server::{Level, LogFormat, Logger, PoolConfig, ThreadPool};
+///
+/// let logger = Logger::stdout(LogFormat::Json).with_level(Level::Debug);
+/// let pool = ThreadPool::with_config(PoolConfig {
+///     logger,
+///     ..PoolConfig::new(4)
+/// })
+/// .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Logger {
+    // Each destination has its own level, so one logger can feed a quiet
+    // log file and something that wants to see every job.
+    sinks: Vec<(Arc<dyn Log>, Level)>,
+}
+
+impl Logger {
+    /// Send events at `Info` level and above to `log`.
+    pub fn new(log: impl Log) -> Logger {
+        Logger {
+            sinks: vec![(Arc::new(log), Level::Info)],
+        }
+    }
+
+    /// A logger that discards everything.
+    pub fn silent() -> Logger {
+        Logger { sinks: Vec::new() }
+    }
+
+    /// Write events to standard output.
+    pub fn stdout(format: LogFormat) -> Logger {
+        Logger::to_writer(format, io::stdout())
+    }
+
+    /// Write events to `writer`, one per line.
+    pub fn to_writer<W: Write + Send + 'static>(format: LogFormat, writer: W) -> Logger {
+        let writer = Mutex::new(writer);
+
+        Logger::new(move |event: &Event| {
+            let line = match format {
+                LogFormat::Human => event.to_string(),
+                LogFormat::Json => with_time(event.to_json()),
+            };
+
+            // A log line we can't write isn't worth failing a request over.
+            let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+            let _ = writeln!(writer, "{}", line);
+        })
+    }
+
+    /// Only pass on events at `level` or above.
+    pub fn with_level(mut self, level: Level) -> Logger {
+        for (_, sink_level) in &mut self.sinks {
+            *sink_level = level;
+        }
+        self
+    }
+
+    /// Send events to `other` as well, at whatever levels it was set up
+    /// with.
+    pub fn and(mut self, other: Logger) -> Logger {
+        self.sinks.extend(other.sinks);
+        self
+    }
+
+    pub fn log(&self, event: &Event) {
+        let level = event.level();
+
+        for (sink, sink_level) in &self.sinks {
+            if level >= *sink_level {
+                sink.log(event);
+            }
+        }
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Logger {
+        Logger::silent()
+    }
+}
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let levels: Vec<Level> = self.sinks.iter().map(|(_, level)| *level).collect();
+
+        f.debug_struct("Logger").field("sinks", &levels).finish()
+    }
+}
+
+// Put the current time at the front of a JSON object.
+fn with_time(json: String) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    format!("{{\"time\":{:.3},{}", now.as_secs_f64(), &json[1..])
+}
+
+// Just enough of a JSON writer for flat objects of strings and numbers.
+struct Json {
+    out: String,
+}
+
+impl Json {
+    fn new() -> Json {
+        Json {
+            out: String::from("{"),
+        }
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.out.len() > 1 {
+            self.out.push(',');
+        }
+        let _ = write!(self.out, "\"{}\":", key);
+    }
+
+    fn raw(&mut self, key: &str, value: &str) {
+        self.key(key);
+        self.out.push_str(value);
+    }
+
+    fn number(&mut self, key: &str, value: impl fmt::Display) {
+        self.raw(key, &value.to_string());
+    }
+
+    fn millis(&mut self, key: &str, duration: Duration) {
+        self.raw(key, &format!("{:.3}", duration.as_secs_f64() * 1000.0));
+    }
+
+    fn field(&mut self, key: &str, value: &str) {
+        self.key(key);
+        self.out.push('"');
+
+        for c in value.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                c if c.is_control() => {
+                    let _ = write!(self.out, "\\u{:04x}", c as u32);
+                }
+                c => self.out.push(c),
+            }
+        }
+
+        self.out.push('"');
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push('}');
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access() -> Event<'static> {
+        Event::Access {
+            method: "GET",
+            path: "/say \"hi\"",
+            route: Some("/say/*what"),
+            status: 200,
+            bytes: 12,
+            latency: Duration::from_micros(1500),
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn events_read_as_sentences() {
+        assert_eq!(
+            Event::JobStarted { worker: 3 }.to_string(),
+            "Worker 3 got a job; executing!"
+        );
+        assert_eq!(access().to_string(), "GET /say \"hi\" 200 12B 1.5ms");
+
+        let Event::Access { latency, .. } = access() else {
+            unreachable!()
+        };
+        let with_id = Event::Access {
+            method: "GET",
+            path: "/",
+            route: None,
+            status: 404,
+            bytes: 0,
+            latency,
+            request_id: Some("abc-1"),
+        };
+        assert_eq!(with_id.to_string(), "GET / 404 0B 1.5ms [abc-1]");
+
+        assert_eq!(
+            Event::SignalReceived {
+                signal: 2,
+                forced: false
+            }
+            .to_string(),
+            "Received signal 2; shutting down."
+        );
+    }
+
+    #[test]
+    fn events_serialize_as_json_objects() {
+        assert_eq!(
+            access().to_json(),
+            "{\"level\":\"info\",\"event\":\"access\",\"method\":\"GET\",\
+             \"path\":\"/say \\\"hi\\\"\",\"route\":\"/say/*what\",\"status\":200,\"bytes\":12,\"latency_ms\":1.500}"
+        );
+        assert_eq!(
+            Event::JobPanicked {
+                worker: None,
+                message: "line\nbreak"
+            }
+            .to_json(),
+            "{\"level\":\"warn\",\"event\":\"job_panicked\",\"worker\":null,\
+             \"message\":\"line\\nbreak\"}"
+        );
+        assert_eq!(
+            Event::SignalReceived {
+                signal: 15,
+                forced: true
+            }
+            .to_json(),
+            "{\"level\":\"warn\",\"event\":\"signal_received\",\"signal\":15,\"forced\":true}"
+        );
+    }
+
+    #[test]
+    fn the_logger_filters_by_level() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&lines);
+        let logger = Logger::new(move |event: &Event| sink.lock().unwrap().push(event.name()));
+
+        logger.log(&Event::JobStarted { worker: 0 });
+        logger.log(&access());
+        logger
+            .clone()
+            .with_level(Level::Debug)
+            .log(&Event::JobStarted { worker: 0 });
+
+        assert_eq!(*lines.lock().unwrap(), vec!["access", "job_started"]);
+    }
+
+    #[test]
+    fn combined_loggers_keep_their_own_levels() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = |tag: &'static str| {
+            let lines = Arc::clone(&lines);
+            Logger::new(move |event: &Event| lines.lock().unwrap().push((tag, event.name())))
+        };
+
+        let logger = sink("quiet")
+            .with_level(Level::Warn)
+            .and(sink("chatty").with_level(Level::Debug));
+        logger.log(&Event::JobStarted { worker: 0 });
+        logger.log(&Event::WorkerDied { worker: 0 });
+
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec![
+                ("chatty", "job_started"),
+                ("quiet", "worker_died"),
+                ("chatty", "worker_died")
+            ]
+        );
+    }
+
+    #[test]
+    fn json_lines_start_with_the_time() {
+        let line = with_time(Event::PoolStopping.to_json());
+
+        assert!(line.starts_with("{\"time\":"));
+        assert!(line.ends_with(",\"level\":\"info\",\"event\":\"pool_stopping\"}"));
+    }
+}
@@ -0,0 +1,439 @@
+use crate::logging::{Event, Level, Log, Logger};
+use crate::pool::PoolStats;
+use crate::request::Request;
+use crate::response::{Response, StatusCode};
+use crate::router::{Handler, Params};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+// Upper bounds, in seconds, of the latency histograms' buckets.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// The route label for requests that didn't match any route. Labelling them
+// by path instead would let clients create as many series as they like.
+const UNMATCHED: &str = "unmatched";
+
+/// Counts requests and jobs, and reports them along with the state of the
+/// pool in the [Prometheus text format][format].
+///
+/// `Metrics` learns about requests and jobs from the same events as the log,
+/// so it's hooked up with a [`Logger`]:
+///
+/// ```
+/// use server::{Metrics, PoolConfig, Router, ThreadPool};
+///
+/// let metrics = Metrics::new();
+/// let pool = ThreadPool::with_config(PoolConfig {
+///     logger: metrics.logger(),
+///     ..PoolConfig::new(4)
+/// })
+/// .unwrap();
+///
+/// let router = Router::new().get("/metrics", metrics.handler(pool.stats()));
+/// // Then serve `router` on `pool`, with `metrics.logger()` as the server's
+/// // logger too.
+/// ```
+///
+/// [format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<Counters>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    // Keyed by route, method and status.
+    requests: BTreeMap<(String, String, u16), u64>,
+    // Keyed by route.
+    latency: BTreeMap<String, Histogram>,
+    jobs: Histogram,
+    job_panics: u64,
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    // How many observations fell in each bucket, not counting the ones
+    // below it. Prometheus wants the running total, which we add up when
+    // rendering.
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(i) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        // Bucket lines put `le` after the histogram's own labels.
+        let prefix = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{},", labels)
+        };
+
+        let mut total = 0;
+        for (bound, count) in BUCKETS.iter().zip(&self.buckets) {
+            total += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, prefix, bound, total
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, prefix, self.count
+        );
+
+        let braces = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, braces, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces, self.count);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// A logger that feeds these metrics. Combine it with another logger
+    /// using [`Logger::and`] to keep a log as well.
+    pub fn logger(&self) -> Logger {
+        // Job events are logged at debug level, and we need to see them all.
+        Logger::new(self.clone()).with_level(Level::Debug)
+    }
+
+    /// A route handler that answers with [`render`](Metrics::render).
+    pub fn handler(&self, pool: PoolStats) -> impl Handler {
+        let metrics = self.clone();
+
+        move |_: &Request, _: &Params| {
+            Response::new(StatusCode::Ok)
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+                .with_body(metrics.render(Some(&pool)))
+        }
+    }
+
+    /// Everything counted so far, plus the current state of `pool` if given,
+    /// in the Prometheus text format.
+    pub fn render(&self, pool: Option<&PoolStats>) -> String {
+        // Copy what we need and let go of the lock, so requests being
+        // counted aren't held up by formatting.
+        let (requests, latency, jobs, job_panics) = {
+            let counters = self.lock();
+            (
+                counters.requests.clone(),
+                counters.latency.clone(),
+                counters.jobs.clone(),
+                counters.job_panics,
+            )
+        };
+
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Requests served, by route, method and status.",
+        );
+        for ((route, method, status), count) in &requests {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                escape(route),
+                escape(method),
+                status,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time from reading a request to writing its response, by route.",
+        );
+        for (route, histogram) in &latency {
+            let labels = format!("route=\"{}\"", escape(route));
+            histogram.render(&mut out, "http_request_duration_seconds", &labels);
+        }
+
+        header(
+            &mut out,
+            "pool_job_duration_seconds",
+            "histogram",
+            "Time workers spent running jobs.",
+        );
+        jobs.render(&mut out, "pool_job_duration_seconds", "");
+
+        sample(
+            &mut out,
+            "pool_job_panics_total",
+            "counter",
+            "Jobs run by workers that panicked.",
+            job_panics,
+        );
+
+        if let Some(pool) = pool {
+            render_pool(&mut out, pool);
+        }
+
+        out
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Log for Metrics {
+    fn log(&self, event: &Event) {
+        match *event {
+            Event::Access {
+                method,
+                route,
+                status,
+                latency,
+                ..
+            } => {
+                let route = route.unwrap_or(UNMATCHED);
+                let mut counters = self.lock();
+
+                *counters
+                    .requests
+                    .entry((route.to_string(), method.to_string(), status))
+                    .or_insert(0) += 1;
+                counters
+                    .latency
+                    .entry(route.to_string())
+                    .or_default()
+                    .observe(latency);
+            }
+            Event::JobFinished { duration, .. } => self.lock().jobs.observe(duration),
+            Event::JobPanicked {
+                worker: Some(_), ..
+            } => self.lock().job_panics += 1,
+            _ => {}
+        }
+    }
+}
+
+fn render_pool(out: &mut String, pool: &PoolStats) {
+    let size = pool.size();
+    let busy = pool.busy();
+    let queue = pool.queue_metrics();
+
+    sample(
+        out,
+        "pool_workers",
+        "gauge",
+        "Worker threads in the pool.",
+        size,
+    );
+    sample(
+        out,
+        "pool_workers_busy",
+        "gauge",
+        "Workers running a job.",
+        busy,
+    );
+    sample(
+        out,
+        "pool_utilization",
+        "gauge",
+        "The fraction of workers running a job.",
+        if size == 0 {
+            0.0
+        } else {
+            busy as f64 / size as f64
+        },
+    );
+    sample(
+        out,
+        "pool_queue_depth",
+        "gauge",
+        "Jobs waiting for a worker.",
+        queue.depth,
+    );
+    if let Some(capacity) = queue.capacity {
+        sample(
+            out,
+            "pool_queue_capacity",
+            "gauge",
+            "The most jobs that can wait for a worker.",
+            capacity,
+        );
+    }
+    sample(
+        out,
+        "pool_queue_high_water",
+        "gauge",
+        "The most jobs that have waited for a worker at once.",
+        queue.high_water,
+    );
+    sample(
+        out,
+        "pool_jobs_rejected_total",
+        "counter",
+        "Jobs turned away because the queue was full.",
+        queue.rejected,
+    );
+    sample(
+        out,
+        "pool_jobs_dropped_total",
+        "counter",
+        "Queued jobs thrown away to make room for newer ones.",
+        queue.dropped,
+    );
+    sample(
+        out,
+        "pool_jobs_caller_runs_total",
+        "counter",
+        "Jobs run by the thread that submitted them because the queue was full.",
+        queue.caller_runs,
+    );
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// A metric with a single, unlabelled value.
+fn sample(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Label values are quoted, so quotes, backslashes and newlines need escaping.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ThreadPool;
+    use std::sync::mpsc;
+
+    fn access(route: Option<&str>, status: u16, millis: u64) -> Event<'_> {
+        Event::Access {
+            method: "GET",
+            path: "/ignored",
+            route,
+            status,
+            bytes: 0,
+            latency: Duration::from_millis(millis),
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn requests_are_counted_by_route_and_status() {
+        let metrics = Metrics::new();
+        let logger = metrics.logger();
+
+        logger.log(&access(Some("/users/:id"), 200, 2));
+        logger.log(&access(Some("/users/:id"), 200, 30));
+        logger.log(&access(Some("/users/:id"), 500, 30));
+        logger.log(&access(None, 404, 0));
+
+        let text = metrics.render(None);
+
+        assert!(text.contains(
+            "http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"200\"} 2\n"
+        ));
+        assert!(text.contains(
+            "http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"500\"} 1\n"
+        ));
+        assert!(text.contains(
+            "http_requests_total{route=\"unmatched\",method=\"GET\",status=\"404\"} 1\n"
+        ));
+        assert!(!text.contains("pool_workers"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::new();
+        let logger = metrics.logger();
+
+        logger.log(&access(Some("/"), 200, 2));
+        logger.log(&access(Some("/"), 200, 30));
+        logger.log(&access(Some("/"), 200, 20_000));
+
+        let text = metrics.render(None);
+        let bucket = |le: &str| {
+            format!(
+                "http_request_duration_seconds_bucket{{route=\"/\",le=\"{}\"}} ",
+                le
+            )
+        };
+
+        assert!(text.contains(&(bucket("0.001") + "0\n")));
+        assert!(text.contains(&(bucket("0.005") + "1\n")));
+        assert!(text.contains(&(bucket("0.05") + "2\n")));
+        assert!(text.contains(&(bucket("10") + "2\n")));
+        assert!(text.contains(&(bucket("+Inf") + "3\n")));
+        assert!(text.contains("http_request_duration_seconds_count{route=\"/\"} 3\n"));
+    }
+
+    #[test]
+    fn the_pool_reports_jobs_and_workers() {
+        let metrics = Metrics::new();
+        let pool = ThreadPool::with_config(crate::PoolConfig {
+            logger: metrics.logger(),
+            ..crate::PoolConfig::new(2)
+        })
+        .unwrap();
+        let stats = pool.stats();
+
+        // Hold one worker in a job while we take a look.
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        })
+        .unwrap();
+        started.recv().unwrap();
+
+        let text = metrics.render(Some(&stats));
+        assert!(text.contains("pool_workers 2\n"));
+        assert!(text.contains("pool_workers_busy 1\n"));
+        assert!(text.contains("pool_utilization 0.5\n"));
+        assert!(text.contains("pool_queue_depth 0\n"));
+        assert!(!text.contains("pool_queue_capacity"));
+
+        release.send(()).unwrap();
+        drop(pool);
+
+        let text = metrics.render(None);
+        assert!(text.contains("pool_job_duration_seconds_count 1\n"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,1209 @@
+mod priority;
+mod queue;
+mod task;
+mod timer;
+mod worker;
+
+pub use priority::Priority;
+pub use queue::{OverflowPolicy, QueueMetrics};
+pub use task::{JoinError, TaskHandle};
+pub use timer::{Clock, SystemClock, TimerHandle};
+
+use crate::logging::{Event, Logger};
+use queue::{Pushed, Scheduler};
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use timer::{Action, Timer};
+use worker::Worker;
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    // Grows the pool when jobs back up. Only elastic pools have one.
+    monitor: Option<thread::JoinHandle<()>>,
+    // Feeds delayed and periodic jobs to the workers. It's only started once
+    // there's something for it to do.
+    timer_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    terminating: bool,
+}
+
+// The state the pool shares with its worker threads.
+struct Shared {
+    scheduler: Scheduler,
+    // A slot for every worker the pool could grow to.
+    workers: Vec<Worker>,
+    panics: AtomicUsize,
+    // Workers with a thread to run jobs on. This changes as an elastic pool
+    // grows and shrinks, and drops if a worker dies and we can't start a
+    // replacement.
+    size: AtomicUsize,
+    peak_size: AtomicUsize,
+    busy: AtomicUsize,
+    min_size: usize,
+    // Tells the monitor to stop.
+    stopping: AtomicBool,
+    timer: Timer,
+    logger: Logger,
+}
+
+impl Shared {
+    // Queue a job to be picked up by an available Worker. If it lands on a
+    // busy worker's queue, an idle one will steal it.
+    fn submit(&self, job: Job, priority: Priority) -> Result<(), ExecuteError> {
+        if self.size.load(Ordering::SeqCst) == 0 {
+            return Err(ExecuteError::Disconnected);
+        }
+
+        self.settle(self.scheduler.push(job, priority))
+    }
+
+    fn try_submit(&self, job: Job, priority: Priority) -> Result<(), ExecuteError> {
+        if self.size.load(Ordering::SeqCst) == 0 {
+            return Err(ExecuteError::Disconnected);
+        }
+
+        self.settle(self.scheduler.try_push(job, priority))
+    }
+
+    // Finish off a job the scheduler couldn't queue.
+    fn settle(&self, pushed: Pushed) -> Result<(), ExecuteError> {
+        match pushed {
+            Pushed::Queued => Ok(()),
+            // Dropping the job here, rather than in the scheduler, means
+            // anything it owns is cleaned up before we return.
+            Pushed::Rejected(job) => {
+                drop(job);
+                Err(ExecuteError::QueueFull)
+            }
+            Pushed::RunHere(job) => {
+                // The caller didn't sign up to have its thread taken down by
+                // someone else's job, so treat panics as a worker would.
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    self.panics.fetch_add(1, Ordering::SeqCst);
+
+                    self.logger.log(&Event::JobPanicked {
+                        worker: None,
+                        message: panic_message(&*payload),
+                    });
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Take an idle worker out of the pool, unless that would leave fewer than
+    // the minimum.
+    fn retire(&self) -> bool {
+        self.size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                if size > self.min_size {
+                    Some(size - 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+/// Settings for a [`ThreadPool`].
+///
+/// By default the pool has a fixed number of threads and the job queue is
+/// unbounded. Giving the queue a capacity keeps a burst of work from using up
+/// memory, and `overflow` decides what happens to jobs that arrive while it's
+/// full:
+///
+/// ```
+/// use server::{OverflowPolicy, PoolConfig, ThreadPool};
+///
+/// let pool = ThreadPool::with_config(PoolConfig {
+///     queue_capacity: Some(100),
+///     overflow: OverflowPolicy::Reject,
+///     ..PoolConfig::new(4)
+/// })
+/// .unwrap();
+/// ```
+///
+/// Setting `max_size` makes the pool elastic. It starts with `size` threads,
+/// adds more while jobs are kept waiting for longer than `spawn_after`, and
+/// lets the extra ones go once they've had nothing to do for `keep_alive`.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// The number of threads in the pool. For an elastic pool, this is the
+    /// number it never shrinks below.
+    pub size: usize,
+    /// The most threads an elastic pool may grow to, or `None` to keep the
+    /// pool at `size`.
+    pub max_size: Option<usize>,
+    /// How long a job may wait for a worker before an elastic pool starts
+    /// another one. Must be more than zero if the pool is elastic.
+    pub spawn_after: Duration,
+    /// How long an extra worker in an elastic pool can sit idle before it
+    /// retires.
+    pub keep_alive: Duration,
+    /// How many jobs a waiting job sees started ahead of it before it moves
+    /// up a [`Priority`] level. Zero turns aging off, leaving low priority
+    /// jobs to wait for as long as there's anything more urgent to do.
+    pub age_after: usize,
+    /// The most jobs that can wait for a worker, or `None` for no limit.
+    pub queue_capacity: Option<usize>,
+    /// What to do with a job when the queue is full.
+    pub overflow: OverflowPolicy,
+    /// Where to report jobs and the comings and goings of workers. Silent
+    /// unless set.
+    pub logger: Logger,
+    /// What delayed and periodic jobs tell the time by.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl PoolConfig {
+    /// Settings for a fixed pool of `size` threads with an unbounded queue.
+    pub fn new(size: usize) -> PoolConfig {
+        PoolConfig {
+            size,
+            max_size: None,
+            spawn_after: Duration::from_millis(100),
+            keep_alive: Duration::from_secs(60),
+            age_after: 32,
+            queue_capacity: None,
+            overflow: OverflowPolicy::Block,
+            logger: Logger::silent(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero, or if the operating
+    /// system refuses to start a thread. Use [`ThreadPool::build`] to handle
+    /// those cases instead.
+    pub fn new(size: usize) -> ThreadPool {
+        match ThreadPool::build(size) {
+            Ok(pool) => pool,
+            Err(e) => panic!("failed to create thread pool: {}", e),
+        }
+    }
+
+    /// Create a new ThreadPool, returning an error rather than panicking if
+    /// that isn't possible.
+    ///
+    /// The size is the number of threads in the pool. Each thread is named
+    /// `worker-{id}`, which shows up in panic messages and debuggers.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::with_config(PoolConfig::new(size))
+    }
+
+    /// Create a new ThreadPool from `config`.
+    pub fn with_config(config: PoolConfig) -> Result<ThreadPool, PoolCreationError> {
+        let PoolConfig {
+            size,
+            max_size,
+            spawn_after,
+            keep_alive,
+            age_after,
+            queue_capacity,
+            overflow,
+            logger,
+            clock,
+        } = config;
+
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if max_size.is_some_and(|max| max < size) {
+            return Err(PoolCreationError::MaxBelowSize);
+        }
+        if queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
+        }
+
+        let max = max_size.unwrap_or(size);
+
+        // Every job would count as kept waiting, and the monitor would never
+        // sleep between checks.
+        if max > size && spawn_after.is_zero() {
+            return Err(PoolCreationError::ZeroSpawnAfter);
+        }
+
+        // Every worker gets its own job queue in the scheduler, including the
+        // ones an elastic pool hasn't started yet.
+        let mut scheduler =
+            Scheduler::new(max, queue_capacity, overflow).with_aging(age_after as u64);
+        if max > size {
+            scheduler = scheduler.with_keep_alive(keep_alive);
+        }
+
+        // The state is reference counted so each worker thread can hold on
+        // to it.
+        let shared = Arc::new(Shared {
+            scheduler,
+            workers: (0..max).map(Worker::new).collect(),
+            panics: AtomicUsize::new(0),
+            size: AtomicUsize::new(size),
+            peak_size: AtomicUsize::new(size),
+            busy: AtomicUsize::new(0),
+            min_size: size,
+            stopping: AtomicBool::new(false),
+            timer: Timer::new(clock),
+            logger,
+        });
+
+        let mut pool = ThreadPool {
+            shared,
+            monitor: None,
+            timer_thread: Mutex::new(None),
+            terminating: false,
+        };
+
+        // Start a thread for each of the first `size` workers. If that fails,
+        // dropping the pool stops the ones we did manage to start, so they
+        // aren't left behind.
+        for id in 0..size {
+            worker::start(&pool.shared, id).map_err(PoolCreationError::Spawn)?;
+        }
+
+        if max > size {
+            let shared = Arc::clone(&pool.shared);
+            let monitor = worker::spawn_monitor(shared, max, spawn_after)
+                .map_err(PoolCreationError::Spawn)?;
+            pool.monitor = Some(monitor);
+        }
+
+        Ok(pool)
+    }
+
+    /// Queue `f` to run on the next available worker.
+    ///
+    /// If the queue is full, what happens depends on the pool's
+    /// [`OverflowPolicy`]: this may wait for room, fail with
+    /// [`ExecuteError::QueueFull`], or run `f` before returning. It also fails
+    /// if the pool can no longer run jobs, e.g. because every worker has
+    /// exited.
+    // Use FnOnce as the trait bound, which ensures that the closure
+    // passed to execute can only run at most one time. Send allows for
+    // the transfer of the closure from one thread to another and 'static
+    // is useful because we don't know how long the thread will take to execute.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(Priority::Normal, f)
+    }
+
+    /// Like [`execute`](ThreadPool::execute), but never holds up the calling
+    /// thread. If the queue is full and the pool's [`OverflowPolicy`] would
+    /// wait for room or run `f` right here, this fails with
+    /// [`ExecuteError::QueueFull`] instead.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.try_submit(Box::new(f), Priority::Normal)
+    }
+
+    /// Queue `f` to run ahead of any less urgent jobs.
+    ///
+    /// Each worker runs the most urgent job in its own queue first, and an
+    /// idle worker steals the most urgent job from a busy one's. So in a
+    /// pool with several workers the order is only roughly by priority; with
+    /// a single worker it's exact.
+    ///
+    /// ```
+    /// use server::{Priority, ThreadPool};
+    /// use std::sync::mpsc;
+    ///
+    /// let pool = ThreadPool::new(1);
+    /// let (tx, rx) = mpsc::channel();
+    ///
+    /// // Keep the worker busy while the other jobs queue up.
+    /// let (go, wait) = mpsc::channel::<()>();
+    /// pool.execute(move || wait.recv().unwrap()).unwrap();
+    ///
+    /// for (priority, name) in [(Priority::Low, "report"), (Priority::High, "health check")] {
+    ///     let tx = tx.clone();
+    ///     pool.execute_with_priority(priority, move || tx.send(name).unwrap())
+    ///         .unwrap();
+    /// }
+    ///
+    /// go.send(()).unwrap();
+    /// assert_eq!(rx.recv().unwrap(), "health check");
+    /// assert_eq!(rx.recv().unwrap(), "report");
+    /// ```
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.submit(Box::new(f), priority)
+    }
+
+    /// Queue `f` to run once `delay` has passed.
+    ///
+    /// The job waits on the pool's timer thread, then joins the queue like
+    /// any other. The returned handle can cancel it before then.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> Result<TimerHandle, ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.start_timer()?;
+        Ok(self.shared.timer.schedule(delay, Action::Once(Box::new(f))))
+    }
+
+    /// Queue `f` to run every `interval`, starting one interval from now,
+    /// until the returned handle is cancelled or the pool shuts down.
+    ///
+    /// Each run is a separate job, so a slow run doesn't hold up the next
+    /// one, and a run that panics doesn't stop the rest. A job that falls
+    /// behind (because the queue was backed up, say) skips the runs it missed
+    /// rather than catching up all at once.
+    ///
+    /// Fails with [`ExecuteError::ZeroInterval`] if `interval` is zero.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> Result<TimerHandle, ExecuteError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        if interval.is_zero() {
+            return Err(ExecuteError::ZeroInterval);
+        }
+
+        self.start_timer()?;
+        let action = Action::Every(Arc::new(f), interval);
+        Ok(self.shared.timer.schedule(interval, action))
+    }
+
+    /// Run `f` on the pool and get a handle to its result.
+    ///
+    /// Unlike [`execute`](ThreadPool::execute), the job can return a value,
+    /// which makes the pool useful for splitting up CPU-bound work:
+    ///
+    /// ```
+    /// use server::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let lines = vec!["rust is fast", "safe", "and productive", "rust"];
+    ///
+    /// let handles: Vec<_> = lines
+    ///     .chunks(2)
+    ///     .map(|chunk| {
+    ///         let chunk: Vec<String> = chunk.iter().map(|s| s.to_string()).collect();
+    ///         pool.spawn(move || chunk.iter().filter(|l| l.contains("rust")).count())
+    ///             .unwrap()
+    ///     })
+    ///     .collect();
+    ///
+    /// let total: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    /// assert_eq!(total, 2);
+    /// ```
+    ///
+    /// If `f` panics, the panic is caught and handed to whoever joins the
+    /// task, instead of being reported by the pool.
+    pub fn spawn<F, T>(&self, f: F) -> Result<TaskHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn_with_priority(Priority::Normal, f)
+    }
+
+    /// Like [`spawn`](ThreadPool::spawn), with the job queued at `priority`.
+    pub fn spawn_with_priority<F, T>(
+        &self,
+        priority: Priority,
+        f: F,
+    ) -> Result<TaskHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (completion, handle) = task::task();
+
+        self.execute_with_priority(priority, move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(JoinError::Panicked);
+            completion.complete(result);
+        })?;
+
+        Ok(handle)
+    }
+
+    /// The number of jobs that have panicked since the pool was created.
+    ///
+    /// A panicking job doesn't take its worker down with it: the panic is
+    /// caught, reported and counted here, and the worker moves on to the next
+    /// job.
+    pub fn panic_count(&self) -> usize {
+        self.shared.panics.load(Ordering::SeqCst)
+    }
+
+    /// The number of worker threads in the pool right now.
+    pub fn size(&self) -> usize {
+        self.shared.size.load(Ordering::SeqCst)
+    }
+
+    /// The most worker threads the pool has had at once.
+    pub fn peak_size(&self) -> usize {
+        self.shared.peak_size.load(Ordering::SeqCst)
+    }
+
+    /// The number of workers running a job right now.
+    pub fn busy(&self) -> usize {
+        self.shared.busy.load(Ordering::SeqCst)
+    }
+
+    /// How full the job queue is, and how often it has overflowed.
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.shared.scheduler.metrics()
+    }
+
+    /// A handle for keeping an eye on the pool from elsewhere, such as a
+    /// metrics endpoint served by the pool itself.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Shut the pool down, giving running jobs until `timeout` to finish.
+    ///
+    /// Jobs that are already queued still run before the workers stop. Any
+    /// worker still busy when the deadline passes is left to finish in the
+    /// background, and is listed in the returned report.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        let mut report = ShutdownReport::default();
+
+        self.terminate();
+
+        // Only workers that have been started are worth reporting on.
+        let workers: Vec<&Worker> = self
+            .shared
+            .workers
+            .iter()
+            .filter(|worker| worker.handle().is_some())
+            .collect();
+
+        // JoinHandle has no way to wait with a timeout, so poll each worker
+        // until it's finished or we run out of time.
+        loop {
+            let mut running = false;
+
+            for worker in &workers {
+                let mut handle = worker.handle();
+                if handle.as_ref().is_some_and(|h| h.is_finished()) {
+                    let thread = handle.take();
+                    drop(handle);
+
+                    if let Some(thread) = thread {
+                        let _ = thread.join();
+                    }
+                } else if handle.is_some() {
+                    running = true;
+                }
+            }
+
+            if !running || Instant::now() >= deadline {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        for worker in &workers {
+            // Dropping a JoinHandle detaches its thread rather than killing
+            // it, so the job can still run to completion on its own.
+            if worker.handle().take().is_some() {
+                self.shared
+                    .logger
+                    .log(&Event::WorkerTimedOut { worker: worker.id });
+                report.timed_out.push(worker.id);
+            } else {
+                report.stopped.push(worker.id);
+            }
+        }
+
+        report
+    }
+
+    fn start_timer(&self) -> Result<(), ExecuteError> {
+        let mut timer_thread = lock(&self.timer_thread);
+        if timer_thread.is_some() {
+            return Ok(());
+        }
+
+        let shared = Arc::clone(&self.shared);
+        let thread = thread::Builder::new()
+            .name(String::from("pool-timer"))
+            .spawn(move || {
+                shared.timer.run(|job| {
+                    if let Err(e) = shared.submit(job, Priority::Normal) {
+                        shared.logger.log(&Event::Error {
+                            context: "queueing a timed job",
+                            error: &e,
+                        });
+                    }
+                })
+            })
+            .map_err(|_| ExecuteError::NoTimer)?;
+
+        *timer_thread = Some(thread);
+        Ok(())
+    }
+
+    // Tell every worker to stop once it runs out of queued jobs. This only
+    // needs to happen once, whether we're shutting down or being dropped.
+    fn terminate(&mut self) {
+        if self.terminating {
+            return;
+        }
+        self.terminating = true;
+
+        // Stop growing the pool first, so no new workers turn up while the
+        // others are leaving.
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        if let Some(monitor) = self.monitor.take() {
+            monitor.thread().unpark();
+            let _ = monitor.join();
+        }
+
+        // Timed jobs that haven't fallen due yet won't get the chance.
+        self.shared.timer.stop();
+        if let Some(timer_thread) = lock(&self.timer_thread).take() {
+            let _ = timer_thread.join();
+        }
+
+        // Workers that are busy finish their job first, and everyone keeps
+        // going until the queues are empty.
+        self.shared.logger.log(&Event::PoolStopping);
+
+        self.shared.scheduler.terminate();
+    }
+}
+
+/// A cloneable view of a [`ThreadPool`]'s current state.
+///
+/// Unlike the pool itself, this can be handed to the pool's own jobs. It
+/// doesn't keep the pool's workers running.
+#[derive(Clone)]
+pub struct PoolStats {
+    shared: Arc<Shared>,
+}
+
+impl PoolStats {
+    /// See [`ThreadPool::size`].
+    pub fn size(&self) -> usize {
+        self.shared.size.load(Ordering::SeqCst)
+    }
+
+    /// See [`ThreadPool::busy`].
+    pub fn busy(&self) -> usize {
+        self.shared.busy.load(Ordering::SeqCst)
+    }
+
+    /// See [`ThreadPool::panic_count`].
+    pub fn panic_count(&self) -> usize {
+        self.shared.panics.load(Ordering::SeqCst)
+    }
+
+    /// See [`ThreadPool::queue_metrics`].
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.shared.scheduler.metrics()
+    }
+}
+
+impl fmt::Debug for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoolStats")
+            .field("size", &self.size())
+            .field("busy", &self.busy())
+            .finish()
+    }
+}
+
+/// The ways creating a [`ThreadPool`] can fail.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// A pool needs at least one thread.
+    ZeroSize,
+    /// An elastic pool's `max_size` can't be smaller than its `size`.
+    MaxBelowSize,
+    /// A bounded queue needs room for at least one job.
+    ZeroCapacity,
+    /// An elastic pool's `spawn_after` has to be longer than zero.
+    ZeroSpawnAfter,
+    /// The operating system couldn't start a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => write!(f, "a thread pool needs at least one thread"),
+            PoolCreationError::MaxBelowSize => {
+                write!(f, "a thread pool's max_size can't be smaller than its size")
+            }
+            PoolCreationError::ZeroCapacity => {
+                write!(f, "a bounded job queue needs room for at least one job")
+            }
+            PoolCreationError::ZeroSpawnAfter => {
+                write!(
+                    f,
+                    "an elastic thread pool's spawn_after must be more than zero"
+                )
+            }
+            PoolCreationError::Spawn(e) => write!(f, "couldn't spawn a worker thread: {}", e),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize
+            | PoolCreationError::MaxBelowSize
+            | PoolCreationError::ZeroCapacity
+            | PoolCreationError::ZeroSpawnAfter => None,
+            PoolCreationError::Spawn(e) => Some(e),
+        }
+    }
+}
+
+/// The ways submitting a job to a [`ThreadPool`] can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// There are no workers left to run the job.
+    Disconnected,
+    /// The queue is full and the pool's [`OverflowPolicy`] is `Reject`.
+    QueueFull,
+    /// The pool couldn't start the thread that runs delayed and periodic
+    /// jobs.
+    NoTimer,
+    /// A periodic job was given an interval of zero.
+    ZeroInterval,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::Disconnected => write!(f, "the thread pool has no workers left"),
+            ExecuteError::QueueFull => write!(f, "the thread pool's job queue is full"),
+            ExecuteError::NoTimer => write!(f, "couldn't start the thread pool's timer thread"),
+            ExecuteError::ZeroInterval => write!(f, "a periodic job needs a non-zero interval"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+/// What happened to each worker when a pool was shut down.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ShutdownReport {
+    /// Workers that finished their jobs and stopped in time.
+    pub stopped: Vec<usize>,
+    /// Workers that were still running a job when the deadline passed.
+    pub timed_out: Vec<usize>,
+    /// How many upgraded connections, like WebSockets, were still open when
+    /// the deadline passed. Only [`Server::run`](crate::Server::run) fills
+    /// this in.
+    pub upgraded_timed_out: usize,
+}
+
+impl ShutdownReport {
+    /// True if every worker, and every upgraded connection, stopped before
+    /// the deadline.
+    pub fn is_clean(&self) -> bool {
+        self.timed_out.is_empty() && self.upgraded_timed_out == 0
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.terminate();
+
+        for worker in &self.shared.workers {
+            // When the ThreadPool is dropped, ensure each worker's thread is
+            // joined. After shutdown_timeout there are none left to join.
+            if worker.handle().is_some() {
+                worker.join();
+            }
+        }
+    }
+}
+
+// Lock a mutex, carrying on even if another thread panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::Level;
+    use crate::pool::timer::MockClock;
+    use std::sync::mpsc;
+
+    #[test]
+    fn build_rejects_an_empty_pool() {
+        assert!(matches!(
+            ThreadPool::build(0),
+            Err(PoolCreationError::ZeroSize)
+        ));
+    }
+
+    #[test]
+    fn with_config_rejects_an_empty_queue() {
+        let config = PoolConfig {
+            queue_capacity: Some(0),
+            ..PoolConfig::new(1)
+        };
+
+        assert!(matches!(
+            ThreadPool::with_config(config),
+            Err(PoolCreationError::ZeroCapacity)
+        ));
+    }
+
+    #[test]
+    fn with_config_rejects_a_max_below_the_size() {
+        let config = PoolConfig {
+            max_size: Some(1),
+            ..PoolConfig::new(2)
+        };
+
+        assert!(matches!(
+            ThreadPool::with_config(config),
+            Err(PoolCreationError::MaxBelowSize)
+        ));
+    }
+
+    #[test]
+    fn with_config_rejects_an_elastic_pool_that_never_waits() {
+        let config = PoolConfig {
+            max_size: Some(4),
+            spawn_after: Duration::ZERO,
+            ..PoolConfig::new(1)
+        };
+
+        assert!(matches!(
+            ThreadPool::with_config(config),
+            Err(PoolCreationError::ZeroSpawnAfter)
+        ));
+
+        // A fixed-size pool has no monitor, so it doesn't matter there.
+        let config = PoolConfig {
+            spawn_after: Duration::ZERO,
+            ..PoolConfig::new(1)
+        };
+        assert!(ThreadPool::with_config(config).is_ok());
+    }
+
+    // Poll `condition` for up to a few seconds.
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn an_elastic_pool_grows_and_shrinks() {
+        let pool = ThreadPool::with_config(PoolConfig {
+            max_size: Some(3),
+            spawn_after: Duration::from_millis(20),
+            keep_alive: Duration::from_millis(100),
+            ..PoolConfig::new(1)
+        })
+        .unwrap();
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        // Three jobs that each hold on to a worker until told to finish.
+        let (started_tx, started_rx) = mpsc::channel();
+        for _ in 0..3 {
+            let rx = Arc::clone(&rx);
+            let started_tx = started_tx.clone();
+            pool.execute(move || {
+                started_tx.send(()).unwrap();
+                lock(&rx).recv().unwrap();
+            })
+            .unwrap();
+        }
+
+        // They can only all start if the pool grew to fit them.
+        for _ in 0..3 {
+            started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(pool.size(), 3);
+        assert_eq!(pool.peak_size(), 3);
+
+        for _ in 0..3 {
+            tx.send(()).unwrap();
+        }
+
+        // The extra workers retire once they've been idle for a while, but
+        // the pool never drops below its size.
+        assert!(eventually(|| pool.size() == 1));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.peak_size(), 3);
+
+        let report = pool.shutdown_timeout(Duration::from_secs(5));
+        assert!(report.is_clean());
+        assert_eq!(report.stopped.len(), 3);
+    }
+
+    #[test]
+    fn a_full_pool_turns_jobs_away() {
+        let pool = ThreadPool::with_config(PoolConfig {
+            queue_capacity: Some(1),
+            overflow: OverflowPolicy::Reject,
+            ..PoolConfig::new(1)
+        })
+        .unwrap();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (block_tx, block_rx) = mpsc::channel::<()>();
+
+        // One job keeps the worker busy and another fills the queue.
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            block_rx.recv().unwrap();
+        })
+        .unwrap();
+        started_rx.recv().unwrap();
+        let queued = pool.spawn(|| "queued").unwrap();
+
+        let rejected = pool.spawn(|| "rejected");
+        assert_eq!(rejected.unwrap_err(), ExecuteError::QueueFull);
+
+        block_tx.send(()).unwrap();
+        assert_eq!(queued.join().unwrap(), "queued");
+
+        let metrics = pool.queue_metrics();
+        assert_eq!(metrics.capacity, Some(1));
+        assert_eq!(metrics.high_water, 1);
+        assert_eq!(metrics.rejected, 1);
+    }
+
+    #[test]
+    fn try_execute_never_holds_up_the_caller() {
+        for overflow in [OverflowPolicy::Block, OverflowPolicy::CallerRuns] {
+            let pool = ThreadPool::with_config(PoolConfig {
+                queue_capacity: Some(1),
+                overflow,
+                ..PoolConfig::new(1)
+            })
+            .unwrap();
+            let (started_tx, started_rx) = mpsc::channel();
+            let (block_tx, block_rx) = mpsc::channel::<()>();
+
+            pool.execute(move || {
+                started_tx.send(()).unwrap();
+                block_rx.recv().unwrap();
+            })
+            .unwrap();
+            started_rx.recv().unwrap();
+            pool.try_execute(|| {}).unwrap();
+
+            // Neither waits for the worker nor runs the job on this thread.
+            let ran = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&ran);
+            let result = pool.try_execute(move || flag.store(true, Ordering::SeqCst));
+            assert_eq!(result, Err(ExecuteError::QueueFull), "{:?}", overflow);
+            assert!(!ran.load(Ordering::SeqCst));
+
+            block_tx.send(()).unwrap();
+            assert!(pool.shutdown_timeout(Duration::from_secs(5)).is_clean());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one thread")]
+    fn new_panics_on_an_empty_pool() {
+        ThreadPool::new(0);
+    }
+
+    #[test]
+    fn workers_are_named_threads() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(move || {
+            let name = thread::current().name().map(String::from);
+            tx.send(name).unwrap();
+        })
+        .unwrap();
+
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("worker-0"));
+    }
+
+    #[test]
+    fn spawn_returns_the_job_result() {
+        let pool = ThreadPool::build(2).unwrap();
+        let handles: Vec<_> = (0..10u64)
+            .map(|i| pool.spawn(move || i * i).unwrap())
+            .collect();
+
+        let squares: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(squares, vec![0, 1, 4, 9, 16, 25, 36, 49, 64, 81]);
+    }
+
+    #[test]
+    fn spawn_hands_back_panics() {
+        let pool = ThreadPool::build(1).unwrap();
+        let handle = pool.spawn(|| -> u32 { panic!("boom") }).unwrap();
+
+        let payload = handle.join().unwrap_err().into_panic().unwrap();
+
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+        // The panic went to the handle, not to the pool.
+        assert_eq!(pool.panic_count(), 0);
+    }
+
+    #[test]
+    fn join_timeout_gives_the_handle_back() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (tx, rx) = mpsc::channel::<()>();
+        let handle = pool
+            .spawn(move || {
+                rx.recv().unwrap();
+                "done"
+            })
+            .unwrap();
+
+        let handle = handle.join_timeout(Duration::from_millis(20)).unwrap_err();
+        assert!(!handle.is_finished());
+
+        tx.send(()).unwrap();
+
+        let result = handle.join_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result.unwrap(), "done");
+    }
+
+    #[test]
+    fn jobs_behind_a_busy_worker_are_stolen() {
+        let pool = ThreadPool::build(2).unwrap();
+        let (block_tx, block_rx) = mpsc::channel::<()>();
+        let (tx, rx) = mpsc::channel();
+
+        // Keep one worker busy. Submissions alternate between the two queues,
+        // so half of the jobs below land behind the blocked one.
+        pool.execute(move || block_rx.recv().unwrap()).unwrap();
+
+        for i in 0..6 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap()).unwrap();
+        }
+
+        let mut results: Vec<i32> = rx.iter().take(6).collect();
+        results.sort_unstable();
+
+        assert_eq!(results, vec![0, 1, 2, 3, 4, 5]);
+        block_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn jobs_can_submit_more_jobs() {
+        let pool = Arc::new(ThreadPool::build(1).unwrap());
+
+        let inner = Arc::clone(&pool);
+        let outer = pool
+            .spawn(move || inner.spawn(|| "nested").unwrap())
+            .unwrap();
+
+        // The nested job went on the only worker's own queue and runs once
+        // the outer job returns.
+        let nested = outer.join().unwrap();
+        assert_eq!(nested.join().unwrap(), "nested");
+    }
+
+    #[test]
+    fn delayed_jobs_run_on_the_pool() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        let submitted = Instant::now();
+        pool.execute_after(Duration::from_millis(30), move || {
+            let name = thread::current().name().map(String::from);
+            tx.send((name, submitted.elapsed())).unwrap();
+        })
+        .unwrap();
+
+        let (name, waited) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name.as_deref(), Some("worker-0"));
+        assert!(waited >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn timed_jobs_can_be_cancelled() {
+        let clock = MockClock::new();
+        let pool = ThreadPool::with_config(PoolConfig {
+            clock: Arc::new(clock.clone()),
+            ..PoolConfig::new(1)
+        })
+        .unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        let tick = tx.clone();
+        let every = pool
+            .execute_every(Duration::from_secs(10), move || tick.send("tick").unwrap())
+            .unwrap();
+        let marker = tx.clone();
+        let after = pool
+            .execute_after(Duration::from_secs(60), move || tx.send("after").unwrap())
+            .unwrap();
+
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(10));
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "tick");
+        }
+
+        // The next tick isn't due until the clock moves again, so nothing
+        // can be on its way to the worker.
+        assert!(every.cancel());
+        assert!(after.cancel());
+
+        // Jobs fall due in order and there's only one worker, so once this
+        // has run, anything else that was going to would have too.
+        pool.execute_after(Duration::from_secs(120), move || {
+            marker.send("marker").unwrap()
+        })
+        .unwrap();
+        clock.advance(Duration::from_secs(120));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "marker");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn periodic_jobs_need_an_interval() {
+        let pool = ThreadPool::new(1);
+
+        assert_eq!(
+            pool.execute_every(Duration::ZERO, || {}).unwrap_err(),
+            ExecuteError::ZeroInterval
+        );
+    }
+
+    #[test]
+    fn dropped_tasks_are_cancelled() {
+        let (completion, handle) = task::task::<()>();
+
+        assert!(!handle.is_finished());
+        drop(completion);
+
+        assert!(handle.is_finished());
+        assert!(matches!(handle.join(), Err(JoinError::Cancelled)));
+    }
+
+    #[test]
+    fn a_panicking_job_does_not_take_down_the_pool() {
+        // With a single worker, the jobs after the panic can only run if that
+        // worker survived it.
+        let pool = ThreadPool::build(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(|| panic!("oh no")).unwrap();
+
+        for i in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap()).unwrap();
+        }
+
+        let mut results: Vec<i32> = rx.iter().take(4).collect();
+        results.sort_unstable();
+
+        assert_eq!(results, vec![0, 1, 2, 3]);
+        assert_eq!(pool.panic_count(), 1);
+    }
+
+    #[test]
+    fn jobs_and_workers_are_logged() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let logger = Logger::new(move |event: &Event| {
+            let _ = lock(&tx).send(event.to_string());
+        })
+        .with_level(Level::Debug);
+
+        let pool = ThreadPool::with_config(PoolConfig {
+            logger,
+            ..PoolConfig::new(1)
+        })
+        .unwrap();
+        pool.execute(|| panic!("oh no")).unwrap();
+        drop(pool);
+
+        // The pool's own event can land anywhere among the worker's, which
+        // come in the order they happened.
+        let (pool_events, worker_events): (Vec<String>, Vec<String>) = rx
+            .try_iter()
+            .partition(|event| event.starts_with("Sending"));
+
+        assert_eq!(pool_events.len(), 1);
+        assert_eq!(
+            worker_events[..3],
+            [
+                "Started worker 0.",
+                "Worker 0 got a job; executing!",
+                "Worker 0 caught a panic in a job: oh no",
+            ]
+        );
+        assert!(worker_events[3].starts_with("Worker 0 finished a job in "));
+        assert_eq!(worker_events[4], "Worker 0 was told to terminate.");
+    }
+
+    // A panic payload that panics again when it's dropped, which happens
+    // outside of catch_unwind and so kills the worker thread.
+    struct PanicOnDrop;
+
+    impl Drop for PanicOnDrop {
+        fn drop(&mut self) {
+            panic!("dropping the payload panicked too");
+        }
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(|| panic::panic_any(PanicOnDrop)).unwrap();
+        pool.execute(move || {
+            let name = thread::current().name().map(String::from);
+            tx.send(name).unwrap();
+        })
+        .unwrap();
+
+        // The only worker died, so this job can only run if it was replaced.
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("worker-0"));
+        assert_eq!(pool.panic_count(), 1);
+
+        let report = pool.shutdown_timeout(Duration::from_secs(5));
+        assert_eq!(report.stopped, vec![0]);
+    }
+}
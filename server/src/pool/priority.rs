@@ -0,0 +1,170 @@
+use super::Job;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// How urgently a job should run.
+///
+/// Workers always pick the most urgent job waiting. To keep a steady stream
+/// of urgent work from starving everything else, waiting jobs age: a job
+/// moves up a level for every [`age_after`](super::PoolConfig::age_after)
+/// jobs the pool starts while it waits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+const LEVELS: usize = 3;
+
+impl Priority {
+    fn level(self) -> usize {
+        self as usize
+    }
+}
+
+// A job waiting in a queue. Jobs are tagged with the order they were
+// submitted in, so we can find the oldest one across all the queues, and with
+// how many jobs had been started at the time, so we can tell how long they've
+// been waiting without looking at a clock.
+pub(super) struct Entry {
+    pub(super) seq: u64,
+    pub(super) tick: u64,
+    pub(super) queued_at: Instant,
+    pub(super) job: Job,
+}
+
+/// One worker's waiting jobs, first in first out within each priority.
+pub(super) struct PriorityQueue {
+    levels: [VecDeque<Entry>; LEVELS],
+}
+
+impl PriorityQueue {
+    pub(super) fn new() -> PriorityQueue {
+        PriorityQueue {
+            levels: Default::default(),
+        }
+    }
+
+    pub(super) fn push(&mut self, priority: Priority, entry: Entry) {
+        self.levels[priority.level()].push_back(entry);
+    }
+
+    /// Take the job that should run next, now that `tick` jobs have been
+    /// started. Jobs move up a level for every `age_after` ticks they've
+    /// waited; zero turns aging off.
+    pub(super) fn pop(&mut self, tick: u64, age_after: u64) -> Option<Entry> {
+        let level = (0..LEVELS)
+            .filter_map(|level| {
+                let entry = self.levels[level].front()?;
+
+                let aged = match age_after {
+                    0 => 0,
+                    n => (tick.saturating_sub(entry.tick) / n) as usize,
+                };
+                let effective = (level + aged).min(LEVELS - 1);
+
+                // Between jobs that have reached the same level, the one
+                // that was submitted first wins.
+                Some((effective, std::cmp::Reverse(entry.seq), level))
+            })
+            .max()
+            .map(|(_, _, level)| level)?;
+
+        self.levels[level].pop_front()
+    }
+
+    /// The longest-waiting job at each priority.
+    pub(super) fn fronts(&self) -> impl Iterator<Item = &Entry> {
+        self.levels.iter().filter_map(|level| level.front())
+    }
+
+    /// Remove the job numbered `seq`, if it's at the front of its queue.
+    pub(super) fn pop_if_front(&mut self, seq: u64) -> Option<Entry> {
+        let level = self
+            .levels
+            .iter()
+            .position(|level| level.front().is_some_and(|entry| entry.seq == seq))?;
+
+        self.levels[level].pop_front()
+    }
+
+    #[cfg(test)]
+    pub(super) fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(seq: u64, tick: u64) -> Entry {
+        Entry {
+            seq,
+            tick,
+            queued_at: Instant::now(),
+            job: Box::new(|| {}),
+        }
+    }
+
+    // Pop everything, counting a tick per job as the scheduler does, and
+    // return the order the jobs came out in.
+    fn drain(queue: &mut PriorityQueue, age_after: u64) -> Vec<u64> {
+        let mut order = Vec::new();
+        let mut tick = 0;
+
+        while let Some(entry) = queue.pop(tick, age_after) {
+            order.push(entry.seq);
+            tick += 1;
+        }
+
+        order
+    }
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let mut queue = PriorityQueue::new();
+
+        queue.push(Priority::Low, entry(0, 0));
+        queue.push(Priority::Normal, entry(1, 0));
+        queue.push(Priority::High, entry(2, 0));
+        queue.push(Priority::Normal, entry(3, 0));
+        queue.push(Priority::High, entry(4, 0));
+
+        assert_eq!(queue.len(), 5);
+        assert_eq!(drain(&mut queue, 0), vec![2, 4, 1, 3, 0]);
+    }
+
+    #[test]
+    fn waiting_jobs_age_into_higher_priorities() {
+        let mut queue = PriorityQueue::new();
+
+        queue.push(Priority::Low, entry(0, 0));
+        for seq in 1..=6 {
+            queue.push(Priority::High, entry(seq, 0));
+        }
+
+        // The low priority job gains a level every two ticks. After four it
+        // has caught up with the high priority jobs and, having been
+        // submitted first, goes ahead of the rest of them.
+        assert_eq!(drain(&mut queue, 2), vec![1, 2, 3, 4, 0, 5, 6]);
+    }
+
+    #[test]
+    fn pop_if_front_only_takes_the_front() {
+        let mut queue = PriorityQueue::new();
+
+        queue.push(Priority::Normal, entry(0, 0));
+        queue.push(Priority::Normal, entry(1, 0));
+        queue.push(Priority::Low, entry(2, 0));
+
+        assert!(queue.pop_if_front(1).is_none());
+        assert_eq!(queue.pop_if_front(2).unwrap().seq, 2);
+        assert_eq!(
+            queue.fronts().map(|entry| entry.seq).collect::<Vec<_>>(),
+            vec![0]
+        );
+    }
+}
@@ -0,0 +1,641 @@
+use super::priority::{Entry, Priority, PriorityQueue};
+use super::{lock, Job};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many times an idle worker checks the queues before going to sleep.
+const SPINS: usize = 16;
+
+thread_local! {
+    // Which scheduler and queue the current thread works for, if it's a
+    // worker. Jobs submitted from inside a job go to the submitting worker's
+    // own queue, where they're likely to run while their data is still in
+    // that CPU's cache.
+    static CURRENT: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// What a [`ThreadPool`](super::ThreadPool) with a bounded queue does with a
+/// job that arrives when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until a worker frees up a slot.
+    Block,
+    /// Turn the job away with [`ExecuteError::QueueFull`](super::ExecuteError::QueueFull).
+    Reject,
+    /// Throw away the job that has been waiting longest to make room. A
+    /// dropped [`spawn`](super::ThreadPool::spawn) job's handle reports
+    /// [`JoinError::Cancelled`](super::JoinError::Cancelled).
+    DropOldest,
+    /// Run the job straight away on the thread that submitted it. This slows
+    /// down the submitter, which is often exactly the push back you want.
+    CallerRuns,
+}
+
+// What a worker should do next.
+pub(super) enum Next {
+    Job(Job),
+    // Nothing turned up within the keep-alive period.
+    Idle,
+    // The pool is terminating and the queues are empty.
+    Terminate,
+}
+
+// What happened to a job handed to Scheduler::push.
+pub(super) enum Pushed {
+    Queued,
+    // The queue was full. The job comes back so the pool can deal with it
+    // according to the policy.
+    Rejected(Job),
+    RunHere(Job),
+}
+
+/// Hands jobs out to workers.
+///
+/// Every worker has its own queue, so submitting and picking up jobs only
+/// ever contends on one queue's lock at a time, and that lock is never held
+/// while waiting for work. A worker whose queue runs dry steals from the
+/// others before going to sleep.
+pub(super) struct Scheduler {
+    queues: Vec<Mutex<PriorityQueue>>,
+    // Jobs pushed but not yet popped, across every queue. A slot is counted
+    // here before its job is actually in a queue, which is how the capacity
+    // is enforced.
+    queued: AtomicUsize,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+    seq: AtomicU64,
+    // Jobs taken out of the queues so far. Waiting jobs age against this
+    // rather than the clock, so priorities behave the same however fast the
+    // machine is.
+    started: AtomicU64,
+    // How many jobs a waiting job sees started before it moves up a
+    // priority level. Zero means never.
+    age_after: u64,
+    high_water: AtomicUsize,
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+    caller_runs: AtomicU64,
+    // Submitters blocked waiting for room, and what they wait on.
+    blocked: AtomicUsize,
+    space: Mutex<()>,
+    freed: Condvar,
+    // Round-robin position for jobs submitted from outside the pool.
+    next: AtomicUsize,
+    // Workers asleep (or about to be) waiting for a job.
+    idle: AtomicUsize,
+    // How long a worker sleeps before being told it's idle, if ever.
+    keep_alive: Option<Duration>,
+    // Guards going to sleep and waking up. Holds whether the pool is
+    // terminating.
+    sleep: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Scheduler {
+    pub(super) fn new(
+        workers: usize,
+        capacity: Option<usize>,
+        policy: OverflowPolicy,
+    ) -> Scheduler {
+        Scheduler {
+            queues: (0..workers)
+                .map(|_| Mutex::new(PriorityQueue::new()))
+                .collect(),
+            queued: AtomicUsize::new(0),
+            capacity,
+            policy,
+            seq: AtomicU64::new(0),
+            started: AtomicU64::new(0),
+            age_after: 0,
+            high_water: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            caller_runs: AtomicU64::new(0),
+            blocked: AtomicUsize::new(0),
+            space: Mutex::new(()),
+            freed: Condvar::new(),
+            next: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            keep_alive: None,
+            sleep: Mutex::new(false),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Have workers that find nothing to do for `keep_alive` told so, so they
+    /// can retire.
+    pub(super) fn with_keep_alive(mut self, keep_alive: Duration) -> Scheduler {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Move waiting jobs up a priority level for every `age_after` jobs
+    /// started ahead of them.
+    pub(super) fn with_aging(mut self, age_after: u64) -> Scheduler {
+        self.age_after = age_after;
+        self
+    }
+
+    /// Mark the calling thread as the worker that owns queue `index`.
+    pub(super) fn enter(&self, index: usize) {
+        CURRENT.with(|current| current.set(Some((self.id(), index))));
+    }
+
+    pub(super) fn push(&self, job: Job, priority: Priority) -> Pushed {
+        self.push_as(job, priority, self.policy)
+    }
+
+    // Like push, but a full queue never holds up the caller, either by
+    // making it wait or by handing the job back to run itself. Those
+    // policies turn the job away instead.
+    pub(super) fn try_push(&self, job: Job, priority: Priority) -> Pushed {
+        let policy = match self.policy {
+            OverflowPolicy::Block | OverflowPolicy::CallerRuns => OverflowPolicy::Reject,
+            policy => policy,
+        };
+        self.push_as(job, priority, policy)
+    }
+
+    fn push_as(&self, job: Job, priority: Priority, policy: OverflowPolicy) -> Pushed {
+        // The job has to be counted before we look for idle workers. A worker
+        // counts itself as idle before it checks for jobs, so between the two
+        // of us at least one will see the other (see next_job).
+        if !self.reserve() {
+            match policy {
+                OverflowPolicy::Block => self.wait_for_space(),
+                OverflowPolicy::Reject => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Pushed::Rejected(job);
+                }
+                OverflowPolicy::DropOldest => {
+                    if !self.drop_oldest() {
+                        // Every counted job has already been picked up, so
+                        // there's nothing to drop; try again for a free slot.
+                        return self.push_as(job, priority, policy);
+                    }
+                }
+                OverflowPolicy::CallerRuns => {
+                    self.caller_runs.fetch_add(1, Ordering::Relaxed);
+                    return Pushed::RunHere(job);
+                }
+            }
+        }
+
+        let index = match CURRENT.with(|current| current.get()) {
+            Some((id, index)) if id == self.id() => index,
+            _ => self.next.fetch_add(1, Ordering::Relaxed) % self.queues.len(),
+        };
+
+        let entry = Entry {
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            tick: self.started.load(Ordering::SeqCst),
+            queued_at: Instant::now(),
+            job,
+        };
+        lock(&self.queues[index]).push(priority, entry);
+
+        if self.idle.load(Ordering::SeqCst) > 0 {
+            // Taking the lock means a worker that's on its way to sleep has
+            // got there, so it can't miss this notification.
+            let _sleep = lock(&self.sleep);
+            self.wake.notify_one();
+        }
+
+        Pushed::Queued
+    }
+
+    /// Wait for the next job for the worker owning queue `index`.
+    pub(super) fn next_job(&self, index: usize) -> Next {
+        let mut timed_out = false;
+
+        loop {
+            // Going to sleep and being woken up is expensive compared to a
+            // small job, so look around a few times before giving up.
+            for _ in 0..SPINS {
+                if let Some(job) = self.pop(index).or_else(|| self.steal(index)) {
+                    return Next::Job(job);
+                }
+                thread::yield_now();
+            }
+
+            let terminating = lock(&self.sleep);
+            self.idle.fetch_add(1, Ordering::SeqCst);
+
+            // A job may have arrived while we were looking, or be sitting in
+            // a queue we couldn't lock. Either way, go round again.
+            if self.queued.load(Ordering::SeqCst) > 0 {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+
+            if *terminating {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                return Next::Terminate;
+            }
+
+            if timed_out {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                return Next::Idle;
+            }
+
+            match self.keep_alive {
+                Some(keep_alive) => {
+                    let (_terminating, result) = self
+                        .wake
+                        .wait_timeout(terminating, keep_alive)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    timed_out = result.timed_out();
+                }
+                None => {
+                    let _terminating = self
+                        .wake
+                        .wait(terminating)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Wake every worker and let them exit once the queues are drained.
+    pub(super) fn terminate(&self) {
+        *lock(&self.sleep) = true;
+        self.wake.notify_all();
+    }
+
+    /// How long the oldest queued job has been waiting.
+    pub(super) fn oldest_wait(&self) -> Duration {
+        self.queues
+            .iter()
+            .filter_map(|queue| lock(queue).fronts().map(|entry| entry.queued_at).min())
+            .min()
+            .map_or(Duration::ZERO, |queued_at| queued_at.elapsed())
+    }
+
+    pub(super) fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            depth: self.queued.load(Ordering::SeqCst),
+            capacity: self.capacity,
+            high_water: self.high_water.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            caller_runs: self.caller_runs.load(Ordering::Relaxed),
+        }
+    }
+
+    // Claim a slot for a new job, if there's room.
+    fn reserve(&self) -> bool {
+        let capacity = self.capacity.unwrap_or(usize::MAX);
+        let reserved = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                if queued < capacity {
+                    Some(queued + 1)
+                } else {
+                    None
+                }
+            });
+
+        match reserved {
+            Ok(previous) => {
+                self.high_water.fetch_max(previous + 1, Ordering::Relaxed);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Block until we manage to reserve a slot.
+    fn wait_for_space(&self) {
+        loop {
+            let space = lock(&self.space);
+            self.blocked.fetch_add(1, Ordering::SeqCst);
+
+            // Same dance as in next_job: check again after saying we're
+            // waiting, so a slot freed in between isn't missed.
+            let reserved = self.reserve();
+            if !reserved {
+                let _space = self
+                    .freed
+                    .wait(space)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+
+            self.blocked.fetch_sub(1, Ordering::SeqCst);
+            if reserved {
+                return;
+            }
+        }
+    }
+
+    // Remove the job that has been queued longest, keeping its slot for the
+    // job that's taking its place. Returns false if every queue was empty.
+    fn drop_oldest(&self) -> bool {
+        loop {
+            let oldest = self
+                .queues
+                .iter()
+                .enumerate()
+                .filter_map(|(index, queue)| {
+                    let oldest = lock(queue).fronts().map(|entry| entry.seq).min();
+                    oldest.map(|seq| (seq, index))
+                })
+                .min();
+
+            let (seq, index) = match oldest {
+                Some(oldest) => oldest,
+                None => return false,
+            };
+
+            // The queue may have changed since we looked, in which case look
+            // again.
+            let job = lock(&self.queues[index]).pop_if_front(seq);
+
+            if let Some(Entry { job, .. }) = job {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                // Dropping a job can run arbitrary code (it may own a
+                // connection that wants to say goodbye), so make sure no lock
+                // is held while that happens.
+                drop(job);
+                return true;
+            }
+        }
+    }
+
+    fn pop(&self, index: usize) -> Option<Job> {
+        let job = self.pop_from(&mut lock(&self.queues[index]));
+        job.map(|job| self.taken(job))
+    }
+
+    // Take the most urgent job from `queue`, counting it as started.
+    fn pop_from(&self, queue: &mut PriorityQueue) -> Option<Job> {
+        let tick = self.started.load(Ordering::SeqCst);
+        let entry = queue.pop(tick, self.age_after)?;
+
+        self.started.fetch_add(1, Ordering::SeqCst);
+        Some(entry.job)
+    }
+
+    // Account for a job leaving the queues.
+    fn taken(&self, job: Job) -> Job {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _space = lock(&self.space);
+            self.freed.notify_one();
+        }
+
+        job
+    }
+
+    // Take the most urgent job from another worker's queue. Queues that are busy
+    // are skipped rather than waited on; if that means we miss a job, the
+    // queued count sends us back round to try again.
+    fn steal(&self, thief: usize) -> Option<Job> {
+        let n = self.queues.len();
+
+        for offset in 1..n {
+            let victim = &self.queues[(thief + offset) % n];
+
+            let mut queue = match victim.try_lock() {
+                Ok(queue) => queue,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => continue,
+            };
+
+            if let Some(job) = self.pop_from(&mut queue) {
+                drop(queue);
+                return Some(self.taken(job));
+            }
+        }
+
+        None
+    }
+
+    // The scheduler's address identifies it to the thread-local above.
+    fn id(&self) -> usize {
+        self as *const Scheduler as usize
+    }
+}
+
+/// A snapshot of a [`ThreadPool`](super::ThreadPool)'s job queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueMetrics {
+    /// Jobs waiting for a worker right now.
+    pub depth: usize,
+    /// The most jobs the queue will hold, or `None` if it's unbounded.
+    pub capacity: Option<usize>,
+    /// The deepest the queue has been.
+    pub high_water: usize,
+    /// Jobs turned away by [`OverflowPolicy::Reject`].
+    pub rejected: u64,
+    /// Jobs thrown away by [`OverflowPolicy::DropOldest`].
+    pub dropped: u64,
+    /// Jobs run on the submitting thread by [`OverflowPolicy::CallerRuns`].
+    pub caller_runs: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    fn job(tx: &mpsc::Sender<usize>, n: usize) -> Job {
+        let tx = tx.clone();
+        Box::new(move || tx.send(n).unwrap())
+    }
+
+    fn next(scheduler: &Scheduler, index: usize) -> Option<Job> {
+        match scheduler.next_job(index) {
+            Next::Job(job) => Some(job),
+            Next::Idle | Next::Terminate => None,
+        }
+    }
+
+    fn run(pushed: Pushed) -> &'static str {
+        match pushed {
+            Pushed::Queued => "queued",
+            Pushed::Rejected(_) => "rejected",
+            Pushed::RunHere(job) => {
+                job();
+                "ran here"
+            }
+        }
+    }
+
+    #[test]
+    fn outside_submissions_are_spread_round_robin() {
+        let scheduler = Scheduler::new(3, None, OverflowPolicy::Block);
+        let (tx, _rx) = mpsc::channel();
+
+        for n in 0..6 {
+            scheduler.push(job(&tx, n), Priority::Normal);
+        }
+
+        for queue in &scheduler.queues {
+            assert_eq!(lock(queue).len(), 2);
+        }
+        assert_eq!(scheduler.metrics().depth, 6);
+    }
+
+    #[test]
+    fn idle_workers_steal_the_oldest_job() {
+        let scheduler = Scheduler::new(2, None, OverflowPolicy::Block);
+        let (tx, rx) = mpsc::channel();
+
+        // Pretend to be worker 0, so every job lands on its queue.
+        scheduler.enter(0);
+        for n in 0..3 {
+            scheduler.push(job(&tx, n), Priority::Normal);
+        }
+        assert_eq!(lock(&scheduler.queues[1]).len(), 0);
+
+        // Worker 1 has nothing of its own, so it takes from worker 0.
+        (next(&scheduler, 1).unwrap())();
+        assert_eq!(rx.recv().unwrap(), 0);
+        assert_eq!(scheduler.metrics().depth, 2);
+    }
+
+    #[test]
+    fn terminating_drains_before_stopping() {
+        let scheduler = Scheduler::new(1, None, OverflowPolicy::Block);
+        let (tx, rx) = mpsc::channel();
+
+        scheduler.push(job(&tx, 7), Priority::Normal);
+        scheduler.terminate();
+
+        (next(&scheduler, 0).unwrap())();
+        assert_eq!(rx.recv().unwrap(), 7);
+        assert!(next(&scheduler, 0).is_none());
+    }
+
+    #[test]
+    fn a_full_queue_rejects() {
+        let scheduler = Scheduler::new(2, Some(2), OverflowPolicy::Reject);
+        let (tx, _rx) = mpsc::channel();
+
+        assert_eq!(run(scheduler.push(job(&tx, 0), Priority::Normal)), "queued");
+        assert_eq!(run(scheduler.push(job(&tx, 1), Priority::Normal)), "queued");
+        assert_eq!(
+            run(scheduler.push(job(&tx, 2), Priority::Normal)),
+            "rejected"
+        );
+
+        // Taking a job out makes room again.
+        (next(&scheduler, 0).unwrap())();
+        assert_eq!(run(scheduler.push(job(&tx, 3), Priority::Normal)), "queued");
+
+        let metrics = scheduler.metrics();
+        assert_eq!(metrics.depth, 2);
+        assert_eq!(metrics.high_water, 2);
+        assert_eq!(metrics.rejected, 1);
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_oldest_job() {
+        let scheduler = Scheduler::new(2, Some(3), OverflowPolicy::DropOldest);
+        let (tx, rx) = mpsc::channel();
+
+        for n in 0..5 {
+            assert_eq!(run(scheduler.push(job(&tx, n), Priority::Normal)), "queued");
+        }
+        scheduler.terminate();
+
+        // 0 and 1 made way for 3 and 4, even though they sat in different
+        // queues.
+        let mut left = Vec::new();
+        while let Some(job) = next(&scheduler, 0) {
+            job();
+            left.push(rx.recv().unwrap());
+        }
+        left.sort_unstable();
+
+        assert_eq!(left, vec![2, 3, 4]);
+        assert_eq!(scheduler.metrics().dropped, 2);
+    }
+
+    #[test]
+    fn a_full_queue_hands_the_job_back_to_the_caller() {
+        let scheduler = Scheduler::new(1, Some(1), OverflowPolicy::CallerRuns);
+        let (tx, rx) = mpsc::channel();
+
+        assert_eq!(run(scheduler.push(job(&tx, 0), Priority::Normal)), "queued");
+        assert_eq!(
+            run(scheduler.push(job(&tx, 1), Priority::Normal)),
+            "ran here"
+        );
+
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert_eq!(scheduler.metrics().caller_runs, 1);
+    }
+
+    #[test]
+    fn a_full_queue_blocks_until_there_is_room() {
+        let scheduler = Arc::new(Scheduler::new(1, Some(1), OverflowPolicy::Block));
+        let (tx, rx) = mpsc::channel();
+
+        scheduler.push(job(&tx, 0), Priority::Normal);
+
+        let submitter = {
+            let scheduler = Arc::clone(&scheduler);
+            thread::spawn(move || run(scheduler.push(job(&tx, 1), Priority::Normal)))
+        };
+
+        // The submitter can't get anywhere until a job is taken.
+        thread::sleep(Duration::from_millis(50));
+        assert!(!submitter.is_finished());
+
+        (next(&scheduler, 0).unwrap())();
+        assert_eq!(submitter.join().unwrap(), "queued");
+        assert_eq!(rx.recv().unwrap(), 0);
+        assert_eq!(scheduler.metrics().depth, 1);
+    }
+
+    #[test]
+    fn idle_workers_are_told_after_the_keep_alive() {
+        let scheduler = Scheduler::new(1, None, OverflowPolicy::Block)
+            .with_keep_alive(Duration::from_millis(20));
+
+        assert!(matches!(scheduler.next_job(0), Next::Idle));
+    }
+
+    #[test]
+    fn oldest_wait_tracks_the_longest_waiting_job() {
+        let scheduler = Scheduler::new(2, None, OverflowPolicy::Block);
+        let (tx, _rx) = mpsc::channel();
+
+        assert_eq!(scheduler.oldest_wait(), Duration::ZERO);
+
+        scheduler.push(job(&tx, 0), Priority::Normal);
+        thread::sleep(Duration::from_millis(30));
+        scheduler.push(job(&tx, 1), Priority::Normal);
+
+        assert!(scheduler.oldest_wait() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn workers_take_the_most_urgent_job_they_can_see() {
+        let scheduler = Scheduler::new(1, None, OverflowPolicy::Block).with_aging(2);
+        let (tx, rx) = mpsc::channel();
+
+        scheduler.push(job(&tx, 0), Priority::Low);
+        scheduler.push(job(&tx, 1), Priority::Normal);
+        for n in 2..7 {
+            scheduler.push(job(&tx, n), Priority::High);
+        }
+        scheduler.terminate();
+
+        while let Some(job) = next(&scheduler, 0) {
+            job();
+        }
+
+        // The normal job ages into a high one after two jobs have started
+        // ahead of it, and the low one after four.
+        let order: Vec<usize> = rx.try_iter().collect();
+        assert_eq!(order, vec![2, 3, 1, 4, 0, 5, 6]);
+    }
+}
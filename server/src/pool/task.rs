@@ -0,0 +1,159 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// A handle to the result of a job submitted with
+/// [`ThreadPool::spawn`](crate::ThreadPool::spawn).
+///
+/// It works like `std::thread::JoinHandle`, except that the job runs on one
+/// of the pool's existing workers rather than on a thread of its own.
+pub struct TaskHandle<T> {
+    state: Arc<State<T>>,
+}
+
+/// Why a task didn't produce a value.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The task panicked. This holds the value it panicked with, just like
+    /// the `Err` returned by `std::thread::JoinHandle::join`.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The task was dropped by the pool without ever running.
+    Cancelled,
+}
+
+impl JoinError {
+    /// The panic payload, if the task panicked.
+    pub fn into_panic(self) -> Option<Box<dyn Any + Send + 'static>> {
+        match self {
+            JoinError::Panicked(payload) => Some(payload),
+            JoinError::Cancelled => None,
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(_) => write!(f, "the task panicked"),
+            JoinError::Cancelled => write!(f, "the task was cancelled before it ran"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+struct State<T> {
+    result: Mutex<Option<Result<T, JoinError>>>,
+    finished: Condvar,
+}
+
+impl<T> State<T> {
+    fn lock(&self) -> MutexGuard<'_, Option<Result<T, JoinError>>> {
+        self.result.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The sending half of a task, moved into the job that computes its result.
+///
+/// If it's dropped without a result having been sent (because the job
+/// itself was dropped before it could run), the task is marked as cancelled
+/// so nobody waits on it forever.
+pub(crate) struct Completion<T> {
+    state: Option<Arc<State<T>>>,
+}
+
+impl<T> Completion<T> {
+    pub(crate) fn complete(mut self, result: Result<T, JoinError>) {
+        if let Some(state) = self.state.take() {
+            *state.lock() = Some(result);
+            state.finished.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            *state.lock() = Some(Err(JoinError::Cancelled));
+            state.finished.notify_all();
+        }
+    }
+}
+
+/// Create a linked completion and handle for a new task.
+pub(crate) fn task<T>() -> (Completion<T>, TaskHandle<T>) {
+    let state = Arc::new(State {
+        result: Mutex::new(None),
+        finished: Condvar::new(),
+    });
+
+    let completion = Completion {
+        state: Some(Arc::clone(&state)),
+    };
+
+    (completion, TaskHandle { state })
+}
+
+impl<T> TaskHandle<T> {
+    /// Wait for the task to finish and return its result.
+    pub fn join(self) -> Result<T, JoinError> {
+        let mut result = self.state.lock();
+
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+
+            result = self
+                .state
+                .finished
+                .wait(result)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Wait up to `timeout` for the task to finish.
+    ///
+    /// If it finishes in time you get its result. If not, you get the handle
+    /// back so you can keep waiting or check on it later.
+    pub fn join_timeout(self, timeout: Duration) -> Result<Result<T, JoinError>, TaskHandle<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut result = self.state.lock();
+
+        loop {
+            if let Some(result) = result.take() {
+                return Ok(result);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                drop(result);
+                return Err(self);
+            }
+
+            // wait_timeout can wake up early, so loop until the result is
+            // there or the deadline has really passed.
+            result = self
+                .state
+                .finished
+                .wait_timeout(result, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Check whether the task has finished, without waiting for it.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().is_some()
+    }
+}
+
+impl<T> fmt::Debug for TaskHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TaskHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
@@ -0,0 +1,386 @@
+use super::{lock, Job};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Where a pool's delayed and periodic jobs get the time from.
+///
+/// The pool uses [`SystemClock`] unless its [`PoolConfig`](super::PoolConfig)
+/// says otherwise. Tests can swap in a clock they move forward themselves,
+/// rather than sleeping until jobs fall due.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The longest the timer thread waits before looking at the clock
+    /// again, or `None` to sleep until the next job is due. A clock that can
+    /// jump ahead should keep this short, since the timer works out how long
+    /// to sleep in real time.
+    fn recheck_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Clock").field("now", &self.now()).finish()
+    }
+}
+
+/// The real time, from [`Instant::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// A clock that only moves when a test says so.
+#[cfg(test)]
+#[derive(Clone)]
+pub(super) struct MockClock(Arc<Mutex<Instant>>);
+
+#[cfg(test)]
+impl MockClock {
+    pub(super) fn new() -> MockClock {
+        MockClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub(super) fn advance(&self, by: Duration) {
+        *lock(&self.0) += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *lock(&self.0)
+    }
+
+    fn recheck_after(&self) -> Option<Duration> {
+        Some(Duration::from_millis(1))
+    }
+}
+
+// The life of a timed job, shared between the timer and its handle.
+const PENDING: u8 = 0;
+const FIRED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// A handle to a job scheduled with
+/// [`ThreadPool::execute_after`](super::ThreadPool::execute_after) or
+/// [`ThreadPool::execute_every`](super::ThreadPool::execute_every).
+///
+/// Dropping the handle leaves the job scheduled.
+#[derive(Clone)]
+pub struct TimerHandle {
+    state: Arc<AtomicU8>,
+}
+
+impl TimerHandle {
+    /// Stop the job from running again.
+    ///
+    /// Returns false if there was nothing left to cancel: the job was already
+    /// cancelled, or it was a one-off job that has already been handed to a
+    /// worker. A periodic job that's already on its way to a worker still
+    /// runs that one last time.
+    pub fn cancel(&self) -> bool {
+        self.state
+            .compare_exchange(PENDING, CANCELLED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == CANCELLED
+    }
+}
+
+impl fmt::Debug for TimerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TimerHandle")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+pub(super) enum Action {
+    Once(Job),
+    Every(Arc<dyn Fn() + Send + Sync + 'static>, Duration),
+}
+
+struct Timed {
+    action: Action,
+    state: Arc<AtomicU8>,
+}
+
+struct Timers {
+    // Ordered by deadline, then by the order they were scheduled in so that
+    // jobs due at the same moment run in submission order.
+    pending: BTreeMap<(Instant, u64), Timed>,
+    next_id: u64,
+    stopped: bool,
+}
+
+/// Holds jobs until they're due, then hands them to the pool.
+pub(super) struct Timer {
+    clock: Arc<dyn Clock>,
+    timers: Mutex<Timers>,
+    changed: Condvar,
+}
+
+impl Timer {
+    pub(super) fn new(clock: Arc<dyn Clock>) -> Timer {
+        Timer {
+            clock,
+            timers: Mutex::new(Timers {
+                pending: BTreeMap::new(),
+                next_id: 0,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Schedule `action` to become due after `delay`.
+    pub(super) fn schedule(&self, delay: Duration, action: Action) -> TimerHandle {
+        let state = Arc::new(AtomicU8::new(PENDING));
+        let deadline = self.clock.now() + delay;
+
+        let mut timers = lock(&self.timers);
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.pending.insert(
+            (deadline, id),
+            Timed {
+                action,
+                state: Arc::clone(&state),
+            },
+        );
+
+        // The new job may be due before whatever the timer thread is waiting
+        // for, so have it take another look.
+        self.changed.notify_one();
+
+        TimerHandle { state }
+    }
+
+    /// Take the jobs that are due, scheduling the next run of periodic ones.
+    pub(super) fn due(&self) -> Vec<Job> {
+        let now = self.clock.now();
+        let mut timers = lock(&self.timers);
+        take_due(&mut timers, now)
+    }
+
+    /// Hand jobs to `submit` as they fall due, until the timer is stopped.
+    pub(super) fn run(&self, submit: impl Fn(Job)) {
+        loop {
+            // Submitting may block if the pool's queue is full, so it
+            // happens without holding the lock.
+            let jobs = self.due();
+            if !jobs.is_empty() {
+                jobs.into_iter().for_each(&submit);
+                continue;
+            }
+
+            let timers = lock(&self.timers);
+            if timers.stopped {
+                return;
+            }
+
+            // Anything scheduled since we looked is in here too, so nothing
+            // can be missed while we sleep.
+            let now = self.clock.now();
+            match timers.pending.keys().next() {
+                Some((deadline, _)) if *deadline <= now => continue,
+                Some((deadline, _)) => {
+                    let mut timeout = *deadline - now;
+                    if let Some(limit) = self.clock.recheck_after() {
+                        timeout = timeout.min(limit);
+                    }
+                    let _timers = self
+                        .changed
+                        .wait_timeout(timers, timeout)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                None => {
+                    let _timers = self
+                        .changed
+                        .wait(timers)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+        }
+    }
+
+    /// Stop the timer thread. Jobs that haven't fallen due are dropped.
+    pub(super) fn stop(&self) {
+        let mut timers = lock(&self.timers);
+        timers.stopped = true;
+        timers.pending.clear();
+        self.changed.notify_all();
+    }
+}
+
+fn take_due(timers: &mut Timers, now: Instant) -> Vec<Job> {
+    let mut jobs = Vec::new();
+
+    while let Some(entry) = timers.pending.first_entry() {
+        let (deadline, _) = *entry.key();
+        if deadline > now {
+            break;
+        }
+
+        let timed = entry.remove();
+
+        match timed.action {
+            Action::Once(job) => {
+                // Only run it if it wasn't cancelled first.
+                let fired = timed
+                    .state
+                    .compare_exchange(PENDING, FIRED, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok();
+                if fired {
+                    jobs.push(job);
+                }
+            }
+            Action::Every(job, interval) => {
+                if timed.state.load(Ordering::SeqCst) == CANCELLED {
+                    continue;
+                }
+
+                let run = Arc::clone(&job);
+                jobs.push(Box::new(move || run()));
+
+                // Keep to the original schedule, unless we've fallen so far
+                // behind that we'd owe several runs at once. Then start
+                // counting again from now.
+                let mut next = deadline + interval;
+                if next <= now {
+                    next = now + interval;
+                }
+
+                let id = timers.next_id;
+                timers.next_id += 1;
+                timers.pending.insert(
+                    (next, id),
+                    Timed {
+                        action: Action::Every(job, interval),
+                        state: timed.state,
+                    },
+                );
+            }
+        }
+    }
+
+    jobs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn timer() -> (Timer, MockClock) {
+        let clock = MockClock::new();
+        (Timer::new(Arc::new(clock.clone())), clock)
+    }
+
+    fn send(tx: &mpsc::Sender<&'static str>, message: &'static str) -> Action {
+        let tx = tx.clone();
+        Action::Once(Box::new(move || tx.send(message).unwrap()))
+    }
+
+    fn run_due(timer: &Timer) -> usize {
+        let jobs = timer.due();
+        let count = jobs.len();
+        jobs.into_iter().for_each(|job| job());
+        count
+    }
+
+    #[test]
+    fn jobs_run_once_their_delay_has_passed() {
+        let (timer, clock) = timer();
+        let (tx, rx) = mpsc::channel();
+
+        timer.schedule(Duration::from_secs(2), send(&tx, "later"));
+        timer.schedule(Duration::from_secs(1), send(&tx, "sooner"));
+
+        assert_eq!(run_due(&timer), 0);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(run_due(&timer), 1);
+        assert_eq!(rx.try_recv().unwrap(), "sooner");
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(run_due(&timer), 1);
+        assert_eq!(rx.try_recv().unwrap(), "later");
+        assert_eq!(run_due(&timer), 0);
+    }
+
+    #[test]
+    fn periodic_jobs_keep_to_their_schedule() {
+        let (timer, clock) = timer();
+        let (tx, rx) = mpsc::channel();
+
+        let every = Action::Every(
+            Arc::new(move || tx.send(()).unwrap()),
+            Duration::from_secs(10),
+        );
+        timer.schedule(Duration::from_secs(10), every);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(run_due(&timer), 0);
+
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(10));
+            assert_eq!(run_due(&timer), 1);
+        }
+        assert_eq!(rx.try_iter().count(), 3);
+
+        // After falling far behind, we get one run rather than a burst.
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(run_due(&timer), 1);
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(run_due(&timer), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(run_due(&timer), 1);
+    }
+
+    #[test]
+    fn cancelled_jobs_never_run() {
+        let (timer, clock) = timer();
+        let (tx, rx) = mpsc::channel();
+
+        let once = timer.schedule(Duration::from_secs(1), send(&tx, "once"));
+        let every = timer.schedule(
+            Duration::from_secs(1),
+            Action::Every(Arc::new(|| {}), Duration::from_secs(1)),
+        );
+
+        assert!(once.cancel());
+        assert!(every.cancel());
+        assert!(!once.cancel());
+        assert!(once.is_cancelled());
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(run_due(&timer), 0);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn jobs_cannot_be_cancelled_once_handed_over() {
+        let (timer, clock) = timer();
+        let (tx, _rx) = mpsc::channel();
+
+        let handle = timer.schedule(Duration::from_secs(1), send(&tx, "once"));
+        clock.advance(Duration::from_secs(1));
+        let jobs = timer.due();
+
+        assert_eq!(jobs.len(), 1);
+        assert!(!handle.cancel());
+        assert!(!handle.is_cancelled());
+    }
+}
@@ -0,0 +1,244 @@
+use super::queue::Next;
+use super::{lock, panic_message, Shared};
+use crate::logging::Event;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A place in the pool for one worker thread.
+///
+/// The pool has a slot for as many workers as it may ever grow to. A slot is
+/// empty until a thread is started in it, and keeps the thread's handle after
+/// it retires so that it can be joined.
+pub(super) struct Worker {
+    pub(super) id: usize,
+    // If the thread dies, it swaps in the handle of its replacement.
+    handle: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Worker {
+    pub(super) fn new(id: usize) -> Worker {
+        Worker {
+            id,
+            handle: Mutex::new(None),
+        }
+    }
+
+    pub(super) fn handle(&self) -> MutexGuard<'_, Option<thread::JoinHandle<()>>> {
+        lock(&self.handle)
+    }
+
+    // Wait for the worker's thread to stop. If it was replaced while we were
+    // waiting, wait for the replacement too.
+    pub(super) fn join(&self) {
+        loop {
+            let thread = self.handle().take();
+
+            match thread {
+                Some(thread) => {
+                    let _ = thread.join();
+                }
+                None => break,
+            }
+        }
+    }
+
+    // Whether a new thread can be started in this slot.
+    fn is_vacant(&self) -> bool {
+        self.handle().as_ref().is_none_or(|h| h.is_finished())
+    }
+}
+
+/// Start a thread for the worker in slot `id`.
+pub(super) fn start(shared: &Arc<Shared>, id: usize) -> io::Result<()> {
+    let context = WorkerContext {
+        id,
+        shared: Arc::clone(shared),
+    };
+
+    let previous = shared.workers[id].handle().replace(context.spawn()?);
+
+    // A worker that retired from this slot has already finished, so this
+    // doesn't wait; it just cleans up after the thread.
+    if let Some(previous) = previous {
+        let _ = previous.join();
+    }
+
+    Ok(())
+}
+
+/// Start a thread that adds workers to the pool, up to `max`, whenever jobs
+/// have been waiting longer than `spawn_after`.
+pub(super) fn spawn_monitor(
+    shared: Arc<Shared>,
+    max: usize,
+    spawn_after: Duration,
+) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name(String::from("pool-monitor"))
+        .spawn(move || {
+            // Check often enough that a job isn't kept waiting much longer
+            // than spawn_after.
+            let interval = spawn_after / 2;
+
+            while !shared.stopping.load(Ordering::SeqCst) {
+                thread::park_timeout(interval);
+
+                if shared.scheduler.oldest_wait() >= spawn_after {
+                    grow(&shared, max);
+                }
+            }
+        })
+}
+
+// Start one more worker, unless the pool is already at its largest.
+fn grow(shared: &Arc<Shared>, max: usize) {
+    let claimed = shared
+        .size
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+            if size < max {
+                Some(size + 1)
+            } else {
+                None
+            }
+        });
+
+    let size = match claimed {
+        Ok(previous) => previous + 1,
+        Err(_) => return,
+    };
+
+    // A worker that has just retired may not have quite finished yet, in
+    // which case its slot isn't free and we try again next time round.
+    let started = match shared.workers.iter().find(|worker| worker.is_vacant()) {
+        Some(worker) => match start(shared, worker.id) {
+            // The worker reports that it started itself.
+            Ok(()) => true,
+            Err(e) => {
+                shared.logger.log(&Event::Error {
+                    context: "starting a worker",
+                    error: &e,
+                });
+                false
+            }
+        },
+        None => false,
+    };
+
+    if started {
+        shared.peak_size.fetch_max(size, Ordering::SeqCst);
+    } else {
+        shared.size.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Everything a worker thread needs, kept together so a dying thread can hand
+// it on to its replacement.
+#[derive(Clone)]
+struct WorkerContext {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl WorkerContext {
+    fn spawn(&self) -> io::Result<thread::JoinHandle<()>> {
+        let context = self.clone();
+
+        // thread::Builder lets us name the thread, and unlike thread::spawn it
+        // returns an error instead of panicking if the thread can't be created.
+        thread::Builder::new()
+            .name(format!("worker-{}", self.id))
+            .spawn(move || {
+                let sentinel = Sentinel(Some(context.clone()));
+                context.run();
+                sentinel.disarm();
+            })
+    }
+
+    fn run(&self) {
+        let id = self.id;
+        let scheduler = &self.shared.scheduler;
+        let logger = &self.shared.logger;
+
+        // Each worker owns the queue with the same index as its id.
+        scheduler.enter(id);
+        logger.log(&Event::WorkerStarted { worker: id });
+
+        loop {
+            match scheduler.next_job(id) {
+                Next::Job(job) => {
+                    logger.log(&Event::JobStarted { worker: id });
+                    let started = Instant::now();
+                    self.shared.busy.fetch_add(1, Ordering::SeqCst);
+
+                    // Catch the panic here so that one bad job only costs us
+                    // that job, not the worker running it.
+                    let result = panic::catch_unwind(AssertUnwindSafe(job));
+                    self.shared.busy.fetch_sub(1, Ordering::SeqCst);
+
+                    if let Err(payload) = result {
+                        self.shared.panics.fetch_add(1, Ordering::SeqCst);
+
+                        logger.log(&Event::JobPanicked {
+                            worker: Some(id),
+                            message: panic_message(&*payload),
+                        });
+                    }
+
+                    logger.log(&Event::JobFinished {
+                        worker: id,
+                        duration: started.elapsed(),
+                    });
+                }
+                Next::Idle => {
+                    if self.shared.retire() {
+                        logger.log(&Event::WorkerRetired { worker: id });
+                        return;
+                    }
+                }
+                Next::Terminate => break,
+            }
+        }
+
+        logger.log(&Event::WorkerStopped { worker: id });
+    }
+}
+
+// Lives on a worker thread's stack. If the thread unwinds past the point
+// where catch_unwind can help (dropping a panic payload can itself panic, for
+// example), its destructor starts a new thread to take the worker's place so
+// the pool doesn't shrink.
+struct Sentinel(Option<WorkerContext>);
+
+impl Sentinel {
+    // The thread is exiting normally, so there's nothing to replace.
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        let context = match self.0.take() {
+            Some(context) if thread::panicking() => context,
+            _ => return,
+        };
+
+        let logger = &context.shared.logger;
+        logger.log(&Event::WorkerDied { worker: context.id });
+
+        match context.spawn() {
+            Ok(thread) => *context.shared.workers[context.id].handle() = Some(thread),
+            Err(e) => {
+                context.shared.size.fetch_sub(1, Ordering::SeqCst);
+                logger.log(&Event::Error {
+                    context: "replacing a dead worker",
+                    error: &e,
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::ops::Range;
+
+// A request for more pieces than this gets the whole file instead. Lots of
+// tiny ranges cost far more to send than the bytes in them are worth.
+const MAX_RANGES: usize = 16;
+
+/// What a `Range` header asks for, once it's been checked against the length
+/// of the body.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Ranges {
+    /// The ranges to send, in order, with any that overlap or touch merged.
+    Satisfiable(Vec<Range<usize>>),
+    /// None of the ranges start inside the body.
+    Unsatisfiable,
+}
+
+/// Work out which bytes of a `len`-byte body a `Range` header asks for.
+///
+/// Returns `None` if the header can't be read, isn't in bytes or asks for too
+/// many ranges, all of which mean the whole body should be sent as usual.
+pub(crate) fn parse_range(header: &str, len: usize) -> Option<Ranges> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+
+        if let Some(range) = parse_spec(spec, len)? {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        return Some(Ranges::Unsatisfiable);
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    if merged.len() > MAX_RANGES {
+        return None;
+    }
+
+    Some(Ranges::Satisfiable(merged))
+}
+
+// One `first-last`, `first-` or `-suffix` spec. The outer None means the spec
+// is malformed; the inner one that it's well formed but misses the body.
+fn parse_spec(spec: &str, len: usize) -> Option<Option<Range<usize>>> {
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // The last `suffix` bytes.
+        let suffix = parse_number(last)?;
+        if suffix == 0 || len == 0 {
+            return Some(None);
+        }
+        return Some(Some(len.saturating_sub(suffix)..len));
+    }
+
+    let first = parse_number(first)?;
+    let last = if last.is_empty() {
+        None
+    } else {
+        Some(parse_number(last)?)
+    };
+
+    if let Some(last) = last {
+        if last < first {
+            return None;
+        }
+    }
+    if first >= len {
+        return Some(None);
+    }
+
+    let end = last.map_or(len, |last| last.saturating_add(1).min(len));
+    Some(Some(first..end))
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // A number too big to fit can only be past the end of the body.
+    Some(s.parse().unwrap_or(usize::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn satisfiable(header: &str, len: usize) -> Vec<Range<usize>> {
+        match parse_range(header, len) {
+            Some(Ranges::Satisfiable(ranges)) => ranges,
+            other => panic!("{:?} gave {:?}", header, other),
+        }
+    }
+
+    #[test]
+    fn reads_each_kind_of_range() {
+        assert_eq!(satisfiable("bytes=0-499", 1000), vec![0..500]);
+        assert_eq!(satisfiable("bytes=500-", 1000), vec![500..1000]);
+        assert_eq!(satisfiable("bytes=-200", 1000), vec![800..1000]);
+        assert_eq!(satisfiable("bytes=-2000", 1000), vec![0..1000]);
+        assert_eq!(satisfiable("bytes=900-5000", 1000), vec![900..1000]);
+        assert_eq!(satisfiable("bytes=0-0, -1", 1000), vec![0..1, 999..1000]);
+    }
+
+    #[test]
+    fn merges_ranges_that_overlap_or_touch() {
+        assert_eq!(
+            satisfiable("bytes=500-600, 0-99, 100-199, 550-700", 1000),
+            vec![0..200, 500..701]
+        );
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            Some(Ranges::Unsatisfiable)
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Ranges::Unsatisfiable));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Ranges::Unsatisfiable));
+        assert_eq!(
+            parse_range("bytes=99999999999999999999999-", 1000),
+            Some(Ranges::Unsatisfiable)
+        );
+        // Only the ranges that miss are dropped.
+        assert_eq!(satisfiable("bytes=2000-, 0-9", 1000), vec![0..10]);
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        assert_eq!(parse_range("bytes=500-100", 1000), None);
+        assert_eq!(parse_range("bytes=abc", 1000), None);
+        assert_eq!(parse_range("bytes=1-2-3", 1000), None);
+        assert_eq!(parse_range("items=0-5", 1000), None);
+        assert_eq!(parse_range("0-5", 1000), None);
+
+        let many = (0..20)
+            .map(|i| format!("{}-{}", i * 10, i * 10))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(parse_range(&format!("bytes={}", many), 1000), None);
+    }
+}
@@ -0,0 +1,899 @@
+// An event loop that serves every connection from one thread, using epoll to
+// find the sockets that are ready. Connections only reach the pool once a
+// whole request has arrived, so a slow client costs a buffer rather than a
+// worker.
+
+use crate::connection::{
+    error_status, poll_interval, respond, After, ConnectionConfig, Upgrade, Upgraded,
+    LINGER_TIMEOUT,
+};
+use crate::logging::Event;
+use crate::pool::ThreadPool;
+use crate::request::{Parser, Request};
+use crate::response::{Response, StatusCode};
+use crate::router::Router;
+use crate::shutdown::ShutdownHandle;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Tokens for the two sockets that aren't connections.
+const LISTENER: u64 = 0;
+const WAKER: u64 = 1;
+
+const READABLE: u32 = libc::EPOLLIN as u32;
+const WRITABLE: u32 = libc::EPOLLOUT as u32;
+const HUNG_UP: u32 = (libc::EPOLLHUP | libc::EPOLLERR) as u32;
+
+/// Serve connections from `listener` until `shutdown` is triggered and the
+/// open connections have finished, or `shutdown_timeout` has passed since.
+/// Returns how much of `shutdown_timeout` is left for the pool to drain.
+pub(crate) fn run(
+    listener: TcpListener,
+    pool: &ThreadPool,
+    router: Arc<Router>,
+    config: Arc<ConnectionConfig>,
+    shutdown: &ShutdownHandle,
+    shutdown_timeout: Duration,
+) -> io::Result<Duration> {
+    let mut reactor = Reactor::new(listener, pool, router, config, shutdown.clone())?;
+    let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; 256];
+    let tick = poll_interval(&reactor.config);
+    let mut deadline = None;
+
+    loop {
+        let ready = reactor.poller.wait(&mut events, tick)?;
+        for event in &events[..ready] {
+            let (token, flags) = (event.u64, event.events);
+            match token {
+                LISTENER => reactor.accept(),
+                WAKER => reactor.waker.reset(),
+                _ => reactor.ready(token, flags),
+            }
+        }
+
+        reactor.complete();
+        reactor.check_timers();
+
+        if shutdown.is_shutdown() {
+            let deadline = *deadline.get_or_insert_with(|| {
+                reactor.stop_accepting();
+                Instant::now() + shutdown_timeout
+            });
+
+            let now = Instant::now();
+            if reactor.connections.is_empty() || now >= deadline {
+                return Ok(deadline.saturating_duration_since(now));
+            }
+        }
+    }
+}
+
+struct Reactor<'a> {
+    poller: Poller,
+    listener: Option<TcpListener>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    pool: &'a ThreadPool,
+    router: Arc<Router>,
+    config: Arc<ConnectionConfig>,
+    shutdown: ShutdownHandle,
+    waker: Waker,
+    replies: Sender<Completion>,
+    completions: Receiver<Completion>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    state: State,
+    // What epoll is watching the socket for.
+    interest: u32,
+    // Bytes read but not yet parsed. With pipelining this can hold several
+    // requests.
+    input: Vec<u8>,
+    parser: Parser,
+    output: Vec<u8>,
+    written: usize,
+    last_activity: Instant,
+    // When the first byte of the request we're waiting on arrived.
+    request_started: Option<Instant>,
+}
+
+enum State {
+    Reading,
+    // A worker has the request.
+    Dispatched,
+    Writing(Then),
+    // Refused, and throwing away whatever the client still sends until it
+    // hangs up or the deadline passes.
+    Lingering(Instant),
+}
+
+// What to do once a response is written.
+enum Then {
+    Read,
+    Close,
+    Linger,
+    Upgrade(Upgrade),
+}
+
+// A request a worker has finished with.
+struct Completion {
+    token: u64,
+    outcome: Outcome,
+}
+
+enum Outcome {
+    Response(Vec<u8>, After),
+    Failed(io::Error),
+    Panicked,
+    // The pool never ran the job, because the queue was full or it was
+    // dropped to make room for a newer one.
+    Dropped,
+}
+
+impl<'a> Reactor<'a> {
+    fn new(
+        listener: TcpListener,
+        pool: &'a ThreadPool,
+        router: Arc<Router>,
+        config: Arc<ConnectionConfig>,
+        shutdown: ShutdownHandle,
+    ) -> io::Result<Reactor<'a>> {
+        listener.set_nonblocking(true)?;
+        let poller = Poller::new()?;
+        let waker = Waker::new()?;
+        poller.add(listener.as_raw_fd(), LISTENER, READABLE)?;
+        poller.add(waker.0.as_raw_fd(), WAKER, READABLE)?;
+        let (replies, completions) = mpsc::channel();
+
+        Ok(Reactor {
+            poller,
+            listener: Some(listener),
+            connections: HashMap::new(),
+            next_token: WAKER + 1,
+            pool,
+            router,
+            config,
+            shutdown,
+            waker,
+            replies,
+            completions,
+        })
+    }
+
+    fn accept(&mut self) {
+        while let Some(listener) = &self.listener {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.log_error("accepting a connection", &e);
+                    return;
+                }
+            };
+
+            if let Err(e) = self.register(stream) {
+                self.log_error("accepting a connection", &e);
+            }
+        }
+    }
+
+    fn register(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let token = self.next_token;
+        self.poller.add(stream.as_raw_fd(), token, READABLE)?;
+        self.next_token += 1;
+
+        self.connections.insert(
+            token,
+            Connection {
+                stream,
+                state: State::Reading,
+                interest: READABLE,
+                input: Vec::new(),
+                parser: Parser::new(self.config.limits),
+                output: Vec::new(),
+                written: 0,
+                last_activity: Instant::now(),
+                request_started: None,
+            },
+        );
+        Ok(())
+    }
+
+    // Close the listening socket so new clients are refused rather than left
+    // waiting while we drain.
+    fn stop_accepting(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            let _ = self.poller.delete(listener.as_raw_fd());
+            self.config.logger.log(&Event::ServerStopping);
+        }
+    }
+
+    fn ready(&mut self, token: u64, flags: u32) {
+        let state = match self.connections.get(&token) {
+            Some(connection) => &connection.state,
+            None => return,
+        };
+
+        match state {
+            State::Reading => self.read(token),
+            State::Writing(_) => self.write(token),
+            State::Lingering(_) => self.discard(token),
+            // Nothing is watched while a worker has the request, but epoll
+            // reports errors and hang-ups regardless. There's nobody left to
+            // answer, and the socket would keep being reported until closed.
+            State::Dispatched if flags & HUNG_UP != 0 => self.close(token),
+            State::Dispatched => {}
+        }
+    }
+
+    fn read(&mut self, token: u64) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        // One read per wakeup. If there's more, epoll will say so again, and
+        // in the meantime the request limits get a chance to stop a client
+        // that's sending too much.
+        let mut chunk = [0; 16 * 1024];
+        match (&connection.stream).read(&mut chunk) {
+            // The client closed its end of the connection.
+            Ok(0) => return self.close(token),
+            Ok(n) => {
+                connection.input.extend_from_slice(&chunk[..n]);
+                connection.last_activity = Instant::now();
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                return
+            }
+            Err(_) => return self.close(token),
+        }
+
+        self.parse(token);
+    }
+
+    // Hand the next complete request in the buffer to the pool, if there is
+    // one.
+    fn parse(&mut self, token: u64) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        match connection.parser.parse(&connection.input) {
+            Ok(Some((request, used))) => {
+                connection.input.drain(..used);
+                connection.request_started = None;
+                self.dispatch(token, request);
+            }
+            Ok(None) => {
+                if !connection.input.is_empty() {
+                    connection.request_started.get_or_insert_with(Instant::now);
+                }
+            }
+            Err(e) => {
+                self.config.logger.log(&Event::BadRequest { error: &e });
+
+                // We can't tell where a malformed request ends, so there's no
+                // way to find the next one. Answer and hang up.
+                match error_status(&e) {
+                    Some(status) => self.refuse(token, status),
+                    None => self.close(token),
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self, token: u64, mut request: Request) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        // Stop reading until the response is on its way, so a client can't
+        // pile up requests faster than they're answered.
+        connection.state = State::Dispatched;
+        if let Err(e) = watch(&self.poller, token, connection, 0) {
+            self.log_error("serving a connection", &e);
+            return self.close(token);
+        }
+
+        let reply = Reply {
+            token,
+            replies: self.replies.clone(),
+            waker: self.waker.clone(),
+            sent: false,
+        };
+        let router = Arc::clone(&self.router);
+        let config = Arc::clone(&self.config);
+        let shutdown = self.shutdown.clone();
+
+        // A full queue mustn't wait, or run the job here: either would hold
+        // up every other connection.
+        let result = self.pool.try_execute(move || {
+            let mut output = Vec::new();
+            let outcome = match respond(&mut output, &router, &mut request, &config, &shutdown) {
+                Ok(after) => Outcome::Response(output, after),
+                Err(e) => Outcome::Failed(e),
+            };
+            reply.send(outcome);
+        });
+
+        // The reply goes back with the job, so the client still hears about
+        // it with a 503.
+        if let Err(e) = result {
+            self.log_error("handing off a request", &e);
+        }
+    }
+
+    // Pick up the responses workers have finished.
+    fn complete(&mut self) {
+        while let Ok(Completion { token, outcome }) = self.completions.try_recv() {
+            let (output, then) = match outcome {
+                Outcome::Response(output, After::KeepAlive) => (output, Then::Read),
+                Outcome::Response(output, After::Close) => (output, Then::Close),
+                Outcome::Response(output, After::Upgrade(upgrade)) => {
+                    (output, Then::Upgrade(upgrade))
+                }
+                Outcome::Failed(e) => {
+                    self.log_error("serving a connection", &e);
+                    self.close(token);
+                    continue;
+                }
+                // The pool has already logged the panic.
+                Outcome::Panicked => (
+                    Response::new(StatusCode::InternalServerError)
+                        .with_header("Connection", "close")
+                        .to_bytes(),
+                    Then::Close,
+                ),
+                Outcome::Dropped => (
+                    Response::new(StatusCode::ServiceUnavailable)
+                        .with_header("Retry-After", "1")
+                        .with_header("Connection", "close")
+                        .to_bytes(),
+                    Then::Close,
+                ),
+            };
+
+            self.start_writing(token, output, then);
+        }
+    }
+
+    // Answer with an error and hang up.
+    fn refuse(&mut self, token: u64, status: StatusCode) {
+        let output = Response::new(status)
+            .with_header("Connection", "close")
+            .to_bytes();
+        self.start_writing(token, output, Then::Linger);
+    }
+
+    fn start_writing(&mut self, token: u64, output: Vec<u8>, then: Then) {
+        if let Some(connection) = self.connections.get_mut(&token) {
+            connection.output = output;
+            connection.written = 0;
+            connection.state = State::Writing(then);
+            connection.last_activity = Instant::now();
+
+            // Most responses fit in the socket's send buffer, so try now
+            // rather than waiting to hear that it's writable.
+            self.write(token);
+        }
+    }
+
+    fn write(&mut self, token: u64) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        while connection.written < connection.output.len() {
+            match (&connection.stream).write(&connection.output[connection.written..]) {
+                Ok(0) => return self.close(token),
+                Ok(n) => {
+                    connection.written += n;
+                    connection.last_activity = Instant::now();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if let Err(e) = watch(&self.poller, token, connection, WRITABLE) {
+                        self.log_error("serving a connection", &e);
+                        self.close(token);
+                    }
+                    return;
+                }
+                Err(_) => return self.close(token),
+            }
+        }
+
+        connection.output = Vec::new();
+        connection.written = 0;
+        let then = match mem::replace(&mut connection.state, State::Reading) {
+            State::Writing(then) => then,
+            _ => unreachable!("only a connection that's writing has output"),
+        };
+
+        match then {
+            Then::Read => {
+                // However long the response took, the clock for the next
+                // request starts now.
+                connection.last_activity = Instant::now();
+                if let Err(e) = watch(&self.poller, token, connection, READABLE) {
+                    self.log_error("serving a connection", &e);
+                    return self.close(token);
+                }
+
+                // There may already be another request waiting.
+                self.parse(token);
+            }
+            Then::Close => self.close(token),
+            Then::Linger => {
+                // See connection::linger for why we don't just close.
+                connection.state = State::Lingering(Instant::now() + LINGER_TIMEOUT);
+                if connection.stream.shutdown(Shutdown::Write).is_err()
+                    || watch(&self.poller, token, connection, READABLE).is_err()
+                {
+                    self.close(token);
+                }
+            }
+            Then::Upgrade(upgrade) => self.upgrade(token, upgrade),
+        }
+    }
+
+    fn discard(&mut self, token: u64) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let mut discard = [0; 4096];
+        match (&connection.stream).read(&mut discard) {
+            Ok(0) => self.close(token),
+            Ok(_) => {}
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => self.close(token),
+        }
+    }
+
+    // Switch protocols. Whatever the connection is upgraded to takes over the
+    // socket, blocking as usual, on a thread of its own.
+    fn upgrade(&mut self, token: u64, upgrade: Upgrade) {
+        let connection = match self.connections.remove(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let Connection { stream, input, .. } = connection;
+        let _ = self.poller.delete(stream.as_raw_fd());
+
+        let result = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(poll_interval(&self.config))))
+            .and_then(|_| stream.set_write_timeout(Some(self.config.write_timeout)))
+            .and_then(|_| {
+                upgrade.start(Upgraded {
+                    stream: Box::new(stream),
+                    read_ahead: input,
+                    shutdown: self.shutdown.clone(),
+                    logger: self.config.logger.clone(),
+                })
+            });
+
+        if let Err(e) = result {
+            self.log_error("serving a connection", &e);
+        }
+    }
+
+    fn check_timers(&mut self) {
+        let now = Instant::now();
+        let config = &self.config;
+        let shutting_down = self.shutdown.is_shutdown();
+        let mut closing = Vec::new();
+        let mut timed_out = Vec::new();
+
+        for (&token, connection) in &self.connections {
+            let quiet = now - connection.last_activity;
+
+            match connection.state {
+                State::Reading => {
+                    // Only a connection sitting between requests is closed
+                    // for shutdown or for being idle.
+                    let expired = if connection.input.is_empty() {
+                        shutting_down || quiet >= config.idle_timeout
+                    } else {
+                        let started = connection
+                            .request_started
+                            .unwrap_or(connection.last_activity);
+                        quiet >= config.read_timeout || now - started >= config.request_timeout
+                    };
+
+                    if expired && connection.input.is_empty() {
+                        closing.push(token);
+                    } else if expired {
+                        timed_out.push(token);
+                    }
+                }
+                State::Writing(_) if quiet >= config.write_timeout => closing.push(token),
+                // These have had their answer already.
+                State::Lingering(deadline) if shutting_down || now >= deadline => {
+                    closing.push(token)
+                }
+                _ => {}
+            }
+        }
+
+        for token in closing {
+            self.close(token);
+        }
+        for token in timed_out {
+            let error = io::Error::new(io::ErrorKind::TimedOut, "request took too long");
+            self.config.logger.log(&Event::BadRequest { error: &error });
+            self.refuse(token, StatusCode::RequestTimeout);
+        }
+    }
+
+    // Closing the socket also takes it out of epoll.
+    fn close(&mut self, token: u64) {
+        self.connections.remove(&token);
+    }
+
+    fn log_error(&self, context: &'static str, error: &dyn std::error::Error) {
+        self.config.logger.log(&Event::Error { context, error });
+    }
+}
+
+// Watch `connection` for `interest`, if it isn't already.
+fn watch(
+    poller: &Poller,
+    token: u64,
+    connection: &mut Connection,
+    interest: u32,
+) -> io::Result<()> {
+    if connection.interest != interest {
+        poller.modify(connection.stream.as_raw_fd(), token, interest)?;
+        connection.interest = interest;
+    }
+    Ok(())
+}
+
+// Carries a job's response back to the reactor, and wakes it up to send it.
+// If the job never runs, or panics, dropping this says so instead.
+struct Reply {
+    token: u64,
+    replies: Sender<Completion>,
+    waker: Waker,
+    sent: bool,
+}
+
+impl Reply {
+    fn send(mut self, outcome: Outcome) {
+        self.deliver(outcome);
+    }
+
+    fn deliver(&mut self, outcome: Outcome) {
+        self.sent = true;
+        // The reactor may have stopped, in which case nobody's waiting.
+        let _ = self.replies.send(Completion {
+            token: self.token,
+            outcome,
+        });
+        self.waker.wake();
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        if !self.sent {
+            let outcome = if thread::panicking() {
+                Outcome::Panicked
+            } else {
+                Outcome::Dropped
+            };
+            self.deliver(outcome);
+        }
+    }
+}
+
+// Level-triggered epoll: a socket is reported for as long as it's ready.
+struct Poller(OwnedFd);
+
+impl Poller {
+    fn new() -> io::Result<Poller> {
+        // SAFETY: epoll_create1 returns a new descriptor that nothing else
+        // owns, or -1.
+        let fd = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Poller(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn add(&self, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    fn modify(&self, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: libc::c_int, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: interest,
+            u64: token,
+        };
+        // SAFETY: both descriptors are open, and the kernel only reads the
+        // event during the call.
+        check(unsafe { libc::epoll_ctl(self.0.as_raw_fd(), op, fd, &mut event) }).map(|_| ())
+    }
+
+    // Wait up to `timeout` for sockets to be ready, filling in `events` and
+    // returning how many there are.
+    fn wait(&self, events: &mut [libc::epoll_event], timeout: Duration) -> io::Result<usize> {
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        let max = events.len().min(libc::c_int::MAX as usize) as libc::c_int;
+
+        // SAFETY: the kernel writes at most `max` events into `events`.
+        match check(unsafe {
+            libc::epoll_wait(self.0.as_raw_fd(), events.as_mut_ptr(), max, timeout)
+        }) {
+            Ok(ready) => Ok(ready as usize),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// An eventfd that workers write to when they've finished a request, to wake
+// the reactor from epoll_wait.
+#[derive(Clone)]
+struct Waker(Arc<File>);
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        // SAFETY: eventfd returns a new descriptor that nothing else owns, or
+        // -1.
+        let fd = check(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        Ok(Waker(Arc::new(unsafe { File::from_raw_fd(fd) })))
+    }
+
+    fn wake(&self) {
+        // This only fails if the counter is about to overflow, in which case
+        // the reactor has plenty of wakeups pending already.
+        let _ = (&*self.0).write(&1u64.to_ne_bytes());
+    }
+
+    fn reset(&self) {
+        let _ = (&*self.0).read(&mut [0; 8]);
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::ConnectionConfig;
+    use crate::pool::{OverflowPolicy, PoolConfig, ThreadPool};
+    use crate::request::{Request, RequestLimits};
+    use crate::response::{Response, StatusCode};
+    use crate::router::{Params, Router};
+    use crate::server::Server;
+    use crate::shutdown::ShutdownHandle;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn router() -> Router {
+        Router::new()
+            .get("/slow/:ms", |_: &Request, params: &Params| {
+                let ms = params.get("ms").unwrap().parse().unwrap();
+                thread::sleep(Duration::from_millis(ms));
+                Response::new(StatusCode::Ok).with_body("slept")
+            })
+            .get("/:name", |_: &Request, params: &Params| {
+                Response::new(StatusCode::Ok).with_body(params.get("name").unwrap().to_string())
+            })
+    }
+
+    fn start(
+        pool: ThreadPool,
+        config: ConnectionConfig,
+    ) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<bool>) {
+        let server = Server::bind("127.0.0.1:0", pool, router())
+            .unwrap()
+            .with_connection_config(config)
+            .with_event_loop()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().is_clean());
+        (addr, handle, running)
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+    }
+
+    fn read_all(client: &mut TcpStream) -> String {
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn slow_clients_dont_hold_up_the_pool() {
+        let (addr, handle, running) = start(ThreadPool::new(1), ConnectionConfig::default());
+
+        // Each of these would have the only worker to itself in the blocking
+        // model, waiting for the rest of its request.
+        let mut slow: Vec<_> = (0..4)
+            .map(|_| {
+                let mut client = connect(addr);
+                client.write_all(b"GET /slow/0 HTTP/1.1\r\nHo").unwrap();
+                client
+            })
+            .collect();
+
+        let start = Instant::now();
+        let mut client = connect(addr);
+        client
+            .write_all(b"GET /fast HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert!(read_all(&mut client).ends_with("fast"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The slow clients are still served once they finish.
+        for client in &mut slow {
+            client
+                .write_all(b"st: x\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            assert!(read_all(client).ends_with("slept"));
+        }
+
+        handle.shutdown();
+        assert!(running.join().unwrap());
+    }
+
+    #[test]
+    fn serves_pipelined_requests_in_order() {
+        let (addr, handle, running) = start(ThreadPool::new(2), ConnectionConfig::default());
+        let mut client = connect(addr);
+
+        client
+            .write_all(
+                b"GET /one HTTP/1.1\r\nHost: x\r\n\r\n\
+                  GET /slow/50 HTTP/1.1\r\nHost: x\r\n\r\n\
+                  GET /three HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let response = read_all(&mut client);
+
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+        let one = response.find("one").unwrap();
+        let two = response.find("slept").unwrap();
+        let three = response.find("three").unwrap();
+        assert!(one < two && two < three);
+
+        handle.shutdown();
+        assert!(running.join().unwrap());
+    }
+
+    #[test]
+    fn enforces_the_same_timeouts_and_limits() {
+        let config = ConnectionConfig {
+            read_timeout: Duration::from_millis(200),
+            limits: RequestLimits {
+                max_head: 256,
+                max_body: 16,
+            },
+            ..ConnectionConfig::default()
+        };
+        let (addr, handle, running) = start(ThreadPool::new(1), config);
+
+        let mut client = connect(addr);
+        client.write_all(b"GET /stall HTTP/1.1\r\nHo").unwrap();
+        assert!(read_all(&mut client).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        let mut client = connect(addr);
+        let request = format!("GET /big HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(300));
+        client.write_all(request.as_bytes()).unwrap();
+        assert!(
+            read_all(&mut client).starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n")
+        );
+
+        handle.shutdown();
+        assert!(running.join().unwrap());
+    }
+
+    #[test]
+    fn answers_503_when_the_pool_turns_a_request_away() {
+        // Whatever the policy, the event loop turns the request away rather
+        // than waiting for room or running the handler itself.
+        for overflow in [
+            OverflowPolicy::Reject,
+            OverflowPolicy::Block,
+            OverflowPolicy::CallerRuns,
+        ] {
+            let pool = ThreadPool::with_config(PoolConfig {
+                queue_capacity: Some(1),
+                overflow,
+                ..PoolConfig::new(1)
+            })
+            .unwrap();
+            let (addr, handle, running) = start(pool, ConnectionConfig::default());
+            let get = move |path: &str| {
+                let mut client = connect(addr);
+                write!(
+                    client,
+                    "GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+                    path
+                )
+                .unwrap();
+                read_all(&mut client)
+            };
+
+            // Keep the only worker busy, then take the only place in the queue.
+            let busy = thread::spawn(move || get("/slow/300"));
+            thread::sleep(Duration::from_millis(100));
+            let queued = thread::spawn(move || get("/queued"));
+            thread::sleep(Duration::from_millis(50));
+
+            let rejected = get("/rejected");
+            assert!(
+                rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+                "{:?}",
+                overflow
+            );
+            assert!(busy.join().unwrap().ends_with("slept"));
+            assert!(queued.join().unwrap().ends_with("queued"));
+
+            handle.shutdown();
+            assert!(running.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn finishes_requests_in_flight_on_shutdown() {
+        let (addr, handle, running) = start(ThreadPool::new(1), ConnectionConfig::default());
+
+        let mut client = connect(addr);
+        client
+            .write_all(b"GET /slow/200 HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        handle.shutdown();
+
+        // The keep-alive connection is closed once it's been answered.
+        let response = read_all(&mut client);
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("slept"));
+        assert!(running.join().unwrap());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
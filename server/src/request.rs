@@ -0,0 +1,1015 @@
+use crate::headers::Headers;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+/// The request methods the server understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    // Methods are case-sensitive, so "get" is not the same as "GET".
+    fn from_str(s: &str) -> Result<Method, ParseError> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ if is_token(s) => Err(ParseError::UnsupportedMethod(s.to_string())),
+            _ => Err(ParseError::InvalidRequestLine),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The protocol versions the server speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Version, ParseError> {
+        match s {
+            "HTTP/1.0" => Ok(Version::Http10),
+            "HTTP/1.1" => Ok(Version::Http11),
+            _ => {
+                // Anything shaped like HTTP/x.y is a well-formed version we
+                // just don't speak; anything else is garbage.
+                let bytes = s.as_bytes();
+                if bytes.len() == 8
+                    && s.starts_with("HTTP/")
+                    && bytes[5].is_ascii_digit()
+                    && bytes[6] == b'.'
+                    && bytes[7].is_ascii_digit()
+                {
+                    Err(ParseError::UnsupportedVersion(s.to_string()))
+                } else {
+                    Err(ParseError::InvalidRequestLine)
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The ways reading or parsing a request can fail.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The underlying stream returned an error.
+    Io(io::Error),
+    /// The connection closed before a complete request arrived.
+    UnexpectedEof,
+    /// The request line isn't `Method SP Request-Target SP HTTP-Version`.
+    InvalidRequestLine,
+    /// The method is a valid token, but not one we implement.
+    UnsupportedMethod(String),
+    /// The version is well-formed, but not HTTP/1.0 or HTTP/1.1.
+    UnsupportedVersion(String),
+    /// A header line is malformed.
+    InvalidHeader(String),
+    /// An HTTP/1.1 request didn't include a `Host` header.
+    MissingHost,
+    /// `Content-Length` isn't a number, or appears with conflicting values.
+    InvalidContentLength,
+    /// The request uses a `Transfer-Encoding` we can't decode.
+    UnsupportedTransferEncoding(String),
+    /// The request has both `Transfer-Encoding` and `Content-Length`, so
+    /// there are two answers to where its body ends.
+    ConflictingLength,
+    /// A chunked body isn't framed correctly.
+    InvalidChunk,
+    /// The request line and headers, or a chunked body's trailers, are
+    /// longer than [`RequestLimits::max_head`].
+    HeadTooLarge,
+    /// The body is longer than [`RequestLimits::max_body`].
+    BodyTooLarge,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "I/O error while reading request: {}", e),
+            ParseError::UnexpectedEof => {
+                write!(f, "connection closed before the request was complete")
+            }
+            ParseError::InvalidRequestLine => write!(f, "malformed request line"),
+            ParseError::UnsupportedMethod(m) => write!(f, "unsupported method: {}", m),
+            ParseError::UnsupportedVersion(v) => write!(f, "unsupported HTTP version: {}", v),
+            ParseError::InvalidHeader(line) => write!(f, "malformed header: {:?}", line),
+            ParseError::MissingHost => write!(f, "HTTP/1.1 request is missing a Host header"),
+            ParseError::InvalidContentLength => write!(f, "invalid Content-Length header"),
+            ParseError::UnsupportedTransferEncoding(te) => {
+                write!(f, "unsupported Transfer-Encoding: {}", te)
+            }
+            ParseError::ConflictingLength => {
+                write!(f, "request has both Transfer-Encoding and Content-Length")
+            }
+            ParseError::InvalidChunk => write!(f, "malformed chunked body"),
+            ParseError::HeadTooLarge => write!(f, "request headers are too large"),
+            ParseError::BodyTooLarge => write!(f, "request body is too large"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+/// The most a request is allowed to take up, so a client can't make the
+/// server hold on to an endless stream of headers or body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// The most bytes the request line and headers can take, including the
+    /// line breaks. Trailers after a chunked body get the same allowance.
+    pub max_head: usize,
+    /// The most bytes the body can hold, once any chunked framing is taken
+    /// off.
+    pub max_body: usize,
+}
+
+impl RequestLimits {
+    /// No limits at all, for requests that come from somewhere trusted.
+    pub fn unlimited() -> RequestLimits {
+        RequestLimits {
+            max_head: usize::MAX,
+            max_body: usize::MAX,
+        }
+    }
+}
+
+impl Default for RequestLimits {
+    /// 8 KiB of headers and 1 MiB of body.
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_head: 8 * 1024,
+            max_body: 1024 * 1024,
+        }
+    }
+}
+
+/// A parsed HTTP request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    method: Method,
+    target: String,
+    version: Version,
+    headers: Headers,
+    body: Vec<u8>,
+    trailers: Headers,
+}
+
+impl Request {
+    /// Try to parse a single request from the front of `buf`.
+    ///
+    /// Returns `Ok(None)` if `buf` doesn't hold a complete request yet, in
+    /// which case the caller should read more bytes and try again. On success
+    /// the request is returned along with the number of bytes it used, so
+    /// anything after that belongs to the next request on the connection.
+    ///
+    /// There's no limit on how big the request can be. Use
+    /// [`parse_with_limits`](Request::parse_with_limits) for requests from
+    /// clients that might not be friendly.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+        Request::parse_with_limits(buf, &RequestLimits::unlimited())
+    }
+
+    /// Like [`parse`](Request::parse), but fails as soon as it's clear the
+    /// request will break one of `limits`, without waiting for the rest of
+    /// it to arrive.
+    pub fn parse_with_limits(
+        buf: &[u8],
+        limits: &RequestLimits,
+    ) -> Result<Option<(Request, usize)>, ParseError> {
+        Parser::new(*limits).parse(buf)
+    }
+
+    /// Read a single request from `reader`, calling `read` as many times as it
+    /// takes for a complete request to arrive.
+    ///
+    /// Any bytes read past the end of the request are discarded.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Request, ParseError> {
+        let mut parser = Parser::new(RequestLimits::unlimited());
+        let mut buf = Vec::new();
+        let mut chunk = [0; 1024];
+
+        loop {
+            let n = match reader.read(&mut chunk) {
+                Ok(0) => return Err(ParseError::UnexpectedEof),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            buf.extend_from_slice(&chunk[..n]);
+
+            if let Some((request, _)) = parser.parse(&buf)? {
+                return Ok(request);
+            }
+        }
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// The request target exactly as it appeared in the request line,
+    /// including any query string.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The request target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(i) => &self.target[..i],
+            None => &self.target,
+        }
+    }
+
+    /// The query string, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.target.find('?').map(|i| &self.target[i + 1..])
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The headers, for middleware that needs to add to or change them
+    /// before the handler sees them.
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Shorthand for `request.headers().get(name)`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The body, with any chunked encoding already removed.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Fields sent after a chunked body. These are kept apart from the
+    /// headers because they arrive too late to have affected how the request
+    /// was handled, and so shouldn't be trusted as if they had.
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
+}
+
+fn parse_request_line(line: &[u8]) -> Result<(Method, String, Version), ParseError> {
+    let line = std::str::from_utf8(line).map_err(|_| ParseError::InvalidRequestLine)?;
+
+    // Method SP Request-Target SP HTTP-Version, separated by exactly one space.
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+
+    let method = method.parse()?;
+
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control() || b == b' ') {
+        return Err(ParseError::InvalidRequestLine);
+    }
+
+    let version = version.parse()?;
+
+    Ok((method, target.to_string(), version))
+}
+
+fn parse_header(line: &[u8]) -> Result<(String, String), ParseError> {
+    let invalid = || ParseError::InvalidHeader(String::from_utf8_lossy(line).into_owned());
+
+    // Lines starting with whitespace are the obsolete "line folding" syntax,
+    // which the spec tells servers to reject.
+    if line.starts_with(b" ") || line.starts_with(b"\t") {
+        return Err(invalid());
+    }
+
+    let colon = line.iter().position(|&b| b == b':').ok_or_else(invalid)?;
+    let name = std::str::from_utf8(&line[..colon]).map_err(|_| invalid())?;
+
+    // No whitespace is allowed between the field name and the colon.
+    if !is_token(name) {
+        return Err(invalid());
+    }
+
+    let value = trim_ows(&line[colon + 1..]);
+    if value.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+        return Err(invalid());
+    }
+
+    // Field values are historically Latin-1, so don't insist on UTF-8 here.
+    let value = String::from_utf8_lossy(value).into_owned();
+
+    Ok((name.to_string(), value))
+}
+
+fn content_length(headers: &Headers) -> Result<usize, ParseError> {
+    let mut length = None;
+
+    // Repeated Content-Length headers are only acceptable if they agree,
+    // otherwise we can't tell where the body ends.
+    for value in headers.get_all("Content-Length") {
+        for part in value.split(',') {
+            let part = part.trim();
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::InvalidContentLength);
+            }
+            let n = part
+                .parse::<usize>()
+                .map_err(|_| ParseError::InvalidContentLength)?;
+
+            match length {
+                Some(existing) if existing != n => return Err(ParseError::InvalidContentLength),
+                _ => length = Some(n),
+            }
+        }
+    }
+
+    Ok(length.unwrap_or(0))
+}
+
+// Whether the body is chunked. `chunked` has to be the last coding applied,
+// since it's the one that says where the body ends, and it's the only one we
+// know how to undo.
+fn is_chunked(headers: &Headers) -> Result<bool, ParseError> {
+    let codings: Vec<&str> = headers
+        .get_all("Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .collect();
+
+    match codings.as_slice() {
+        [] => Ok(false),
+        [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(true),
+        _ => Err(ParseError::UnsupportedTransferEncoding(codings.join(", "))),
+    }
+}
+
+/// Parses requests out of a buffer that fills up as bytes arrive.
+///
+/// Calling [`Request::parse_with_limits`] again after every read starts over
+/// each time, which for a big chunked body means decoding all of it again.
+/// This remembers how far it got instead. Between calls the buffer may only
+/// grow at the end, until a request is returned and the caller drops the
+/// bytes it used.
+pub(crate) struct Parser {
+    limits: RequestLimits,
+    // The head of the request whose body we're waiting on.
+    head: Option<Head>,
+}
+
+struct Head {
+    method: Method,
+    target: String,
+    version: Version,
+    headers: Headers,
+    len: usize,
+    framing: Framing,
+}
+
+// How the end of the body is found.
+enum Framing {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+impl Parser {
+    pub(crate) fn new(limits: RequestLimits) -> Parser {
+        Parser { limits, head: None }
+    }
+
+    /// Like [`Request::parse`], but carrying on from the last call.
+    pub(crate) fn parse(&mut self, buf: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+        let head = match &mut self.head {
+            Some(head) => head,
+            None => match parse_head(buf, &self.limits)? {
+                Some(head) => self.head.insert(head),
+                None => return Ok(None),
+            },
+        };
+
+        let end = match &mut head.framing {
+            // The body is framed by Content-Length, so wait until all of it
+            // has arrived.
+            Framing::Length(length) => {
+                let end = head.len + *length;
+                if buf.len() < end {
+                    return Ok(None);
+                }
+                end
+            }
+            Framing::Chunked(decoder) => match decoder.decode(&buf[head.len..], &self.limits)? {
+                Some(used) => head.len + used,
+                None => return Ok(None),
+            },
+        };
+
+        let Head {
+            method,
+            target,
+            version,
+            headers,
+            len,
+            framing,
+        } = self.head.take().expect("the head was parsed above");
+        let (body, trailers) = match framing {
+            Framing::Length(_) => (buf[len..end].to_vec(), Headers::new()),
+            Framing::Chunked(decoder) => (decoder.body, decoder.trailers),
+        };
+
+        let request = Request {
+            method,
+            target,
+            version,
+            headers,
+            body,
+            trailers,
+        };
+
+        Ok(Some((request, end)))
+    }
+}
+
+// Parse the request line and headers, once they've all arrived, and work out
+// how the body is framed.
+fn parse_head(buf: &[u8], limits: &RequestLimits) -> Result<Option<Head>, ParseError> {
+    // The head (request line plus headers) ends with an empty line. Until
+    // we've seen it we can't know how long the body is.
+    let len = match find_within(buf, b"\r\n\r\n", limits.max_head) {
+        Some(i) => i + 4,
+        None if buf.len() >= limits.max_head => return Err(ParseError::HeadTooLarge),
+        None => return Ok(None),
+    };
+
+    // Lines are separated by CRLF. A stray \r or \n left inside a line
+    // makes that line invalid, which the parsers below reject.
+    let mut lines = split_crlf(&buf[..len - 4]);
+
+    let request_line = lines.next().unwrap_or_default();
+    let (method, target, version) = parse_request_line(request_line)?;
+
+    let mut headers = Headers::new();
+    for line in lines {
+        let (name, value) = parse_header(line)?;
+        headers.append(name, value);
+    }
+
+    if version == Version::Http11 && !headers.contains("Host") {
+        return Err(ParseError::MissingHost);
+    }
+
+    let framing = if is_chunked(&headers)? {
+        // Letting Content-Length through as well is how requests get
+        // smuggled past proxies that read the other one.
+        if headers.contains("Content-Length") {
+            return Err(ParseError::ConflictingLength);
+        }
+        Framing::Chunked(ChunkedDecoder::default())
+    } else {
+        let content_length = content_length(&headers)?;
+        if content_length > limits.max_body {
+            return Err(ParseError::BodyTooLarge);
+        }
+        if len.checked_add(content_length).is_none() {
+            return Err(ParseError::InvalidContentLength);
+        }
+        Framing::Length(content_length)
+    };
+
+    Ok(Some(Head {
+        method,
+        target,
+        version,
+        headers,
+        len,
+        framing,
+    }))
+}
+
+// Decodes a chunked body: chunks, each a hex size line followed by that many
+// bytes and a CRLF, then a zero-size chunk, any trailer fields and an empty
+// line. Everything before `pos` has already been decoded into `body` or
+// `trailers`.
+#[derive(Default)]
+struct ChunkedDecoder {
+    pos: usize,
+    body: Vec<u8>,
+    // Where the trailers start, once we've seen the last chunk.
+    trailers_start: Option<usize>,
+    trailers: Headers,
+}
+
+impl ChunkedDecoder {
+    // Decode whatever has arrived of the body in `buf`, returning how long
+    // the body is once all of it has.
+    fn decode(&mut self, buf: &[u8], limits: &RequestLimits) -> Result<Option<usize>, ParseError> {
+        let trailers_start = loop {
+            if let Some(start) = self.trailers_start {
+                break start;
+            }
+
+            let line_end = match find_within(&buf[self.pos..], b"\r\n", MAX_CHUNK_LINE) {
+                Some(i) => self.pos + i,
+                // Nothing but extensions could make a size line this long.
+                None if buf.len() - self.pos >= MAX_CHUNK_LINE => {
+                    return Err(ParseError::InvalidChunk)
+                }
+                None => return Ok(None),
+            };
+            let size = chunk_size(&buf[self.pos..line_end])?;
+            let start = line_end + 2;
+
+            if size == 0 {
+                self.pos = start;
+                self.trailers_start = Some(start);
+                continue;
+            }
+            if size > limits.max_body - self.body.len() {
+                return Err(ParseError::BodyTooLarge);
+            }
+
+            // The chunk's data and the CRLF after it. Until all of that is
+            // here, we'll look at this chunk's size line again next time.
+            let end = start.checked_add(size).ok_or(ParseError::InvalidChunk)?;
+            let end_crlf = end.checked_add(2).ok_or(ParseError::InvalidChunk)?;
+            if buf.len() < end_crlf {
+                return Ok(None);
+            }
+            if &buf[end..end_crlf] != b"\r\n" {
+                return Err(ParseError::InvalidChunk);
+            }
+
+            self.body.extend_from_slice(&buf[start..end]);
+            self.pos = end_crlf;
+        };
+
+        // Trailer fields look just like headers, and end the same way.
+        loop {
+            let line_end = match find(&buf[self.pos..], b"\r\n") {
+                Some(i) => self.pos + i,
+                None if buf.len() - trailers_start >= limits.max_head => {
+                    return Err(ParseError::HeadTooLarge)
+                }
+                None => return Ok(None),
+            };
+            if line_end + 2 - trailers_start > limits.max_head {
+                return Err(ParseError::HeadTooLarge);
+            }
+            let line = &buf[self.pos..line_end];
+            self.pos = line_end + 2;
+
+            if line.is_empty() {
+                return Ok(Some(self.pos));
+            }
+
+            let (name, value) = parse_header(line)?;
+            self.trailers.append(name, value);
+        }
+    }
+}
+
+// The longest size line we'll wait for the end of, extensions and all.
+const MAX_CHUNK_LINE: usize = 4096;
+
+// The size at the start of a chunk, ignoring any `;name=value` extensions.
+fn chunk_size(line: &[u8]) -> Result<usize, ParseError> {
+    let size = match line.iter().position(|&b| b == b';') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    let size = trim_ows(size);
+
+    if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+        return Err(ParseError::InvalidChunk);
+    }
+
+    let size = std::str::from_utf8(size).map_err(|_| ParseError::InvalidChunk)?;
+    usize::from_str_radix(size, 16).map_err(|_| ParseError::InvalidChunk)
+}
+
+// A token is the set of characters allowed in methods and header names.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn trim_ows(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = bytes {
+        bytes = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = bytes {
+        bytes = rest;
+    }
+    bytes
+}
+
+fn split_crlf(mut bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut done = false;
+
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        match find(bytes, b"\r\n") {
+            Some(i) => {
+                let line = &bytes[..i];
+                bytes = &bytes[i + 2..];
+                Some(line)
+            }
+            None => {
+                done = true;
+                Some(bytes)
+            }
+        }
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Like `find`, but only a match that ends within the first `limit` bytes
+// counts.
+fn find_within(haystack: &[u8], needle: &[u8], limit: usize) -> Option<usize> {
+    find(&haystack[..haystack.len().min(limit)], needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<(Request, usize)>, ParseError> {
+        Request::parse(raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_a_simple_get() {
+        let raw = "GET /hello?name=rust HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n";
+        let (request, used) = parse(raw).unwrap().unwrap();
+
+        assert_eq!(used, raw.len());
+        assert_eq!(request.method(), Method::Get);
+        assert_eq!(request.target(), "/hello?name=rust");
+        assert_eq!(request.path(), "/hello");
+        assert_eq!(request.query(), Some("name=rust"));
+        assert_eq!(request.version(), Version::Http11);
+        assert_eq!(request.header("accept"), Some("*/*"));
+        assert!(request.body().is_empty());
+    }
+
+    #[test]
+    fn reads_a_content_length_body() {
+        let raw = "POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloGET";
+        let (request, used) = parse(raw).unwrap().unwrap();
+
+        assert_eq!(request.body(), b"hello");
+        // The trailing bytes belong to the next request.
+        assert_eq!(&raw[used..], "GET");
+    }
+
+    #[test]
+    fn decodes_a_chunked_body_with_trailers() {
+        let raw = "POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5\r\nhello\r\n\
+                   7;ext=1\r\n, world\r\n\
+                   0\r\n\
+                   Checksum: abc\r\n\
+                   \r\n\
+                   GET";
+        let (request, used) = parse(raw).unwrap().unwrap();
+
+        assert_eq!(request.body(), b"hello, world");
+        assert_eq!(request.trailers().get("checksum"), Some("abc"));
+        assert!(!request.headers().contains("Checksum"));
+        assert_eq!(&raw[used..], "GET");
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_chunked_body() {
+        let head = "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        for body in ["", "5\r\nhel", "5\r\nhello\r\n", "5\r\nhello\r\n0\r\n"] {
+            let raw = format!("{}{}", head, body);
+            assert!(parse(&raw).unwrap().is_none(), "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn decodes_a_big_chunked_body_once_as_it_arrives() {
+        let mut raw =
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        let head_len = raw.len();
+        for i in 0..1024 {
+            raw.extend_from_slice(b"3e8\r\n");
+            raw.extend_from_slice(&[b'a' + (i % 26) as u8; 1000]);
+            raw.extend_from_slice(b"\r\n");
+        }
+        raw.extend_from_slice(b"0\r\n\r\n");
+
+        let mut parser = Parser::new(RequestLimits {
+            max_head: 8 * 1024,
+            max_body: 1024 * 1000,
+        });
+        let mut buf = Vec::new();
+        let mut pieces = raw.chunks(4096).peekable();
+
+        while let Some(piece) = pieces.next() {
+            buf.extend_from_slice(piece);
+            let parsed = parser.parse(&buf).unwrap();
+
+            if pieces.peek().is_some() {
+                assert!(parsed.is_none());
+
+                // Every chunk that has arrived in full is already decoded,
+                // so the next call only has to look at what's new.
+                let Some(Head {
+                    framing: Framing::Chunked(decoder),
+                    ..
+                }) = &parser.head
+                else {
+                    panic!("expected a chunked body in progress")
+                };
+                assert!(buf.len() - (head_len + decoder.pos) < 1007);
+                assert!(decoder.body.len().is_multiple_of(1000));
+            } else {
+                let (request, used) = parsed.unwrap();
+                assert_eq!(used, raw.len());
+                assert_eq!(request.body().len(), 1024 * 1000);
+                assert!(request.body()[..1000].iter().all(|&b| b == b'a'));
+                assert!(request.body()[1000 * 1023..].iter().all(|&b| b == b'j'));
+            }
+        }
+
+        // Ready for the next request on the connection.
+        assert!(parser.head.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_chunked_bodies() {
+        let chunked = |body: &str| {
+            parse(&format!(
+                "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                body
+            ))
+        };
+
+        assert!(matches!(chunked("zz\r\n"), Err(ParseError::InvalidChunk)));
+        assert!(matches!(
+            chunked("3\r\nhello\r\n"),
+            Err(ParseError::InvalidChunk)
+        ));
+        assert!(matches!(
+            chunked("ffffffffffffffffffff\r\n"),
+            Err(ParseError::InvalidChunk)
+        ));
+
+        // Sizes so big that the chunk, or the CRLF after it, would end past
+        // the last byte anything could address. The data starts after the
+        // 16 digits and the CRLF.
+        for past in 0..3 {
+            let size = usize::MAX - 18 - 1 + past;
+            assert!(
+                matches!(
+                    chunked(&format!("{:016x}\r\nabc", size)),
+                    Err(ParseError::InvalidChunk)
+                ),
+                "{:x}",
+                size
+            );
+        }
+
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+            Err(ParseError::UnsupportedTransferEncoding(_))
+        ));
+        assert!(matches!(
+            parse(
+                "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\
+                 Content-Length: 3\r\n\r\n0\r\n\r\n"
+            ),
+            Err(ParseError::ConflictingLength)
+        ));
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = RequestLimits {
+            max_head: 64,
+            max_body: 8,
+        };
+        let parse = |raw: &str| Request::parse_with_limits(raw.as_bytes(), &limits);
+
+        // Too much head is caught before the blank line arrives.
+        let long = format!("GET / HTTP/1.1\r\nHost: x\r\nX-Long: {}", "a".repeat(50));
+        assert!(matches!(parse(&long), Err(ParseError::HeadTooLarge)));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost: x\r\nX-Short: a"),
+            Ok(None)
+        ));
+
+        // A Content-Length that's too big is refused before the body comes.
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 9\r\n\r\n"),
+            Err(ParseError::BodyTooLarge)
+        ));
+        assert!(
+            parse("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 8\r\n\r\n12345678")
+                .unwrap()
+                .is_some()
+        );
+
+        // Chunked bodies are counted as they're decoded.
+        let chunked = "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(matches!(
+            parse(&format!("{}5\r\nhello\r\n4\r\n", chunked)),
+            Err(ParseError::BodyTooLarge)
+        ));
+        assert!(
+            parse(&format!("{}5\r\nhello\r\n3\r\nabc\r\n0\r\n\r\n", chunked))
+                .unwrap()
+                .is_some()
+        );
+        let trailer = format!("{}0\r\nX-Trailer: {}\r\n", chunked, "a".repeat(60));
+        assert!(matches!(parse(&trailer), Err(ParseError::HeadTooLarge)));
+        let extension = format!("{}1;{}", chunked, "a".repeat(MAX_CHUNK_LINE));
+        assert!(matches!(parse(&extension), Err(ParseError::InvalidChunk)));
+    }
+
+    #[test]
+    fn waits_for_more_input() {
+        assert!(parse("GET / HTTP/1.1\r\nHost: x\r\n").unwrap().is_none());
+        assert!(
+            parse("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nabc")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(matches!(
+            parse("GET /\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine)
+        ));
+        assert!(matches!(
+            parse("GET  / HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine)
+        ));
+        assert!(matches!(
+            parse("BREW / HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Err(ParseError::UnsupportedMethod(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/2.0\r\nHost: x\r\n\r\n"),
+            Err(ParseError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost : x\r\n\r\n"),
+            Err(ParseError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n"),
+            Err(ParseError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::MissingHost)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: -1\r\n\r\n"),
+            Err(ParseError::InvalidContentLength)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"),
+            Err(ParseError::InvalidContentLength)
+        ));
+    }
+
+    #[test]
+    fn http_10_does_not_need_host() {
+        let (request, _) = parse("GET / HTTP/1.0\r\n\r\n").unwrap().unwrap();
+
+        assert_eq!(request.version(), Version::Http10);
+    }
+
+    // A reader that hands out its data a few bytes at a time, like a slow
+    // network connection would.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(self.data.len()).min(buf.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_from_handles_many_small_reads() {
+        let mut reader = Trickle {
+            data: b"PUT /file HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabc",
+            step: 3,
+        };
+        let request = Request::read_from(&mut reader).unwrap();
+
+        assert_eq!(request.method(), Method::Put);
+        assert_eq!(request.body(), b"abc");
+    }
+
+    #[test]
+    fn read_from_reports_truncated_requests() {
+        let mut reader = Trickle {
+            data: b"GET / HTTP/1.1\r\nHost:",
+            step: 100,
+        };
+
+        assert!(matches!(
+            Request::read_from(&mut reader),
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+}
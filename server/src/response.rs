@@ -0,0 +1,488 @@
+use crate::connection::Upgrade;
+use crate::headers::Headers;
+use crate::request::Version;
+use std::fmt;
+use std::io::{self, Write};
+
+/// The status codes the server sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    ContentTooLarge,
+    RangeNotSatisfiable,
+    UpgradeRequired,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
+}
+
+impl StatusCode {
+    /// The numeric code, e.g. `404`.
+    pub fn code(&self) -> u16 {
+        match self {
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::NoContent => 204,
+            StatusCode::PartialContent => 206,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::Found => 302,
+            StatusCode::NotModified => 304,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::ContentTooLarge => 413,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::HttpVersionNotSupported => 505,
+        }
+    }
+
+    /// The reason phrase the spec suggests for this code, e.g. `Not Found`.
+    pub fn reason_phrase(&self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::ContentTooLarge => "Content Too Large",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    // 1xx, 204 and 304 responses never carry a body, so they mustn't
+    // advertise a Content-Length for one either.
+    fn allows_body(&self) -> bool {
+        let code = self.code();
+        !(code < 200 || code == 204 || code == 304)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason_phrase())
+    }
+}
+
+/// An HTTP response, built up with chained calls and then serialized onto
+/// the wire.
+///
+/// ```
+/// use server::{Response, StatusCode};
+///
+/// let response = Response::new(StatusCode::Ok)
+///     .with_header("Content-Type", "text/plain")
+///     .with_body("Hello!");
+///
+/// assert_eq!(
+///     response.to_bytes(),
+///     b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\nHello!"
+/// );
+/// ```
+///
+/// Bodies that are too big to hold in memory, or that aren't ready all at
+/// once, can be streamed instead. They're sent with
+/// `Transfer-Encoding: chunked`, a few kilobytes at a time as they're
+/// produced:
+///
+/// ```
+/// use server::{Response, StatusCode};
+///
+/// let response = Response::new(StatusCode::Ok)
+///     .with_chunks((1..=3).map(|n| format!("line {}\n", n)));
+///
+/// assert_eq!(
+///     response.to_bytes(),
+///     b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+///       15\r\nline 1\nline 2\nline 3\n\r\n0\r\n\r\n"
+/// );
+/// ```
+#[derive(Debug)]
+pub struct Response {
+    status: StatusCode,
+    version: Version,
+    headers: Headers,
+    body: Body,
+    // Takes over the connection once a 101 response has been sent.
+    upgrade: Option<Upgrade>,
+}
+
+// Writes a streamed body. It's only called once, when the response is sent.
+type Stream = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static>;
+
+enum Body {
+    Full(Vec<u8>),
+    Stream(Stream),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Full(bytes) => write!(f, "Full({} bytes)", bytes.len()),
+            Body::Stream(_) => write!(f, "Stream"),
+        }
+    }
+}
+
+// Streamed writes are gathered up to this size before going out as a chunk,
+// so a handler writing a line at a time doesn't send a chunk per line.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+impl Response {
+    /// Create an empty response with the given status.
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            version: Version::Http11,
+            headers: Headers::new(),
+            body: Body::Full(Vec::new()),
+            upgrade: None,
+        }
+    }
+
+    /// Add a header, keeping any existing headers with the same name.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Replace the body. `Content-Length` is worked out when the response is
+    /// serialized, so it never needs to be set by hand.
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Full(body.into());
+        self
+    }
+
+    /// Stream the body from an iterator, sending each item as it's produced.
+    pub fn with_chunks<I>(self, chunks: I) -> Response
+    where
+        I: IntoIterator + Send + 'static,
+        I::Item: AsRef<[u8]>,
+    {
+        self.with_stream(move |writer| {
+            for chunk in chunks {
+                writer.write_all(chunk.as_ref())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Stream the body by writing it to the connection. `write` runs when
+    /// the response is sent, after the head has gone out.
+    pub fn with_stream<F>(mut self, write: F) -> Response
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        self.body = Body::Stream(Box::new(write));
+        self
+    }
+
+    /// Hand the connection to `upgrade` once this response has been sent,
+    /// if it's a `101 Switching Protocols`. See
+    /// [`WebSocket::upgrade`](crate::WebSocket::upgrade).
+    pub(crate) fn with_upgrade(mut self, upgrade: Upgrade) -> Response {
+        self.upgrade = Some(upgrade);
+        self
+    }
+
+    pub(crate) fn take_upgrade(&mut self) -> Option<Upgrade> {
+        match self.status {
+            StatusCode::SwitchingProtocols => self.upgrade.take(),
+            _ => None,
+        }
+    }
+
+    /// Set the protocol version written in the status line.
+    pub fn with_version(mut self, version: Version) -> Response {
+        self.version = version;
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// The body, or nothing if it's streamed.
+    pub fn body(&self) -> &[u8] {
+        match &self.body {
+            Body::Full(bytes) => bytes,
+            Body::Stream(_) => &[],
+        }
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        matches!(self.body, Body::Stream(_))
+    }
+
+    /// Run a streamed body to completion and keep the result, for clients
+    /// that can't take a chunked response.
+    pub fn buffer(&mut self) -> io::Result<()> {
+        if let Body::Stream(_) = self.body {
+            let stream = match std::mem::replace(&mut self.body, Body::Full(Vec::new())) {
+                Body::Stream(stream) => stream,
+                Body::Full(_) => unreachable!(),
+            };
+
+            let mut bytes = Vec::new();
+            stream(&mut bytes)?;
+            self.body = Body::Full(bytes);
+        }
+        Ok(())
+    }
+
+    /// Write the status line, headers and body to `writer`.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        self.write_head_to(writer)?;
+        self.write_body_to(writer).map(|_| ())
+    }
+
+    /// Write only the status line and headers. This is what a `HEAD` request
+    /// gets: the same `Content-Length` as the full response, but no body.
+    pub fn write_head_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        // Status-Line = HTTP-Version SP Status-Code SP Reason-Phrase CRLF
+        write!(writer, "{} {}\r\n", self.version, self.status)?;
+
+        for (name, value) in self.headers.iter() {
+            // We always frame the body ourselves, so a stale value set by a
+            // handler can't disagree with the body we actually send.
+            if name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                continue;
+            }
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+
+        if self.status.allows_body() {
+            match &self.body {
+                Body::Full(bytes) => write!(writer, "Content-Length: {}\r\n", bytes.len())?,
+                Body::Stream(_) => writer.write_all(b"Transfer-Encoding: chunked\r\n")?,
+            }
+        }
+
+        // An empty line separates the headers from the body.
+        writer.write_all(b"\r\n")
+    }
+
+    /// Write the body that goes after [`write_head_to`](Response::write_head_to),
+    /// returning its length, not counting any chunked framing.
+    pub(crate) fn write_body_to<W: Write>(self, writer: &mut W) -> io::Result<usize> {
+        if !self.status.allows_body() {
+            return Ok(0);
+        }
+
+        match self.body {
+            Body::Full(bytes) => {
+                writer.write_all(&bytes)?;
+                Ok(bytes.len())
+            }
+            Body::Stream(stream) => {
+                let mut chunked = ChunkedWriter {
+                    inner: &mut *writer,
+                    written: 0,
+                };
+
+                // Errors from the handler's own writing come back out of
+                // here, leaving the body unfinished. Without the last chunk
+                // the client can tell it didn't get everything.
+                let mut buffered = io::BufWriter::with_capacity(CHUNK_SIZE, &mut chunked);
+                stream(&mut buffered)?;
+                buffered.flush()?;
+                drop(buffered);
+
+                let written = chunked.written;
+                writer.write_all(b"0\r\n\r\n")?;
+                Ok(written)
+            }
+        }
+    }
+
+    /// Serialize the whole response into a byte vector.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(128 + self.body().len());
+
+        // Writing into a Vec can't fail, though a streamed body's own code
+        // might.
+        self.write_to(&mut bytes).unwrap();
+
+        bytes
+    }
+}
+
+// Frames everything written through it as one chunk per write.
+struct ChunkedWriter<W> {
+    inner: W,
+    written: usize,
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would mark the end of the body.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+
+        self.written += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_without_stray_whitespace() {
+        let response = Response::new(StatusCode::NotFound).with_body("nope");
+
+        assert_eq!(
+            response.to_bytes(),
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope".to_vec()
+        );
+    }
+
+    #[test]
+    fn content_length_always_matches_the_body() {
+        let response = Response::new(StatusCode::Ok)
+            .with_header("content-length", "999")
+            .with_body(vec![0u8; 3]);
+        let bytes = response.to_bytes();
+        let text = String::from_utf8_lossy(&bytes);
+
+        assert!(text.contains("Content-Length: 3\r\n"));
+        assert!(!text.contains("999"));
+    }
+
+    #[test]
+    fn bodiless_statuses_have_no_length() {
+        let response = Response::new(StatusCode::NoContent).with_body("ignored");
+
+        assert_eq!(
+            response.to_bytes(),
+            b"HTTP/1.1 204 No Content\r\n\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn keeps_repeated_headers() {
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Set-Cookie", "a=1")
+            .with_header("Set-Cookie", "b=2")
+            .with_version(Version::Http10);
+        let bytes = response.to_bytes();
+
+        assert_eq!(
+            bytes,
+            b"HTTP/1.0 200 OK\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Length: 0\r\n\r\n"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn streamed_writes_are_gathered_into_chunks() {
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Content-Length", "5")
+            .with_stream(|writer| {
+                for word in ["a", "few", "small", "writes"] {
+                    write!(writer, "{} ", word)?;
+                }
+                Ok(())
+            });
+        let bytes = response.to_bytes();
+
+        assert_eq!(
+            bytes,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              13\r\na few small writes \r\n0\r\n\r\n"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn large_streams_are_sent_in_several_chunks() {
+        let response =
+            Response::new(StatusCode::Ok).with_chunks(std::iter::repeat_n(vec![b'x'; 1000], 20));
+        let mut bytes = Vec::new();
+        response.write_head_to(&mut bytes).unwrap();
+        let written = response.write_body_to(&mut bytes).unwrap();
+
+        let text = String::from_utf8(bytes).unwrap();
+        assert_eq!(written, 20_000);
+        // 1000-byte writes fill up 8000 bytes at a time before each chunk.
+        assert_eq!(text.matches("\r\n1f40\r\n").count(), 2);
+        assert_eq!(text.matches("\r\nfa0\r\n").count(), 1);
+        assert!(text.ends_with("\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn buffering_a_stream_gives_it_a_length() {
+        let mut response = Response::new(StatusCode::Ok).with_chunks(vec!["ab", "cd"]);
+        assert!(response.is_streamed());
+
+        response.buffer().unwrap();
+
+        assert!(!response.is_streamed());
+        assert_eq!(response.body(), b"abcd");
+        assert!(String::from_utf8(response.to_bytes())
+            .unwrap()
+            .contains("Content-Length: 4\r\n"));
+    }
+}
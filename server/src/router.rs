@@ -0,0 +1,512 @@
+use crate::middleware::{Middleware, Next};
+use crate::request::{Method, Request};
+use crate::response::{Response, StatusCode};
+use std::cell::Cell;
+
+/// Something that can turn a request into a response.
+///
+/// Any closure of the form `Fn(&Request, &Params) -> Response` is a handler,
+/// so most routes can be written inline. Handlers are shared between all the
+/// worker threads, which is why they need to be `Send + Sync`.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request, params: &Params) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        self(request, params)
+    }
+}
+
+/// The values captured from the request path by `:name` and `*name`
+/// segments of a route pattern.
+///
+/// Values are taken from the path as-is, so they are still percent-encoded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    entries: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    // Must match the path segment exactly.
+    Literal(String),
+    // `:name` matches any single, non-empty segment.
+    Param(String),
+    // `*name` matches everything that's left, slashes included.
+    Wildcard(String),
+}
+
+struct Route {
+    method: Method,
+    // As registered, for labelling metrics and logs.
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Box<dyn Handler>,
+    cache_control: Option<String>,
+}
+
+impl Route {
+    fn call(&self, request: &Request, params: &Params) -> Response {
+        let mut response = self.handler.handle(request, params);
+
+        if let Some(policy) = &self.cache_control {
+            // Errors shouldn't be cached as though they were the real thing,
+            // and a handler that picked its own policy knows best.
+            let code = response.status().code();
+            let cacheable = (200..300).contains(&code) || code == 304;
+            if cacheable && !response.headers().contains("Cache-Control") {
+                response
+                    .headers_mut()
+                    .insert("Cache-Control", policy.as_str());
+            }
+        }
+
+        response
+    }
+}
+
+/// Dispatches requests to handlers based on their method and path.
+///
+/// ```
+/// use server::{Params, Request, Response, Router, StatusCode};
+///
+/// let router = Router::new().get("/users/:id", |_: &Request, params: &Params| {
+///     Response::new(StatusCode::Ok).with_body(format!("user {}", params.get("id").unwrap()))
+/// });
+///
+/// let (request, _) = Request::parse(b"GET /users/42 HTTP/1.1\r\nHost: x\r\n\r\n")
+///     .unwrap()
+///     .unwrap();
+///
+/// assert_eq!(router.handle(&request).body(), b"user 42");
+/// ```
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Box<dyn Handler>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    /// Create a router with no routes. Every request gets a 404 until routes
+    /// are added.
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_: &Request, _: &Params| Response::new(StatusCode::NotFound)),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Register `handler` for requests with the given method whose path
+    /// matches `pattern`.
+    ///
+    /// Patterns are made of `/`-separated segments. A segment starting with
+    /// `:` captures one path segment, and a final segment starting with `*`
+    /// captures the rest of the path. When several routes match, the one
+    /// registered first wins.
+    ///
+    /// # Panics
+    ///
+    /// The `route` function will panic if the pattern doesn't start with `/`,
+    /// has an unnamed `:` or `*` segment, or has a `*` segment that isn't last.
+    pub fn route<H: Handler>(mut self, method: Method, pattern: &str, handler: H) -> Router {
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+            cache_control: None,
+        });
+        self
+    }
+
+    /// Send `Cache-Control: policy` with the successful and 304 responses of
+    /// the route registered last, unless its handler sets the header itself.
+    ///
+    /// ```
+    /// use server::{Router, StaticFiles};
+    ///
+    /// let router = Router::new()
+    ///     .get("/assets/*path", StaticFiles::new("public"))
+    ///     .cache_control("public, max-age=86400");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// The `cache_control` function will panic if no routes have been added.
+    pub fn cache_control(mut self, policy: impl Into<String>) -> Router {
+        let route = self
+            .routes
+            .last_mut()
+            .expect("cache_control must come after the route it applies to");
+        route.cache_control = Some(policy.into());
+        self
+    }
+
+    pub fn get<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Use `handler` for requests that don't match any route, instead of an
+    /// empty 404.
+    pub fn not_found<H: Handler>(mut self, handler: H) -> Router {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Run `middleware` around every request, including ones that don't
+    /// match a route. Middleware runs in the order it was added: the first
+    /// sees each request first and each response last.
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Router {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Find the route for `request` and run its handler, inside any
+    /// middleware. Middleware can change the request, so it's given a copy.
+    ///
+    /// `HEAD` requests without a `HEAD` route of their own are sent to the
+    /// matching `GET` route; the connection leaves the body off when it writes
+    /// the response.
+    ///
+    /// If the path matches a route but none of them accept the request's
+    /// method, the response is a 405 listing the methods that would have
+    /// worked in its `Allow` header.
+    pub fn handle(&self, request: &Request) -> Response {
+        self.dispatch(&mut request.clone()).0
+    }
+
+    /// Like [`handle`](Router::handle), but works on the request in place,
+    /// and also returns the pattern of the route that answered, if one did.
+    pub(crate) fn dispatch(&self, request: &mut Request) -> (Response, Option<&str>) {
+        let route = Cell::new(None);
+        let endpoint = |request: &Request| {
+            let (response, matched) = self.call_route(request);
+            route.set(matched);
+            response
+        };
+
+        let response = Next::new(&self.middleware, &endpoint).run(request);
+        (response, route.get())
+    }
+
+    fn call_route(&self, request: &Request) -> (Response, Option<&str>) {
+        let mut allowed: Vec<Method> = Vec::new();
+        let mut get = None;
+
+        for route in &self.routes {
+            let params = match match_path(&route.segments, request.path()) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            if route.method == request.method() {
+                return (route.call(request, &params), Some(&route.pattern));
+            }
+
+            if route.method == Method::Get && get.is_none() {
+                get = Some((route, params));
+            }
+
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
+        }
+
+        if request.method() == Method::Head {
+            if let Some((route, params)) = get {
+                return (route.call(request, &params), Some(&route.pattern));
+            }
+        }
+
+        if allowed.contains(&Method::Get) && !allowed.contains(&Method::Head) {
+            allowed.push(Method::Head);
+        }
+
+        if allowed.is_empty() {
+            return (self.not_found.handle(request, &Params::default()), None);
+        }
+
+        let allow = allowed
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let response = Response::new(StatusCode::MethodNotAllowed).with_header("Allow", allow);
+        (response, None)
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(
+        pattern.starts_with('/'),
+        "route pattern {:?} must start with '/'",
+        pattern
+    );
+
+    let parts: Vec<&str> = pattern[1..].split('/').collect();
+    let last = parts.len() - 1;
+
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "unnamed parameter in {:?}", pattern);
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(!name.is_empty(), "unnamed wildcard in {:?}", pattern);
+                assert!(i == last, "wildcard must be last in {:?}", pattern);
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(part.to_string())
+            }
+        })
+        .collect()
+}
+
+fn match_path(segments: &[Segment], path: &str) -> Option<Params> {
+    let path = path.strip_prefix('/')?;
+    let mut rest = Some(path);
+    let mut params = Params::default();
+
+    for segment in segments {
+        // Once we've run out of path, only a wildcard (matching nothing) can
+        // still succeed.
+        let remaining = match rest {
+            Some(remaining) => remaining,
+            None => match segment {
+                Segment::Wildcard(name) => {
+                    params.entries.push((name.clone(), String::new()));
+                    continue;
+                }
+                _ => return None,
+            },
+        };
+
+        let (part, next) = match remaining.find('/') {
+            Some(i) => (&remaining[..i], Some(&remaining[i + 1..])),
+            None => (remaining, None),
+        };
+
+        match segment {
+            Segment::Literal(literal) if literal == part => {}
+            Segment::Param(name) if !part.is_empty() => {
+                params.entries.push((name.clone(), part.to_string()));
+            }
+            Segment::Wildcard(name) => {
+                params.entries.push((name.clone(), remaining.to_string()));
+                return Some(params);
+            }
+            _ => return None,
+        }
+
+        rest = next;
+    }
+
+    // Every segment matched, but the path might still have more to it.
+    match rest {
+        None => Some(params),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: x\r\n\r\n", method, target);
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    // A handler that echoes the captured parameters back in the body.
+    fn echo(_: &Request, params: &Params) -> Response {
+        let body = params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        Response::new(StatusCode::Ok).with_body(body)
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body()).unwrap()
+    }
+
+    #[test]
+    fn matches_literal_paths() {
+        let router = Router::new().get("/", echo).get("/about", echo);
+
+        assert_eq!(router.handle(&request("GET", "/")).status(), StatusCode::Ok);
+        assert_eq!(
+            router.handle(&request("GET", "/about?x=1")).status(),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            router.handle(&request("GET", "/about/more")).status(),
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            router.handle(&request("GET", "/nope")).status(),
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn captures_params() {
+        let router = Router::new().get("/users/:id/posts/:post", echo);
+        let response = router.handle(&request("GET", "/users/7/posts/hello"));
+
+        assert_eq!(body(&response), "id=7&post=hello");
+        assert_eq!(
+            router
+                .handle(&request("GET", "/users//posts/hello"))
+                .status(),
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn wildcards_capture_the_rest() {
+        let router = Router::new().get("/static/*rest", echo);
+
+        assert_eq!(
+            body(&router.handle(&request("GET", "/static/css/site.css"))),
+            "rest=css/site.css"
+        );
+        assert_eq!(body(&router.handle(&request("GET", "/static/"))), "rest=");
+        assert_eq!(body(&router.handle(&request("GET", "/static"))), "rest=");
+    }
+
+    #[test]
+    fn first_registered_route_wins() {
+        let router = Router::new()
+            .get("/users/me", |_: &Request, _: &Params| {
+                Response::new(StatusCode::Ok).with_body("me")
+            })
+            .get("/users/:id", echo);
+
+        assert_eq!(body(&router.handle(&request("GET", "/users/me"))), "me");
+        assert_eq!(body(&router.handle(&request("GET", "/users/3"))), "id=3");
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow() {
+        let router = Router::new()
+            .get("/items/:id", echo)
+            .put("/items/:id", echo)
+            .delete("/items/:id", echo);
+        let response = router.handle(&request("POST", "/items/1"));
+
+        assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(
+            response.headers().get("Allow"),
+            Some("GET, PUT, DELETE, HEAD")
+        );
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = Router::new().get("/users/:id", echo);
+        let response = router.handle(&request("HEAD", "/users/5"));
+
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(&response), "id=5");
+    }
+
+    #[test]
+    fn dispatch_names_the_route_that_answered() {
+        let router = Router::new().get("/users/:id", echo).get("/*path", echo);
+        let route = |method, path| router.dispatch(&mut request(method, path)).1;
+
+        assert_eq!(route("GET", "/users/5"), Some("/users/:id"));
+        assert_eq!(route("HEAD", "/users/5"), Some("/users/:id"));
+        assert_eq!(route("GET", "/other"), Some("/*path"));
+        assert_eq!(route("POST", "/other"), None);
+    }
+
+    #[test]
+    fn cache_control_applies_to_its_own_route() {
+        let router = Router::new()
+            .get("/cached/:id", echo)
+            .cache_control("max-age=60")
+            .get("/missing", |_: &Request, _: &Params| {
+                Response::new(StatusCode::NotFound)
+            })
+            .cache_control("max-age=60")
+            .get("/own", |_: &Request, _: &Params| {
+                Response::new(StatusCode::Ok).with_header("Cache-Control", "no-store")
+            })
+            .cache_control("max-age=60")
+            .get("/plain", echo);
+        let cache_control = |path| {
+            router
+                .handle(&request("GET", path))
+                .headers()
+                .get("Cache-Control")
+                .map(String::from)
+        };
+
+        assert_eq!(cache_control("/cached/1").as_deref(), Some("max-age=60"));
+        assert_eq!(cache_control("/missing"), None);
+        assert_eq!(cache_control("/own").as_deref(), Some("no-store"));
+        assert_eq!(cache_control("/plain"), None);
+    }
+
+    #[test]
+    fn custom_not_found() {
+        let router = Router::new().not_found(|_: &Request, _: &Params| {
+            Response::new(StatusCode::NotFound).with_body("missing")
+        });
+
+        assert_eq!(body(&router.handle(&request("GET", "/x"))), "missing");
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be last")]
+    fn wildcard_must_be_last() {
+        Router::new().get("/a/*rest/b", echo);
+    }
+}
@@ -0,0 +1,507 @@
+use crate::connection::{handle_connection, ConnectionConfig};
+use crate::logging::{Event, Logger};
+#[cfg(target_os = "linux")]
+use crate::reactor;
+use crate::response::{Response, StatusCode};
+use crate::router::Router;
+use crate::shutdown::ShutdownHandle;
+#[cfg(feature = "tls")]
+use crate::tls::{Acceptor, TlsConfig};
+use crate::{ShutdownReport, ThreadPool};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Accepts connections and hands each one to the thread pool, until asked to
+/// shut down.
+pub struct Server {
+    listener: TcpListener,
+    pool: ThreadPool,
+    router: Arc<Router>,
+    config: Arc<ConnectionConfig>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<Acceptor>,
+    #[cfg(target_os = "linux")]
+    event_loop: bool,
+}
+
+impl Server {
+    /// Bind to `addr`, ready to serve `router` on `pool`.
+    pub fn bind<A: ToSocketAddrs>(addr: A, pool: ThreadPool, router: Router) -> io::Result<Server> {
+        let listener = TcpListener::bind(addr)?;
+        let shutdown = ShutdownHandle::new();
+        shutdown.set_wake_addr(listener.local_addr()?);
+
+        Ok(Server {
+            listener,
+            pool,
+            router: Arc::new(router),
+            config: Arc::new(ConnectionConfig::default()),
+            shutdown,
+            shutdown_timeout: Duration::from_secs(30),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(target_os = "linux")]
+            event_loop: false,
+        })
+    }
+
+    pub fn with_connection_config(mut self, config: ConnectionConfig) -> Server {
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// Where to send access logs and connection errors. This replaces the
+    /// logger in the connection config; the pool has its own, set in its
+    /// [`PoolConfig`](crate::PoolConfig).
+    pub fn with_logger(mut self, logger: Logger) -> Server {
+        Arc::make_mut(&mut self.config).logger = logger;
+        self
+    }
+
+    /// How long in-flight jobs get to finish once shutdown starts. Defaults
+    /// to 30 seconds.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Server {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Speak TLS on every connection, offering the certificates in `tls`.
+    ///
+    /// Fails with `InvalidInput` if the server is already set up to use
+    /// [the event loop](Server::with_event_loop), which can't serve TLS.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> io::Result<Server> {
+        #[cfg(target_os = "linux")]
+        if self.event_loop {
+            return Err(no_tls_on_the_event_loop());
+        }
+
+        self.tls = Some(tls.acceptor());
+        Ok(self)
+    }
+
+    /// Serve every connection from one thread with epoll, and only hand
+    /// requests to the pool once they've fully arrived. Clients that are
+    /// slow to send, or idle between requests, then don't tie up a worker
+    /// each.
+    ///
+    /// The event loop never waits on the pool: when the queue is full, the
+    /// request gets a 503 whatever the pool's
+    /// [`OverflowPolicy`](crate::OverflowPolicy) says, since waiting for
+    /// room or running the handler there would stall every connection.
+    ///
+    /// Responses are written from the event loop too, so a streamed body is
+    /// read into memory first. This can't be combined with TLS, and fails
+    /// with `InvalidInput` if `with_tls` was called first.
+    #[cfg(target_os = "linux")]
+    pub fn with_event_loop(mut self) -> io::Result<Server> {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return Err(no_tls_on_the_event_loop());
+        }
+
+        self.event_loop = true;
+        Ok(self)
+    }
+
+    /// The address the server is listening on. Handy when binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// A handle that can stop this server from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serve connections until shutdown is requested, then wait for the pool
+    /// to finish its jobs, and any WebSockets to close, and report on how
+    /// that went.
+    pub fn run(self) -> ShutdownReport {
+        let Server {
+            listener,
+            pool,
+            router,
+            config,
+            shutdown,
+            shutdown_timeout,
+            #[cfg(feature = "tls")]
+            tls,
+            #[cfg(target_os = "linux")]
+            event_loop,
+        } = self;
+
+        let logger = config.logger.clone();
+
+        #[cfg(target_os = "linux")]
+        {
+            if event_loop {
+                let remaining =
+                    reactor::run(listener, &pool, router, config, &shutdown, shutdown_timeout)
+                        .unwrap_or_else(|e| {
+                            logger.log(&Event::Error {
+                                context: "running the event loop",
+                                error: &e,
+                            });
+                            logger.log(&Event::ServerStopping);
+                            shutdown_timeout
+                        });
+                return drain(pool, &shutdown, remaining);
+            }
+        }
+
+        // The incoming method returns an iterator of TcpStreams. A single
+        // stream is a connection between client and server.
+        for stream in listener.incoming() {
+            // This also catches the connection ShutdownHandle makes to wake
+            // us up, which is dropped without being served.
+            if shutdown.is_shutdown() {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    logger.log(&Event::Error {
+                        context: "accepting a connection",
+                        error: &e,
+                    });
+                    continue;
+                }
+            };
+
+            let router = Arc::clone(&router);
+            let config = Arc::clone(&config);
+            let shutdown = shutdown.clone();
+            #[cfg(feature = "tls")]
+            let tls = tls.clone();
+            #[cfg(feature = "tls")]
+            let pending = PendingConnection {
+                stream: Some(stream),
+                // A TLS client can't read a 503 we haven't shaken hands
+                // over, so it's simply hung up on.
+                answer: tls.is_none(),
+            };
+            #[cfg(not(feature = "tls"))]
+            let pending = PendingConnection {
+                stream: Some(stream),
+                answer: true,
+            };
+
+            // Each job serves every request the client sends on its
+            // connection, so browsers can reuse one connection for a page
+            // and its assets.
+            let result = pool.execute(move || {
+                let stream = pending.take();
+                #[cfg(feature = "tls")]
+                let result = match tls {
+                    Some(tls) => tls
+                        .accept(stream)
+                        .and_then(|stream| handle_connection(stream, &router, &config, &shutdown)),
+                    None => handle_connection(stream, &router, &config, &shutdown),
+                };
+                #[cfg(not(feature = "tls"))]
+                let result = handle_connection(stream, &router, &config, &shutdown);
+
+                if let Err(e) = result {
+                    config.logger.log(&Event::Error {
+                        context: "serving a connection",
+                        error: &e,
+                    });
+                }
+            });
+
+            // The job owns the connection, so if the pool turns it away the
+            // client has already been told to come back later.
+            if let Err(e) = result {
+                logger.log(&Event::Error {
+                    context: "handing off a connection",
+                    error: &e,
+                });
+            }
+        }
+
+        // Close the listening socket first so new clients are refused rather
+        // than left waiting while we drain.
+        drop(listener);
+
+        logger.log(&Event::ServerStopping);
+        drain(pool, &shutdown, shutdown_timeout)
+    }
+}
+
+// Give the pool's jobs and then the upgraded connections, which have threads
+// of their own, until `timeout` between them to finish.
+fn drain(pool: ThreadPool, shutdown: &ShutdownHandle, timeout: Duration) -> ShutdownReport {
+    let deadline = Instant::now() + timeout;
+    let mut report = pool.shutdown_timeout(timeout);
+    report.upgraded_timed_out =
+        shutdown.wait_for_upgraded(deadline.saturating_duration_since(Instant::now()));
+    report
+}
+
+#[cfg(all(feature = "tls", target_os = "linux"))]
+fn no_tls_on_the_event_loop() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "the event loop can't serve TLS",
+    )
+}
+
+// A connection waiting for a worker. If the pool never runs the job holding
+// it, because the queue was full or the job was dropped to make room for a
+// newer one, the client gets a 503 instead of being hung up on.
+struct PendingConnection {
+    stream: Option<TcpStream>,
+    // Whether the client can be sent a 503.
+    answer: bool,
+}
+
+impl PendingConnection {
+    fn take(mut self) -> TcpStream {
+        self.stream.take().expect("connection already taken")
+    }
+}
+
+impl Drop for PendingConnection {
+    fn drop(&mut self) {
+        if let Some(mut stream) = self.stream.take().filter(|_| self.answer) {
+            // The response is small enough to land in the socket's send
+            // buffer, so this won't hold up whoever is dropping us.
+            let _ = Response::new(StatusCode::ServiceUnavailable)
+                .with_header("Retry-After", "1")
+                .with_header("Connection", "close")
+                .write_to(&mut stream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::{OverflowPolicy, PoolConfig};
+    use crate::request::Request;
+    use crate::router::Params;
+    use crate::websocket::WebSocket;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_: &Request, _: &Params| {
+                Response::new(StatusCode::Ok).with_body("hi")
+            })
+            .get("/slow/:ms", |_: &Request, params: &Params| {
+                let ms = params.get("ms").unwrap().parse().unwrap();
+                thread::sleep(Duration::from_millis(ms));
+                Response::new(StatusCode::Ok)
+            })
+    }
+
+    #[test]
+    fn stops_when_asked_and_drains_the_pool() {
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(2), router()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        assert!(get(addr, "/").ends_with("hi"));
+
+        handle.shutdown();
+        let report = running.join().unwrap();
+
+        assert!(report.is_clean());
+        assert_eq!(report.stopped, vec![0, 1]);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn closes_idle_keep_alive_connections_on_shutdown() {
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(1), router())
+            .unwrap()
+            .with_connection_config(ConnectionConfig {
+                idle_timeout: Duration::from_secs(60),
+                ..ConnectionConfig::default()
+            });
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        // Leave a keep-alive connection open and idle.
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let mut buf = [0; 1024];
+        assert!(client.read(&mut buf).unwrap() > 0);
+
+        handle.shutdown();
+        let report = running.join().unwrap();
+
+        // The worker noticed the shutdown well before the 60 second idle
+        // timeout would have closed the connection.
+        assert!(report.is_clean());
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn reports_workers_that_miss_the_deadline() {
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(1), router())
+            .unwrap()
+            .with_shutdown_timeout(Duration::from_millis(50));
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let client = thread::spawn(move || get(addr, "/slow/1000"));
+        // Give the request time to reach the worker.
+        thread::sleep(Duration::from_millis(200));
+
+        handle.shutdown();
+        let report = running.join().unwrap();
+
+        assert_eq!(report.timed_out, vec![0]);
+        assert!(!report.is_clean());
+
+        // The job is still allowed to finish in the background.
+        assert!(client.join().unwrap().starts_with("HTTP/1.1 200 OK"));
+    }
+
+    // Open a WebSocket to `path`, returning once the handshake is done.
+    fn websocket(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path
+        )
+        .unwrap();
+
+        let mut head = Vec::new();
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        stream
+    }
+
+    #[test]
+    fn waits_for_websockets_to_say_goodbye() {
+        let closed = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&closed);
+        let router = Router::new().get("/ws", move |request: &Request, _: &Params| {
+            let flag = Arc::clone(&flag);
+            WebSocket::upgrade(request, move |mut socket| {
+                while socket.recv()?.is_some() {}
+                // Take a while to tidy up, so run has to wait for it.
+                thread::sleep(Duration::from_millis(200));
+                flag.store(true, Ordering::SeqCst);
+                Ok(())
+            })
+        });
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(1), router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let mut client = websocket(addr, "/ws");
+        handle.shutdown();
+        let report = running.join().unwrap();
+
+        assert!(report.is_clean());
+        assert!(closed.load(Ordering::SeqCst));
+
+        // The goodbye made it out before run returned: a close frame whose
+        // payload starts with 1001, going away.
+        let mut frame = [0; 4];
+        client.read_exact(&mut frame).unwrap();
+        assert_eq!(frame[0], 0x88);
+        assert_eq!(&frame[2..], 1001u16.to_be_bytes());
+    }
+
+    #[test]
+    fn reports_websockets_that_miss_the_deadline() {
+        let router = Router::new().get("/ws", |request: &Request, _: &Params| {
+            // Ignores the shutdown entirely.
+            WebSocket::upgrade(request, |_| {
+                thread::sleep(Duration::from_millis(1000));
+                Ok(())
+            })
+        });
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(1), router)
+            .unwrap()
+            .with_shutdown_timeout(Duration::from_millis(50));
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let _client = websocket(addr, "/ws");
+        handle.shutdown();
+        let report = running.join().unwrap();
+
+        assert!(report.timed_out.is_empty());
+        assert_eq!(report.upgraded_timed_out, 1);
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn answers_503_when_the_pool_is_full() {
+        let pool = ThreadPool::with_config(PoolConfig {
+            queue_capacity: Some(1),
+            overflow: OverflowPolicy::Reject,
+            ..PoolConfig::new(1)
+        })
+        .unwrap();
+        let server = Server::bind("127.0.0.1:0", pool, router()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        // Keep the only worker busy, then take the only place in the queue.
+        let busy = thread::spawn(move || get(addr, "/slow/300"));
+        thread::sleep(Duration::from_millis(100));
+        let queued = thread::spawn(move || get(addr, "/"));
+        thread::sleep(Duration::from_millis(50));
+
+        // The 503 is sent as soon as the connection is turned away, so read
+        // it without sending a request that would race with the close.
+        let mut response = String::new();
+        let mut rejected = TcpStream::connect(addr).unwrap();
+        rejected.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 1\r\n"));
+
+        // The connections that made it in are still served.
+        assert!(busy.join().unwrap().starts_with("HTTP/1.1 200 OK"));
+        assert!(queued.join().unwrap().ends_with("hi"));
+
+        handle.shutdown();
+        assert!(running.join().unwrap().is_clean());
+    }
+}
@@ -0,0 +1,170 @@
+#[cfg(unix)]
+use crate::logging::{Event, Logger};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// A cloneable handle used to ask a running [`Server`](crate::Server) to
+/// shut down.
+///
+/// Calling [`shutdown`](ShutdownHandle::shutdown) stops the server accepting
+/// new connections, tells keep-alive connections to close after their current
+/// request and WebSockets to say goodbye, and lets `Server::run` return once
+/// the pool and the WebSockets are done. This is what the signal handlers
+/// use, and it works just as well from tests.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    requested: AtomicBool,
+    // The address the server is listening on. Accepting a connection is a
+    // blocking call, so the only way to interrupt it is to connect to it.
+    wake_addr: Mutex<Option<SocketAddr>>,
+    // How many upgraded connections, like WebSockets, are still being served
+    // on threads of their own. Server::run waits for these along with the
+    // pool.
+    upgraded: Mutex<usize>,
+    upgraded_closed: Condvar,
+}
+
+/// Counts an upgraded connection as open until it's dropped.
+pub(crate) struct UpgradedGuard(ShutdownHandle);
+
+impl Drop for UpgradedGuard {
+    fn drop(&mut self) {
+        let mut open = self.0.inner.upgraded.lock().unwrap();
+        *open -= 1;
+        self.0.inner.upgraded_closed.notify_all();
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    /// Ask the server to shut down. Calling this more than once is harmless.
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+
+        let addr = *self.inner.wake_addr.lock().unwrap();
+        if let Some(addr) = addr {
+            // The connection itself is thrown away; all that matters is that
+            // the accept loop wakes up and notices the flag.
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Count an upgraded connection as open, for as long as the returned
+    /// guard lives.
+    pub(crate) fn track_upgraded(&self) -> UpgradedGuard {
+        *self.inner.upgraded.lock().unwrap() += 1;
+        UpgradedGuard(self.clone())
+    }
+
+    /// Wait up to `timeout` for every upgraded connection to close, returning
+    /// how many are still open.
+    pub(crate) fn wait_for_upgraded(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut open = self.inner.upgraded.lock().unwrap();
+
+        while *open > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            open = self
+                .inner
+                .upgraded_closed
+                .wait_timeout(open, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        *open
+    }
+
+    pub(crate) fn set_wake_addr(&self, mut addr: SocketAddr) {
+        // We can't connect to 0.0.0.0, but the loopback address reaches the
+        // same listener.
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+
+        *self.inner.wake_addr.lock().unwrap() = Some(addr);
+    }
+}
+
+/// Shut down through `handle` when the process receives SIGINT or SIGTERM,
+/// telling `logger` about each signal.
+///
+/// A second signal exits the process straight away, for when waiting on the
+/// in-flight jobs is taking too long.
+#[cfg(unix)]
+pub fn shutdown_on_signals(handle: &ShutdownHandle, logger: Logger) -> io::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    use std::thread;
+
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let handle = handle.clone();
+
+    thread::Builder::new()
+        .name(String::from("signals"))
+        .spawn(move || {
+            for signal in signals.forever() {
+                let forced = handle.is_shutdown();
+                logger.log(&Event::SignalReceived { signal, forced });
+
+                if forced {
+                    std::process::exit(128 + signal);
+                }
+                handle.shutdown();
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn shutdown_is_shared_between_clones() {
+        let handle = ShutdownHandle::new();
+        let clone = handle.clone();
+
+        assert!(!clone.is_shutdown());
+        handle.shutdown();
+        assert!(clone.is_shutdown());
+    }
+
+    #[test]
+    fn shutdown_wakes_a_blocked_accept() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let handle = ShutdownHandle::new();
+        handle.set_wake_addr(listener.local_addr().unwrap());
+
+        let waker = handle.clone();
+        let thread = std::thread::spawn(move || waker.shutdown());
+
+        // Without the wake-up connection this would block forever.
+        listener.accept().unwrap();
+        thread.join().unwrap();
+        assert!(handle.is_shutdown());
+    }
+}
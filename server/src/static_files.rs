@@ -0,0 +1,949 @@
+use crate::compression::{self, Encoding};
+use crate::headers::Headers;
+use crate::http_date::{format_http_date, parse_http_date};
+use crate::range::{parse_range, Ranges};
+use crate::request::{Method, Request};
+use crate::response::{Response, StatusCode};
+use crate::router::{Handler, Params};
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Serves files from a directory on disk.
+///
+/// Request paths are resolved relative to the root directory. Paths that
+/// would leave the root, either with `..` segments or by following a symlink
+/// that points outside of it, are refused with a 403.
+///
+/// Files carry an `ETag` and `Last-Modified` so clients can revalidate
+/// their cached copies cheaply, and can be fetched in pieces with `Range`.
+/// Text files are compressed with gzip or deflate for clients that say they
+/// can take it in `Accept-Encoding`. If a file has a precompressed sibling
+/// with `.gz` on the end, that's sent to gzip clients instead.
+///
+/// As a [`Handler`], it serves the path captured by a `*path` wildcard if the
+/// route has one, and the whole request path otherwise:
+///
+/// ```no_run
+/// use server::{Router, StaticFiles};
+///
+/// let router = Router::new().get("/assets/*path", StaticFiles::new("public"));
+/// ```
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+    not_found_page: Option<String>,
+    compress_above: Option<usize>,
+}
+
+// Below this size, compressing saves too little to be worth the time.
+const DEFAULT_COMPRESS_ABOVE: usize = 1024;
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            index: String::from("index.html"),
+            not_found_page: None,
+            compress_above: Some(DEFAULT_COMPRESS_ABOVE),
+        }
+    }
+
+    /// The file served for a request ending in `/`. Defaults to `index.html`.
+    pub fn index(mut self, index: impl Into<String>) -> StaticFiles {
+        self.index = index.into();
+        self
+    }
+
+    /// A file under the root to send as the body of 404 responses.
+    pub fn not_found_page(mut self, path: impl Into<String>) -> StaticFiles {
+        self.not_found_page = Some(path.into());
+        self
+    }
+
+    /// Compress text files bigger than `size` bytes, or never with `None`.
+    /// Defaults to 1 KiB. Precompressed `.gz` files are used either way.
+    pub fn compress_above(mut self, size: Option<usize>) -> StaticFiles {
+        self.compress_above = size;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Serve the file at `path`, a percent-encoded URL path relative to the
+    /// root. This never panics: every failure becomes an error response.
+    ///
+    /// The whole file is sent as it is. Use
+    /// [`serve_for`](StaticFiles::serve_for) to take the request's caching,
+    /// range and compression headers into account.
+    pub fn serve(&self, path: &str) -> Response {
+        self.respond(path, path, None)
+    }
+
+    /// Serve the file at `path` in reply to `request`.
+    ///
+    /// Every file is sent with an `ETag` and `Last-Modified`, and a request
+    /// whose `If-None-Match` or `If-Modified-Since` shows it already has the
+    /// current version gets an empty 304 instead. `Range` requests get just
+    /// the bytes they ask for, as a 206, and text files are compressed if
+    /// the request's `Accept-Encoding` allows.
+    pub fn serve_for(&self, request: &Request, path: &str) -> Response {
+        self.respond(path, path, Some(request))
+    }
+
+    // `url_path` is the full path the client asked for, which is where a
+    // directory redirect needs to point, even when `path` is only the part
+    // captured by a wildcard.
+    fn respond(&self, path: &str, url_path: &str, request: Option<&Request>) -> Response {
+        match self.resolve(path) {
+            Ok(Resolved::File(file)) => self.read(&file, StatusCode::Ok, request),
+            Ok(Resolved::Directory) => Response::new(StatusCode::MovedPermanently)
+                .with_header("Location", format!("{}/", url_path)),
+            Err(status) => self.error(status, request),
+        }
+    }
+
+    // Turn a URL path into a file inside the root directory, or the status
+    // to respond with if that's not possible.
+    fn resolve(&self, path: &str) -> Result<Resolved, StatusCode> {
+        let decoded = percent_decode(path).ok_or(StatusCode::BadRequest)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| StatusCode::BadRequest)?;
+
+        let mut file = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return Err(StatusCode::Forbidden),
+                // Once decoded, a segment mustn't be able to smuggle in its
+                // own separators or terminate the path early.
+                s if s.contains('\\') || s.contains('\0') => return Err(StatusCode::Forbidden),
+                s => file.push(s),
+            }
+        }
+
+        let metadata = fs::metadata(&file).map_err(|e| status_for(&e))?;
+
+        if metadata.is_dir() {
+            // Relative links inside an index page only work if the URL ends
+            // with a slash, so send the client there first.
+            if !path.is_empty() && !path.ends_with('/') {
+                return Ok(Resolved::Directory);
+            }
+            file.push(&self.index);
+        }
+
+        // Symlinks are resolved by canonicalize, so this also catches links
+        // that point somewhere outside the root.
+        let root = self.root.canonicalize().map_err(|e| status_for(&e))?;
+        let file = file.canonicalize().map_err(|e| status_for(&e))?;
+        if !file.starts_with(&root) {
+            return Err(StatusCode::Forbidden);
+        }
+
+        Ok(Resolved::File(file))
+    }
+
+    fn read(&self, file: &Path, status: StatusCode, request: Option<&Request>) -> Response {
+        let header = |name: &str| request.and_then(|request| request.header(name));
+        let accept_encoding = header("Accept-Encoding");
+
+        let content_type = mime_type(file);
+        let compressible =
+            self.compress_above.is_some() && compression::is_compressible(content_type);
+        let precompressed = self.precompressed(file);
+
+        let mut headers = Headers::new();
+        headers.append("Content-Type", content_type);
+
+        // Caches have to know that what they got depends on Accept-Encoding,
+        // or they'll hand gzip to clients that never asked for it.
+        if compressible || precompressed.is_some() {
+            headers.append("Vary", "Accept-Encoding");
+        }
+
+        let gzipped = precompressed
+            .filter(|_| compression::negotiate(accept_encoding, &[Encoding::Gzip]).is_some());
+        let source = gzipped.as_deref().unwrap_or(file);
+
+        let opened = fs::File::open(source).and_then(|f| Ok((f.metadata()?, f)));
+        let (metadata, mut source) = match opened {
+            Ok(opened) => opened,
+            Err(e) => return Response::new(status_for(&e)),
+        };
+        let len = metadata.len();
+
+        let mut encoding = if gzipped.is_some() {
+            Some(Encoding::Gzip)
+        } else if compressible && self.compress_above.is_some_and(|size| len > size as u64) {
+            compression::negotiate(accept_encoding, &[Encoding::Gzip, Encoding::Deflate])
+        } else {
+            None
+        };
+
+        // Error pages aren't worth caching, so only real files get
+        // validators, and only they can be asked for conditionally.
+        let validators = (status == StatusCode::Ok).then(|| Validators::new(&metadata));
+        if let (Some(validators), Some(request)) = (&validators, request) {
+            if validators.not_modified(request, encoding) {
+                // A 304 stands in for the full response, so it carries the
+                // same validators, but nothing that describes a body.
+                let mut not_modified = Headers::new();
+                if let Some(vary) = headers.get("Vary") {
+                    not_modified.append("Vary", vary);
+                }
+                validators.add_to(&mut not_modified, encoding);
+                return respond_with(StatusCode::NotModified, not_modified, Vec::new());
+            }
+        }
+
+        // Ranges are only offered on files sent as they are. A range of a
+        // compressed body is a range of bytes nobody can decompress.
+        let offers_ranges = validators.is_some() && request.is_some() && encoding.is_none();
+        let ranges = match (&validators, request) {
+            (Some(validators), Some(request)) if offers_ranges => {
+                validators.ranges(request, size_of(len))
+            }
+            _ => None,
+        };
+
+        match ranges {
+            None => {}
+            // Only the bytes asked for are read, so a client fetching the
+            // start of a big file doesn't cost us the whole of it.
+            Some(Ranges::Satisfiable(ranges)) => {
+                if let Some(validators) = &validators {
+                    validators.add_to(&mut headers, None);
+                }
+                headers.append("Accept-Ranges", "bytes");
+
+                return match read_ranges(&mut source, &ranges, len, content_type, &mut headers) {
+                    Ok(body) => respond_with(StatusCode::PartialContent, headers, body),
+                    Err(e) => Response::new(status_for(&e)),
+                };
+            }
+            Some(Ranges::Unsatisfiable) => {
+                return Response::new(StatusCode::RangeNotSatisfiable)
+                    .with_header("Content-Range", format!("bytes */{}", len))
+            }
+        }
+
+        // Read the raw bytes rather than a String so images and other
+        // binary files come through untouched.
+        let mut contents = Vec::with_capacity(size_of(len));
+        if let Err(e) = source.read_to_end(&mut contents) {
+            return Response::new(status_for(&e));
+        }
+
+        if let (Some(chosen), None) = (encoding, &gzipped) {
+            // If compression fails, the original is still good to send.
+            match chosen.compress(&contents) {
+                Ok(compressed) => contents = compressed,
+                Err(_) => encoding = None,
+            }
+        }
+
+        if let Some(encoding) = encoding {
+            headers.append("Content-Encoding", encoding.as_str());
+        }
+        if let Some(validators) = &validators {
+            validators.add_to(&mut headers, encoding);
+        }
+        if offers_ranges {
+            headers.append("Accept-Ranges", "bytes");
+        }
+
+        respond_with(status, headers, contents)
+    }
+
+    // A gzipped copy of `file` sitting next to it, as long as it's inside the
+    // root like the file itself.
+    fn precompressed(&self, file: &Path) -> Option<PathBuf> {
+        let mut name = file.file_name()?.to_os_string();
+        name.push(".gz");
+
+        let gz = file.with_file_name(name).canonicalize().ok()?;
+        let root = self.root.canonicalize().ok()?;
+
+        if gz.is_file() && gz.starts_with(root) {
+            Some(gz)
+        } else {
+            None
+        }
+    }
+
+    fn error(&self, status: StatusCode, request: Option<&Request>) -> Response {
+        if status == StatusCode::NotFound {
+            if let Some(page) = &self.not_found_page {
+                if let Ok(Resolved::File(file)) = self.resolve(page) {
+                    return self.read(&file, StatusCode::NotFound, request);
+                }
+            }
+        }
+
+        Response::new(status)
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        let path = params.get("path").unwrap_or_else(|| request.path());
+        self.respond(path, request.path(), Some(request))
+    }
+}
+
+// What a client can compare its cached copy against to see whether it's
+// still current.
+struct Validators {
+    // Strong, built from the file's length and modification time, which
+    // change whenever its contents do in practice.
+    etag: String,
+    modified: Option<SystemTime>,
+}
+
+impl Validators {
+    fn new(metadata: &fs::Metadata) -> Validators {
+        let modified = metadata.modified().ok();
+        let nanos = modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_nanos());
+
+        Validators {
+            etag: format!("{:x}-{:x}", metadata.len(), nanos),
+            modified,
+        }
+    }
+
+    // Each encoding of a file is a different set of bytes, so each needs a
+    // tag of its own.
+    fn etag(&self, encoding: Option<Encoding>) -> String {
+        match encoding {
+            Some(encoding) => format!("\"{}-{}\"", self.etag, encoding.as_str()),
+            None => format!("\"{}\"", self.etag),
+        }
+    }
+
+    fn add_to(&self, headers: &mut Headers, encoding: Option<Encoding>) {
+        headers.append("ETag", self.etag(encoding));
+        if let Some(modified) = self.modified {
+            headers.append("Last-Modified", format_http_date(modified));
+        }
+    }
+
+    // Whether the client's cached copy is current, going by If-None-Match
+    // if it sent one and If-Modified-Since otherwise.
+    fn not_modified(&self, request: &Request, encoding: Option<Encoding>) -> bool {
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return false;
+        }
+
+        if let Some(tags) = request.header("If-None-Match") {
+            let etag = self.etag(encoding);
+            // Weak comparison: a W/ prefix doesn't stop a match.
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+        }
+
+        match (request.header("If-Modified-Since"), self.modified) {
+            (Some(since), Some(modified)) => match parse_http_date(since) {
+                // Last-Modified only goes to the second, so compare at that.
+                Some(since) => truncate_to_secs(modified) <= since,
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    // The ranges a GET asks for, unless If-Range says its copy is out of
+    // date, in which case it needs the whole file again.
+    fn ranges(&self, request: &Request, len: usize) -> Option<Ranges> {
+        if request.method() != Method::Get {
+            return None;
+        }
+        let range = request.header("Range")?;
+
+        if let Some(if_range) = request.header("If-Range") {
+            let current = if if_range.starts_with('"') {
+                // Strong comparison: only an exact tag will do.
+                if_range == self.etag(None)
+            } else {
+                let since = parse_http_date(if_range);
+                since.is_some() && since == self.modified.map(truncate_to_secs)
+            };
+
+            if !current {
+                return None;
+            }
+        }
+
+        parse_range(range, len)
+    }
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH + Duration::from_secs(since.as_secs()),
+        Err(_) => time,
+    }
+}
+
+fn respond_with(status: StatusCode, headers: Headers, body: impl Into<Vec<u8>>) -> Response {
+    let mut response = Response::new(status).with_body(body);
+    *response.headers_mut() = headers;
+    response
+}
+
+fn content_range(range: &Range<usize>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+// A boundary that won't turn up inside the file by accident.
+fn boundary() -> String {
+    let random = RandomState::new().build_hasher().finish();
+    format!("{:016x}", random)
+}
+
+// The body of a 206 for `ranges` of `file`, which is `len` bytes long,
+// setting the headers that describe it. A single range is sent as it is, and
+// several as a multipart body, each part with its own Content-Type and
+// Content-Range.
+fn read_ranges(
+    file: &mut fs::File,
+    ranges: &[Range<usize>],
+    len: u64,
+    content_type: &str,
+    headers: &mut Headers,
+) -> io::Result<Vec<u8>> {
+    if let [range] = ranges {
+        headers.append("Content-Range", content_range(range, len));
+        return read_range(file, range);
+    }
+
+    let boundary = boundary();
+    headers.insert(
+        "Content-Type",
+        format!("multipart/byteranges; boundary={}", boundary),
+    );
+
+    let mut body = Vec::new();
+    for range in ranges {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                content_range(range, len)
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&read_range(file, range)?);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    Ok(body)
+}
+
+fn read_range(file: &mut fs::File, range: &Range<usize>) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(range.start as u64))?;
+    let mut bytes = vec![0; range.len()];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// A file's length as a usize. One too big for that can't be read into memory
+// anyway.
+fn size_of(len: u64) -> usize {
+    usize::try_from(len).unwrap_or(usize::MAX)
+}
+
+enum Resolved {
+    File(PathBuf),
+    // A directory asked for without a trailing slash.
+    Directory,
+}
+
+fn status_for(error: &io::Error) -> StatusCode {
+    match error.kind() {
+        io::ErrorKind::NotFound => StatusCode::NotFound,
+        io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+        _ => StatusCode::InternalServerError,
+    }
+}
+
+/// Guess a file's `Content-Type` from its extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+// Decode %XX escapes. Returns None if an escape is cut short or isn't hex.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Each test gets its own scratch directory so they can run in parallel.
+    fn scratch_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "server-static-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("public/docs")).unwrap();
+        fs::write(dir.join("public/hello.html"), "<h1>Hello!</h1>").unwrap();
+        fs::write(
+            dir.join("public/logo.png"),
+            [0x89, b'P', b'N', b'G', 0, 0xff],
+        )
+        .unwrap();
+        fs::write(dir.join("public/docs/index.html"), "docs").unwrap();
+        fs::write(dir.join("public/404.html"), "missing").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        dir
+    }
+
+    #[test]
+    fn serves_files_with_a_content_type() {
+        let dir = scratch_dir();
+        let files = StaticFiles::new(dir.join("public"));
+
+        let response = files.serve("/hello.html");
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.body(), b"<h1>Hello!</h1>");
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let response = files.serve("/logo.png");
+        assert_eq!(response.body(), &[0x89, b'P', b'N', b'G', 0, 0xff]);
+        assert_eq!(response.headers().get("Content-Type"), Some("image/png"));
+    }
+
+    #[test]
+    fn directories_serve_their_index() {
+        let dir = scratch_dir();
+        let files = StaticFiles::new(dir.join("public"));
+
+        assert_eq!(files.serve("/docs/").body(), b"docs");
+
+        let response = files.serve("/docs");
+        assert_eq!(response.status(), StatusCode::MovedPermanently);
+        assert_eq!(response.headers().get("Location"), Some("/docs/"));
+
+        // There's no index.html at the top level.
+        assert_eq!(files.serve("/").status(), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn missing_files_are_404() {
+        let dir = scratch_dir();
+        let files = StaticFiles::new(dir.join("public"));
+
+        assert_eq!(files.serve("/nope.html").status(), StatusCode::NotFound);
+
+        let files = files.not_found_page("404.html");
+        let response = files.serve("/nope.html");
+        assert_eq!(response.status(), StatusCode::NotFound);
+        assert_eq!(response.body(), b"missing");
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let dir = scratch_dir();
+        let files = StaticFiles::new(dir.join("public"));
+
+        assert_eq!(
+            files.serve("/../secret.txt").status(),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            files.serve("/docs/%2e%2e/%2E%2E/secret.txt").status(),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            files.serve("/..%2fsecret.txt").status(),
+            StatusCode::Forbidden
+        );
+        assert_eq!(files.serve("/%zz").status(), StatusCode::BadRequest);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_out_of_the_root() {
+        let dir = scratch_dir();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("public/escape.txt")).unwrap();
+        std::os::unix::fs::symlink(dir.join("public/hello.html"), dir.join("public/alias.html"))
+            .unwrap();
+        let files = StaticFiles::new(dir.join("public"));
+
+        assert_eq!(files.serve("/escape.txt").status(), StatusCode::Forbidden);
+        assert_eq!(files.serve("/alias.html").status(), StatusCode::Ok);
+    }
+
+    fn get_with(path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("GET {} HTTP/1.1\r\nHost: x\r\n", path);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        Request::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn get(path: &str, accept_encoding: &str) -> Request {
+        get_with(path, &[("Accept-Encoding", accept_encoding)])
+    }
+
+    #[test]
+    fn compresses_large_text_files_for_clients_that_accept_it() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = scratch_dir();
+        let page = "<p>Hello, world!</p>\n".repeat(100);
+        fs::write(dir.join("public/big.html"), &page).unwrap();
+        let files = StaticFiles::new(dir.join("public"));
+
+        let response = files.serve_for(&get("/big.html", "deflate;q=0.5, gzip"), "/big.html");
+        assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let mut decoded = String::new();
+        GzDecoder::new(response.body())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, page);
+
+        let response = files.serve_for(&get("/big.html", "gzip;q=0, deflate"), "/big.html");
+        assert_eq!(response.headers().get("Content-Encoding"), Some("deflate"));
+
+        // Refused, not asked for, or switched off: sent as it is, though the
+        // answer still depends on Accept-Encoding.
+        let response = files.serve_for(&get("/big.html", "identity"), "/big.html");
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body(), page.as_bytes());
+        assert_eq!(files.serve("/big.html").body(), page.as_bytes());
+
+        let files = files.compress_above(None);
+        let response = files.serve_for(&get("/big.html", "gzip"), "/big.html");
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.headers().get("Vary"), None);
+    }
+
+    #[test]
+    fn small_and_binary_files_are_not_compressed() {
+        let dir = scratch_dir();
+        fs::write(dir.join("public/big.png"), vec![0u8; 4096]).unwrap();
+        let files = StaticFiles::new(dir.join("public"));
+
+        let response = files.serve_for(&get("/hello.html", "gzip"), "/hello.html");
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.body(), b"<h1>Hello!</h1>");
+
+        let response = files.serve_for(&get("/big.png", "gzip"), "/big.png");
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.headers().get("Vary"), None);
+    }
+
+    #[test]
+    fn serves_precompressed_siblings_to_gzip_clients() {
+        let dir = scratch_dir();
+        fs::write(dir.join("public/hello.html.gz"), b"pretend gzip").unwrap();
+        let files = StaticFiles::new(dir.join("public"));
+
+        let response = files.serve_for(&get("/hello.html", "gzip"), "/hello.html");
+        assert_eq!(response.body(), b"pretend gzip");
+        assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let response = files.serve_for(&get("/hello.html", "deflate"), "/hello.html");
+        assert_eq!(response.body(), b"<h1>Hello!</h1>");
+        assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn unchanged_files_are_304() {
+        let dir = scratch_dir();
+        let files = StaticFiles::new(dir.join("public"));
+
+        let response = files.serve_for(&get_with("/hello.html", &[]), "/hello.html");
+        let etag = response.headers().get("ETag").unwrap().to_string();
+        let modified = response.headers().get("Last-Modified").unwrap().to_string();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+        let cases: [(&str, &str); 4] = [
+            ("If-None-Match", &etag),
+            ("If-None-Match", &format!("\"other\", W/{}", etag)),
+            ("If-None-Match", "*"),
+            ("If-Modified-Since", &modified),
+        ];
+        for header in cases {
+            let response = files.serve_for(&get_with("/hello.html", &[header]), "/hello.html");
+            assert_eq!(response.status(), StatusCode::NotModified, "{:?}", header);
+            assert_eq!(response.headers().get("ETag"), Some(etag.as_str()));
+            assert_eq!(response.headers().get("Content-Type"), None);
+            assert!(response.body().is_empty());
+        }
+
+        let cases = [
+            ("If-None-Match", "\"other\""),
+            ("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT"),
+            ("If-Modified-Since", "yesterday"),
+        ];
+        for header in cases {
+            let response = files.serve_for(&get_with("/hello.html", &[header]), "/hello.html");
+            assert_eq!(response.status(), StatusCode::Ok, "{:?}", header);
+            assert_eq!(response.body(), b"<h1>Hello!</h1>");
+        }
+
+        // If-None-Match wins over If-Modified-Since when both are sent.
+        let request = get_with(
+            "/hello.html",
+            &[
+                ("If-None-Match", "\"other\""),
+                ("If-Modified-Since", &modified),
+            ],
+        );
+        assert_eq!(
+            files.serve_for(&request, "/hello.html").status(),
+            StatusCode::Ok
+        );
+
+        // Error pages aren't validated.
+        let files = files.not_found_page("404.html");
+        let request = get_with("/nope.html", &[("If-None-Match", "*")]);
+        let response = files.serve_for(&request, "/nope.html");
+        assert_eq!(response.status(), StatusCode::NotFound);
+        assert_eq!(response.headers().get("ETag"), None);
+    }
+
+    #[test]
+    fn each_encoding_has_its_own_etag() {
+        let dir = scratch_dir();
+        fs::write(dir.join("public/big.txt"), "text ".repeat(1000)).unwrap();
+        let files = StaticFiles::new(dir.join("public"));
+
+        let plain = files.serve_for(&get_with("/big.txt", &[]), "/big.txt");
+        let request = get_with("/big.txt", &[("Accept-Encoding", "gzip")]);
+        let gzipped = files.serve_for(&request, "/big.txt");
+        let plain_etag = plain.headers().get("ETag").unwrap();
+        let gzip_etag = gzipped.headers().get("ETag").unwrap();
+        assert_ne!(plain_etag, gzip_etag);
+
+        let request = get_with(
+            "/big.txt",
+            &[("Accept-Encoding", "gzip"), ("If-None-Match", plain_etag)],
+        );
+        assert_eq!(
+            files.serve_for(&request, "/big.txt").status(),
+            StatusCode::Ok
+        );
+
+        let request = get_with(
+            "/big.txt",
+            &[("Accept-Encoding", "gzip"), ("If-None-Match", gzip_etag)],
+        );
+        let response = files.serve_for(&request, "/big.txt");
+        assert_eq!(response.status(), StatusCode::NotModified);
+        assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn ranges_get_partial_content() {
+        let dir = scratch_dir();
+        let files = StaticFiles::new(dir.join("public"));
+        let serve = |headers: &[(&str, &str)]| {
+            files.serve_for(&get_with("/hello.html", headers), "/hello.html")
+        };
+
+        let response = serve(&[("Range", "bytes=4-9")]);
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        assert_eq!(response.body(), b"Hello!");
+        assert_eq!(
+            response.headers().get("Content-Range"),
+            Some("bytes 4-9/15")
+        );
+        assert_eq!(response.headers().get("Accept-Ranges"), Some("bytes"));
+
+        let response = serve(&[("Range", "bytes=-5")]);
+        assert_eq!(response.body(), b"</h1>");
+
+        let response = serve(&[("Range", "bytes=100-")]);
+        assert_eq!(response.status(), StatusCode::RangeNotSatisfiable);
+        assert_eq!(response.headers().get("Content-Range"), Some("bytes */15"));
+
+        let response = serve(&[("Range", "lines=1-2")]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.body(), b"<h1>Hello!</h1>");
+    }
+
+    #[test]
+    fn several_ranges_are_multipart() {
+        let dir = scratch_dir();
+        let files = StaticFiles::new(dir.join("public"));
+        let request = get_with("/hello.html", &[("Range", "bytes=0-3, 10-")]);
+        let response = files.serve_for(&request, "/hello.html");
+
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        let content_type = response.headers().get("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Range: bytes 0-3/15\r\n\r\n\
+             <h1>\r\n\
+             --{b}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Range: bytes 10-14/15\r\n\r\n\
+             </h1>\r\n\
+             --{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(std::str::from_utf8(response.body()).unwrap(), expected);
+    }
+
+    #[test]
+    fn ranges_only_read_the_bytes_asked_for() {
+        use std::io::Write;
+
+        // A sparse gigabyte, which would take a while to read, and as much
+        // memory to hold, if the ranges were cut from the whole file.
+        let dir = scratch_dir();
+        let mut file = fs::File::create(dir.join("public/huge.bin")).unwrap();
+        file.set_len(1 << 30).unwrap();
+        file.seek(SeekFrom::End(-4)).unwrap();
+        file.write_all(b"tail").unwrap();
+        drop(file);
+
+        let files = StaticFiles::new(dir.join("public"));
+        let serve =
+            |range: &str| files.serve_for(&get_with("/huge.bin", &[("Range", range)]), "/huge.bin");
+
+        let response = serve("bytes=0-0");
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        assert_eq!(response.body(), b"\0");
+
+        let response = serve("bytes=0-1, -4");
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        let body = std::str::from_utf8(response.body()).unwrap();
+        assert!(body.contains("Content-Range: bytes 1073741820-1073741823/1073741824\r\n\r\ntail"));
+
+        let response = serve("bytes=2000000000-");
+        assert_eq!(response.status(), StatusCode::RangeNotSatisfiable);
+        assert_eq!(
+            response.headers().get("Content-Range"),
+            Some("bytes */1073741824")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn if_range_only_allows_ranges_of_the_current_file() {
+        let dir = scratch_dir();
+        let files = StaticFiles::new(dir.join("public"));
+        let full = files.serve_for(&get_with("/hello.html", &[]), "/hello.html");
+        let etag = full.headers().get("ETag").unwrap();
+        let modified = full.headers().get("Last-Modified").unwrap();
+
+        for validator in [etag, modified] {
+            let request = get_with(
+                "/hello.html",
+                &[("Range", "bytes=0-3"), ("If-Range", validator)],
+            );
+            let response = files.serve_for(&request, "/hello.html");
+            assert_eq!(response.status(), StatusCode::PartialContent);
+        }
+
+        for validator in ["\"stale\"", "Thu, 01 Jan 1970 00:00:00 GMT"] {
+            let request = get_with(
+                "/hello.html",
+                &[("Range", "bytes=0-3"), ("If-Range", validator)],
+            );
+            let response = files.serve_for(&request, "/hello.html");
+            assert_eq!(response.status(), StatusCode::Ok);
+            assert_eq!(response.body(), b"<h1>Hello!</h1>");
+        }
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b").unwrap(), b"a b");
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%+1"), None);
+    }
+}
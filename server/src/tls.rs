@@ -0,0 +1,404 @@
+use crate::connection::Transport;
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A certificate chain and the private key that goes with it.
+#[derive(Debug, Clone)]
+pub struct Certificate(Arc<CertifiedKey>);
+
+impl Certificate {
+    /// Load a certificate chain and its private key from PEM files, like the
+    /// `fullchain.pem` and `privkey.pem` a certificate authority hands out.
+    /// The chain starts with the server's own certificate.
+    pub fn from_pem_files(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<Certificate, TlsError> {
+        let read = |path: &Path| {
+            fs::read(path).map_err(|error| TlsError::Read {
+                path: path.to_path_buf(),
+                error,
+            })
+        };
+
+        Certificate::from_pem(&read(cert.as_ref())?, &read(key.as_ref())?)
+    }
+
+    /// Like [`from_pem_files`](Certificate::from_pem_files), for PEM that's
+    /// already in memory.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<Certificate, TlsError> {
+        let chain = rustls_pemfile::certs(&mut &cert[..])
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(TlsError::Pem)?;
+        if chain.is_empty() {
+            return Err(TlsError::NoCertificate);
+        }
+
+        let key = rustls_pemfile::private_key(&mut &key[..])
+            .map_err(TlsError::Pem)?
+            .ok_or(TlsError::NoPrivateKey)?;
+
+        // This also checks that the key is the one the certificate is for.
+        let certified = CertifiedKey::from_der(chain, key, &ring::default_provider())
+            .map_err(TlsError::Rustls)?;
+        Ok(Certificate(Arc::new(certified)))
+    }
+}
+
+/// The certificates a server offers to clients that connect over TLS.
+///
+/// Clients say which host they're after when they connect (Server Name
+/// Indication, or SNI), so one server can have a certificate for each name it
+/// goes by. Clients that ask for a name without one of its own, or don't say,
+/// get the default.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    default: Certificate,
+    by_name: HashMap<String, Certificate>,
+}
+
+impl TlsConfig {
+    pub fn new(default: Certificate) -> TlsConfig {
+        TlsConfig {
+            default,
+            by_name: HashMap::new(),
+        }
+    }
+
+    /// Offer `cert` to clients that ask for `name`. Names are matched
+    /// ignoring case, and `*.example.com` matches any one name directly
+    /// under `example.com`, though an exact match wins.
+    pub fn sni(mut self, name: &str, cert: Certificate) -> TlsConfig {
+        self.by_name.insert(name.to_ascii_lowercase(), cert);
+        self
+    }
+
+    pub(crate) fn acceptor(&self) -> Acceptor {
+        let resolver = Resolver {
+            default: Arc::clone(&self.default.0),
+            by_name: self
+                .by_name
+                .iter()
+                .map(|(name, cert)| (name.clone(), Arc::clone(&cert.0)))
+                .collect(),
+        };
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the default protocol versions are supported")
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Acceptor(Arc::new(config))
+    }
+}
+
+#[derive(Debug)]
+struct Resolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl Resolver {
+    fn lookup(&self, name: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        self.by_name.get(&name).or_else(|| {
+            let (_, parent) = name.split_once('.')?;
+            self.by_name.get(&format!("*.{}", parent))
+        })
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let cert = hello
+            .server_name()
+            .and_then(|name| self.lookup(name))
+            .unwrap_or(&self.default);
+        Some(Arc::clone(cert))
+    }
+}
+
+// Wraps accepted connections in TLS. Cheap to clone, so each connection's
+// job can have its own.
+#[derive(Clone)]
+pub(crate) struct Acceptor(Arc<ServerConfig>);
+
+impl Acceptor {
+    // The handshake happens on the first read, on the worker serving the
+    // connection, so a slow client doesn't hold up the accept loop.
+    pub(crate) fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(Arc::clone(&self.0)).map_err(io::Error::other)?;
+        Ok(TlsStream(StreamOwned::new(connection, stream)))
+    }
+}
+
+pub(crate) struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // Plenty of clients hang up without saying goodbye first. HTTP
+            // messages carry their own length, so a truncated one is caught
+            // by the parser instead.
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Transport for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_write_timeout(timeout)
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.0.conn.send_close_notify();
+        self.0.flush()?;
+        self.0.sock.shutdown(Shutdown::Write)
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // Let the client know the connection was closed on purpose. This is
+        // only a courtesy, so it doesn't matter if it can't be sent.
+        self.0.conn.send_close_notify();
+        let _ = self.0.flush();
+    }
+}
+
+/// The ways loading a [`Certificate`] can fail.
+#[derive(Debug)]
+pub enum TlsError {
+    /// A PEM file couldn't be read.
+    Read { path: PathBuf, error: io::Error },
+    /// The PEM couldn't be parsed.
+    Pem(io::Error),
+    /// There's no certificate in the certificate PEM.
+    NoCertificate,
+    /// There's no private key in the key PEM.
+    NoPrivateKey,
+    /// The key isn't one rustls can use, or isn't the certificate's key.
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Read { path, error } => {
+                write!(f, "couldn't read {}: {}", path.display(), error)
+            }
+            TlsError::Pem(e) => write!(f, "couldn't parse PEM: {}", e),
+            TlsError::NoCertificate => write!(f, "no certificate found"),
+            TlsError::NoPrivateKey => write!(f, "no private key found"),
+            TlsError::Rustls(e) => write!(f, "unusable certificate or key: {}", e),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::Read { error, .. } => Some(error),
+            TlsError::Pem(e) => Some(e),
+            TlsError::Rustls(e) => Some(e),
+            TlsError::NoCertificate | TlsError::NoPrivateKey => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ThreadPool;
+    use crate::request::Request;
+    use crate::response::{Response, StatusCode};
+    use crate::router::{Params, Router};
+    use crate::server::Server;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use std::thread;
+
+    // A self-signed certificate for `names`, as PEM, along with its DER so
+    // clients can trust it.
+    fn self_signed(names: &[&str]) -> (String, String, CertificateDer<'static>) {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let generated = rcgen::generate_simple_self_signed(names).unwrap();
+        (
+            generated.cert.pem(),
+            generated.key_pair.serialize_pem(),
+            generated.cert.der().clone(),
+        )
+    }
+
+    fn certificate(names: &[&str]) -> (Certificate, CertificateDer<'static>) {
+        let (cert, key, der) = self_signed(names);
+        (
+            Certificate::from_pem(cert.as_bytes(), key.as_bytes()).unwrap(),
+            der,
+        )
+    }
+
+    fn serve(tls: TlsConfig) -> SocketAddr {
+        let router = Router::new().get("/:name", |_: &Request, params: &Params| {
+            Response::new(StatusCode::Ok).with_body(format!("hi {}", params.get("name").unwrap()))
+        });
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(1), router)
+            .unwrap()
+            .with_tls(tls)
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // The server is left running until the test process exits.
+        thread::spawn(move || server.run());
+        addr
+    }
+
+    // Connect to `addr`, asking for `name` and trusting only `trusted`.
+    fn connect(
+        addr: SocketAddr,
+        name: &str,
+        trusted: &[&CertificateDer<'static>],
+    ) -> StreamOwned<ClientConnection, TcpStream> {
+        let mut roots = RootCertStore::empty();
+        for cert in trusted {
+            roots.add((*cert).clone()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        StreamOwned::new(connection, TcpStream::connect(addr).unwrap())
+    }
+
+    fn get(stream: &mut StreamOwned<ClientConnection, TcpStream>, path: &str) -> String {
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_requests_over_tls() {
+        let (cert, der) = certificate(&["localhost"]);
+        let addr = serve(TlsConfig::new(cert));
+
+        let mut stream = connect(addr, "localhost", &[&der]);
+        let response = get(&mut stream, "/there");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("hi there"));
+    }
+
+    #[test]
+    fn picks_a_certificate_by_server_name() {
+        let (fallback, fallback_der) = certificate(&["localhost"]);
+        let (one, one_der) = certificate(&["one.test"]);
+        let (many, many_der) = certificate(&["*.many.test"]);
+        let addr = serve(
+            TlsConfig::new(fallback)
+                .sni("ONE.test", one)
+                .sni("*.many.test", many),
+        );
+        let trusted = [&fallback_der, &one_der, &many_der];
+
+        for (name, expected) in [
+            ("one.test", &one_der),
+            ("b.many.test", &many_der),
+            ("localhost", &fallback_der),
+        ] {
+            let mut stream = connect(addr, name, &trusted);
+            assert!(get(&mut stream, "/sni").ends_with("hi sni"));
+            assert_eq!(stream.conn.peer_certificates().unwrap()[0], *expected);
+        }
+
+        // A client that doesn't trust the certificate it's given gives up.
+        let mut stream = connect(addr, "localhost", &[&one_der]);
+        assert!(stream.write_all(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_unusable_pem() {
+        let (cert, key, _) = self_signed(&["localhost"]);
+        let (_, other_key, _) = self_signed(&["localhost"]);
+
+        assert!(matches!(
+            Certificate::from_pem(cert.as_bytes(), other_key.as_bytes()),
+            Err(TlsError::Rustls(_))
+        ));
+        assert!(matches!(
+            Certificate::from_pem(key.as_bytes(), key.as_bytes()),
+            Err(TlsError::NoCertificate)
+        ));
+        assert!(matches!(
+            Certificate::from_pem(cert.as_bytes(), cert.as_bytes()),
+            Err(TlsError::NoPrivateKey)
+        ));
+        assert!(matches!(
+            Certificate::from_pem_files("/nonexistent/cert.pem", "/nonexistent/key.pem"),
+            Err(TlsError::Read { .. })
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn the_event_loop_refuses_tls() {
+        let bind = || Server::bind("127.0.0.1:0", ThreadPool::new(1), Router::new()).unwrap();
+
+        let (cert, _) = certificate(&["localhost"]);
+        let result = bind()
+            .with_event_loop()
+            .unwrap()
+            .with_tls(TlsConfig::new(cert));
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        let (cert, _) = certificate(&["localhost"]);
+        let result = bind()
+            .with_tls(TlsConfig::new(cert))
+            .unwrap()
+            .with_event_loop();
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+}
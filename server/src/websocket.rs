@@ -0,0 +1,812 @@
+use crate::base64;
+use crate::connection::{Transport, Upgrade, Upgraded};
+use crate::logging::Event;
+use crate::request::{Method, Request, Version};
+use crate::response::{Response, StatusCode};
+use crate::shutdown::ShutdownHandle;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Appended to the client's key before hashing it, to prove the server
+// understood the handshake rather than just echoing it.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// Close codes, from RFC 6455 section 7.4.1.
+const NORMAL: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// How long to wait for the client to answer our close frame with its own.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A message sent over a [`WebSocket`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Asks the other end to answer with a `Pong` carrying the same data.
+    /// Pings from the client are answered automatically.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(bytes: Vec<u8>) -> Message {
+        Message::Binary(bytes)
+    }
+}
+
+/// A WebSocket connection to a client, for sending and receiving whole
+/// messages.
+///
+/// A route accepts WebSockets by answering with [`WebSocket::upgrade`]:
+///
+/// ```no_run
+/// use server::{Params, Request, Router, WebSocket};
+///
+/// let router = Router::new().get("/echo", |request: &Request, _: &Params| {
+///     WebSocket::upgrade(request, |mut socket| {
+///         while let Some(message) = socket.recv()? {
+///             socket.send(message)?;
+///         }
+///         Ok(())
+///     })
+/// });
+/// ```
+///
+/// Each socket gets a thread of its own, so sockets that stay open for hours
+/// don't tie up the workers that serve ordinary requests.
+pub struct WebSocket {
+    stream: Box<dyn Transport + Send>,
+    // Bytes read but not yet parsed into frames.
+    buffer: Vec<u8>,
+    shutdown: ShutdownHandle,
+    max_message_size: usize,
+    // The opcode and data so far of a message arriving in fragments.
+    partial: Option<(u8, Vec<u8>)>,
+    sent_close: bool,
+    received_close: bool,
+}
+
+impl WebSocket {
+    /// Answer a WebSocket handshake, and once it's done run `handler` with
+    /// the socket on a new thread.
+    ///
+    /// Requests that aren't a valid handshake get a `400 Bad Request`, or a
+    /// `426 Upgrade Required` if they're for a protocol version we don't
+    /// speak. Errors `handler` returns are logged.
+    pub fn upgrade<F>(request: &Request, handler: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> io::Result<()> + Send + 'static,
+    {
+        let headers = request.headers();
+        let upgrade_required = Response::new(StatusCode::UpgradeRequired)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Version", "13");
+
+        if !headers.has_token("Upgrade", "websocket") || !headers.has_token("Connection", "upgrade")
+        {
+            return upgrade_required;
+        }
+        if request.method() != Method::Get || request.version() != Version::Http11 {
+            return Response::new(StatusCode::BadRequest);
+        }
+        if headers.get("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+            return upgrade_required;
+        }
+
+        // The key is 16 random bytes, base64-encoded.
+        let key = match headers.get("Sec-WebSocket-Key").map(str::trim) {
+            Some(key) if base64::decode(key).is_some_and(|bytes| bytes.len() == 16) => key,
+            _ => return Response::new(StatusCode::BadRequest),
+        };
+
+        Response::new(StatusCode::SwitchingProtocols)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", accept_key(key))
+            .with_upgrade(Upgrade::new(move |upgraded| {
+                // Counted before the thread starts, so a server that's
+                // draining can't miss it.
+                let open = upgraded.shutdown.track_upgraded();
+
+                thread::Builder::new()
+                    .name(String::from("websocket"))
+                    .spawn(move || {
+                        let _open = open;
+                        let logger = upgraded.logger.clone();
+                        if let Err(e) = handler(WebSocket::new(upgraded)) {
+                            logger.log(&Event::Error {
+                                context: "serving a websocket",
+                                error: &e,
+                            });
+                        }
+                    })
+                    .map(|_| ())
+            }))
+    }
+
+    fn new(upgraded: Upgraded) -> WebSocket {
+        WebSocket {
+            stream: upgraded.stream,
+            buffer: upgraded.read_ahead,
+            shutdown: upgraded.shutdown,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            partial: None,
+            sent_close: false,
+            received_close: false,
+        }
+    }
+
+    /// The biggest message the client can send, in bytes. A bigger one
+    /// closes the socket. Defaults to 1MiB.
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.max_message_size = bytes;
+    }
+
+    /// Wait for the next message from the client.
+    ///
+    /// Returns `None` once the socket is closed: by the client, by
+    /// [`close`](WebSocket::close), or because the server is shutting down.
+    /// A client that breaks the protocol is sent a close frame saying why,
+    /// and the error is returned.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        self.next_message(None)
+    }
+
+    /// Like [`recv`](WebSocket::recv), but gives up with a `TimedOut` error
+    /// if no message arrives within `timeout`. The socket is still usable
+    /// afterwards, so this is the way to push updates to the client while
+    /// still answering its pings and noticing when it leaves.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        self.next_message(Some(Instant::now() + timeout))
+    }
+
+    /// Send a message to the client.
+    pub fn send(&mut self, message: impl Into<Message>) -> io::Result<()> {
+        if self.sent_close {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the websocket is closed",
+            ));
+        }
+
+        let message = message.into();
+        let (opcode, payload) = match &message {
+            Message::Text(text) => (TEXT, text.as_bytes()),
+            Message::Binary(bytes) => (BINARY, &bytes[..]),
+            Message::Ping(bytes) => (PING, &bytes[..]),
+            Message::Pong(bytes) => (PONG, &bytes[..]),
+        };
+        if opcode >= CLOSE && payload.len() > 125 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pings and pongs carry at most 125 bytes",
+            ));
+        }
+
+        self.write_frame(opcode, payload)
+    }
+
+    /// Close the socket, telling the client why with a close `code` from
+    /// RFC 6455 and a short `reason`, then wait briefly for it to agree.
+    /// Dropping the socket closes it too, with code 1000 and without
+    /// waiting.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if !self.sent_close {
+            self.send_close(code, reason)?;
+        }
+
+        // Anything the client sends before its own close frame is dropped.
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while !self.received_close {
+            match self.read_frame(Some(deadline)) {
+                Ok(Some(frame)) if frame.opcode == CLOSE => self.received_close = true,
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        self.stream.shutdown_write()
+    }
+
+    fn next_message(&mut self, deadline: Option<Instant>) -> io::Result<Option<Message>> {
+        loop {
+            if self.sent_close || self.received_close {
+                return Ok(None);
+            }
+
+            let frame = match self.read_frame(deadline)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            match frame.opcode {
+                TEXT | BINARY if self.partial.is_some() => {
+                    return Err(self.fail(PROTOCOL_ERROR, "new message before the last one ended"));
+                }
+                TEXT | BINARY if frame.fin => {
+                    return self.finish(frame.opcode, frame.payload).map(Some);
+                }
+                TEXT | BINARY => self.partial = Some((frame.opcode, frame.payload)),
+                CONTINUATION => {
+                    let (opcode, mut data) = match self.partial.take() {
+                        Some(partial) => partial,
+                        None => {
+                            return Err(self.fail(PROTOCOL_ERROR, "continuation of no message"));
+                        }
+                    };
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail(TOO_BIG, "message too big"));
+                    }
+
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.finish(opcode, data).map(Some);
+                    }
+                    self.partial = Some((opcode, data));
+                }
+                CLOSE => {
+                    self.closed_by_client(&frame.payload)?;
+                    return Ok(None);
+                }
+                PING => self.write_frame(PONG, &frame.payload)?,
+                PONG => return Ok(Some(Message::Pong(frame.payload))),
+                _ => return Err(self.fail(PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+    }
+
+    fn finish(&mut self, opcode: u8, data: Vec<u8>) -> io::Result<Message> {
+        if opcode == BINARY {
+            return Ok(Message::Binary(data));
+        }
+        match String::from_utf8(data) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => Err(self.fail(INVALID_DATA, "text message isn't UTF-8")),
+        }
+    }
+
+    // The client wants to close. Check it asked properly, agree if we
+    // haven't already asked ourselves, and stop writing.
+    fn closed_by_client(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.received_close = true;
+
+        let code = match payload.len() {
+            0 => None,
+            1 => return Err(self.fail(PROTOCOL_ERROR, "close frame code cut short")),
+            _ => Some(u16::from_be_bytes([payload[0], payload[1]])),
+        };
+        if let Some(code) = code {
+            if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+                return Err(self.fail(PROTOCOL_ERROR, "invalid close code"));
+            }
+            if std::str::from_utf8(&payload[2..]).is_err() {
+                return Err(self.fail(INVALID_DATA, "close reason isn't UTF-8"));
+            }
+        }
+
+        if !self.sent_close {
+            self.send_close(code.unwrap_or(NORMAL), "")?;
+        }
+        self.stream.shutdown_write()
+    }
+
+    // Read the next frame, waiting until `deadline` if there is one. `None`
+    // means the socket has closed without a close frame.
+    fn read_frame(&mut self, deadline: Option<Instant>) -> io::Result<Option<Frame>> {
+        let mut chunk = [0; 4096];
+
+        loop {
+            match parse_frame(&self.buffer, self.max_message_size) {
+                Ok(Some((frame, used))) => {
+                    self.buffer.drain(..used);
+                    return Ok(Some(frame));
+                }
+                Ok(None) => {}
+                Err((code, reason)) => return Err(self.fail(code, reason)),
+            }
+
+            // The stream's read timeout is short, so this wakes up regularly
+            // to check the deadline and for shutdown.
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.received_close = true;
+                    return Ok(None);
+                }
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if self.shutdown.is_shutdown() {
+                        if !self.sent_close {
+                            let _ = self.send_close(GOING_AWAY, "server shutting down");
+                        }
+                        let _ = self.stream.shutdown_write();
+                        return Ok(None);
+                    }
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "no message arrived in time",
+                        ));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Close the socket because the client broke the protocol, and make an
+    // error saying how.
+    fn fail(&mut self, code: u16, reason: &'static str) -> io::Error {
+        if !self.sent_close {
+            let _ = self.send_close(code, reason);
+        }
+        let _ = self.stream.shutdown_write();
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.sent_close = true;
+
+        let mut payload = code.to_be_bytes().to_vec();
+        // Control frames are limited to 125 bytes, two of them the code.
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+
+        self.write_frame(CLOSE, &payload)
+    }
+
+    // Server frames are never masked or fragmented.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= 0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        if !self.sent_close {
+            let _ = self.send_close(NORMAL, "");
+        }
+        let _ = self.stream.shutdown_write();
+    }
+}
+
+// A frame from the client, with its payload unmasked.
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Parse a frame from the start of `buf`, returning it and how many bytes it
+// took up, or `None` if the whole frame hasn't arrived yet. A frame that
+// breaks the rules gives the close code and reason to fail with.
+fn parse_frame(buf: &[u8], max_len: usize) -> Result<Option<(Frame, usize)>, (u16, &'static str)> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    // The reserved bits are for extensions, and we don't agree to any.
+    if buf[0] & 0x70 != 0 {
+        return Err((PROTOCOL_ERROR, "reserved bits set"));
+    }
+    if buf[1] & 0x80 == 0 {
+        return Err((PROTOCOL_ERROR, "client frames must be masked"));
+    }
+
+    let (len, mut at) = match buf[1] & 0x7f {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        len => (len as u64, 2),
+    };
+
+    if opcode >= CLOSE && (!fin || len > 125) {
+        return Err((PROTOCOL_ERROR, "control frames must be whole and short"));
+    }
+    if len > max_len as u64 {
+        return Err((TOO_BIG, "message too big"));
+    }
+
+    let len = len as usize;
+    if buf.len() < at + 4 + len {
+        return Ok(None);
+    }
+
+    let mask = &buf[at..at + 4];
+    at += 4;
+    let payload = buf[at..at + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        at + len,
+    )))
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+// SHA-1 is long broken for signatures, but it's what the handshake uses, and
+// there it only shows the server speaks WebSocket.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+
+    // Pad to a whole number of 64-byte blocks, ending with the length in
+    // bits.
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let next = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{handle_connection, ConnectionConfig};
+    use crate::router::{Params, Router};
+    use std::net::{TcpListener, TcpStream};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // A frame as a client sends it, masked.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // Read one unmasked frame from the server.
+    fn server_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0, "server frames aren't masked");
+
+        let len = match head[1] {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    // Serve one connection with an echo socket at /echo, and return the
+    // client end once the handshake is done, along with the worker's
+    // thread.
+    fn echo(shutdown: &ShutdownHandle) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = shutdown.clone();
+
+        let worker = thread::spawn(move || {
+            let router = Router::new().get("/echo", |request: &Request, _: &Params| {
+                WebSocket::upgrade(request, |mut socket| {
+                    socket.set_max_message_size(64 * 1024);
+                    while let Some(message) = socket.recv()? {
+                        socket.send(message)?;
+                    }
+                    Ok(())
+                })
+            });
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &router, &ConnectionConfig::default(), &shutdown).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(
+                b"GET /echo HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+                  Connection: keep-alive, Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+
+        // Read the head a byte at a time so none of the first frame is
+        // swallowed with it.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        (client, worker)
+    }
+
+    #[test]
+    fn hashes_with_sha1() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(&sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+        // The example from RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn parses_masked_frames() {
+        let frame = client_frame(true, TEXT, b"Hello");
+        assert_eq!(
+            frame,
+            [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]
+        );
+
+        let expected = Frame {
+            fin: true,
+            opcode: TEXT,
+            payload: b"Hello".to_vec(),
+        };
+        assert_eq!(parse_frame(&frame, 100), Ok(Some((expected, 11))));
+        assert_eq!(parse_frame(&frame[..10], 100), Ok(None));
+
+        let long = client_frame(false, BINARY, &[7; 300]);
+        let (frame, used) = parse_frame(&long, 1000).unwrap().unwrap();
+        assert_eq!((frame.fin, frame.payload.len(), used), (false, 300, 308));
+    }
+
+    #[test]
+    fn rejects_frames_that_break_the_rules() {
+        // Unmasked.
+        assert_eq!(
+            parse_frame(&[0x81, 0x01, b'a'], 100).unwrap_err().0,
+            PROTOCOL_ERROR
+        );
+        // A reserved bit set.
+        let mut frame = client_frame(true, TEXT, b"a");
+        frame[0] |= 0x40;
+        assert_eq!(parse_frame(&frame, 100).unwrap_err().0, PROTOCOL_ERROR);
+        // A fragmented ping, and one that's too long.
+        let frame = client_frame(false, PING, b"a");
+        assert_eq!(parse_frame(&frame, 100).unwrap_err().0, PROTOCOL_ERROR);
+        let frame = client_frame(true, PING, &[0; 126]);
+        assert_eq!(parse_frame(&frame, 1000).unwrap_err().0, PROTOCOL_ERROR);
+        // Bigger than we'll take, known before the payload arrives.
+        let frame = client_frame(true, BINARY, &[0; 200]);
+        assert_eq!(parse_frame(&frame[..4], 100).unwrap_err().0, TOO_BIG);
+    }
+
+    #[test]
+    fn refuses_bad_handshakes() {
+        let handshake = |extra: &str| {
+            let raw = format!(
+                "GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n{}\r\n",
+                extra
+            );
+            let request = Request::parse(raw.as_bytes()).unwrap().unwrap().0;
+            WebSocket::upgrade(&request, |_| Ok(()))
+        };
+
+        let key = "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        let response = handshake(&format!("{}Sec-WebSocket-Version: 13\r\n", key));
+        assert_eq!(response.status(), StatusCode::SwitchingProtocols);
+
+        let response = handshake(&format!("{}Sec-WebSocket-Version: 8\r\n", key));
+        assert_eq!(response.status(), StatusCode::UpgradeRequired);
+        assert_eq!(response.headers().get("Sec-WebSocket-Version"), Some("13"));
+
+        let response = handshake("Sec-WebSocket-Key: c2hvcnQ=\r\nSec-WebSocket-Version: 13\r\n");
+        assert_eq!(response.status(), StatusCode::BadRequest);
+
+        let request = Request::parse(b"GET /ws HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap()
+            .unwrap()
+            .0;
+        let response = WebSocket::upgrade(&request, |_| Ok(()));
+        assert_eq!(response.status(), StatusCode::UpgradeRequired);
+    }
+
+    #[test]
+    fn echoes_messages_and_answers_pings() {
+        let (mut client, worker) = echo(&ShutdownHandle::new());
+
+        // The socket has its own thread, so the worker is already free.
+        worker.join().unwrap();
+
+        client.write_all(&client_frame(true, TEXT, b"hi")).unwrap();
+        assert_eq!(server_frame(&mut client), (TEXT, b"hi".to_vec()));
+
+        // A message in three fragments, with a ping in the middle.
+        client
+            .write_all(&client_frame(false, TEXT, b"frag"))
+            .unwrap();
+        client.write_all(&client_frame(true, PING, b"?")).unwrap();
+        client
+            .write_all(&client_frame(false, CONTINUATION, b"men"))
+            .unwrap();
+        client
+            .write_all(&client_frame(true, CONTINUATION, b"ted"))
+            .unwrap();
+        assert_eq!(server_frame(&mut client), (PONG, b"?".to_vec()));
+        assert_eq!(server_frame(&mut client), (TEXT, b"fragmented".to_vec()));
+
+        let big = vec![9; 1000];
+        client.write_all(&client_frame(true, BINARY, &big)).unwrap();
+        assert_eq!(server_frame(&mut client), (BINARY, big));
+
+        // Closing is echoed, then the server hangs up.
+        let mut close = 1000u16.to_be_bytes().to_vec();
+        close.extend_from_slice(b"bye");
+        client
+            .write_all(&client_frame(true, CLOSE, &close))
+            .unwrap();
+        assert_eq!(server_frame(&mut client), (CLOSE, vec![0x03, 0xe8]));
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn closes_on_protocol_errors() {
+        let (mut client, worker) = echo(&ShutdownHandle::new());
+        worker.join().unwrap();
+
+        client
+            .write_all(&client_frame(true, TEXT, &[0xff, 0xfe]))
+            .unwrap();
+        let (opcode, payload) = server_frame(&mut client);
+        assert_eq!(opcode, CLOSE);
+        assert_eq!(&payload[..2], INVALID_DATA.to_be_bytes());
+
+        let (mut client, worker) = echo(&ShutdownHandle::new());
+        worker.join().unwrap();
+
+        client
+            .write_all(&client_frame(true, CONTINUATION, b"?"))
+            .unwrap();
+        let (opcode, payload) = server_frame(&mut client);
+        assert_eq!(opcode, CLOSE);
+        assert_eq!(&payload[..2], PROTOCOL_ERROR.to_be_bytes());
+    }
+
+    #[test]
+    fn says_goodbye_when_the_server_shuts_down() {
+        let shutdown = ShutdownHandle::new();
+        let (mut client, worker) = echo(&shutdown);
+        worker.join().unwrap();
+
+        shutdown.shutdown();
+
+        let (opcode, payload) = server_frame(&mut client);
+        assert_eq!(opcode, CLOSE);
+        assert_eq!(&payload[..2], GOING_AWAY.to_be_bytes());
+    }
+}